@@ -0,0 +1,37 @@
+#!/usr/bin/env bash
+# wasm を 1MB 以下のチャンクに分けて streamingservice_manager にアップロードし、リリースとして登録する
+#
+# usage: scripts/upload_wasm_release.sh <Streaming|Greet> <version> <wasm file> [dfx options...]
+#   例: scripts/upload_wasm_release.sh Streaming 0.2.0 target/wasm32-unknown-unknown/release/streamingservice_backend.wasm --network ic
+set -euo pipefail
+
+if [ $# -lt 3 ]; then
+  echo "usage: $0 <Streaming|Greet> <version> <wasm file> [dfx options...]" >&2
+  exit 1
+fi
+KIND=$1
+VERSION=$2
+WASM=$3
+shift 3
+
+TMP=$(mktemp -d)
+trap 'rm -rf "$TMP"' EXIT
+
+# バイト列を Candid の blob リテラルの中身 ("\00\61...") に変換する
+to_blob() {
+  od -An -v -tx1 | tr -d ' \n' | sed 's/../\\&/g'
+}
+
+split -b 1000000 -d -a 4 "$WASM" "$TMP/chunk_"
+HASHES=()
+for chunk in "$TMP"/chunk_*; do
+  printf '(blob "%s")' "$(to_blob < "$chunk")" > "$TMP/arg"
+  dfx canister call "$@" streamingservice_manager upload_wasm_chunk --argument-file "$TMP/arg" > /dev/null
+  HASHES+=("blob \"$(sha256sum "$chunk" | cut -d' ' -f1 | sed 's/../\\&/g')\"")
+  echo "Uploaded $(basename "$chunk")"
+done
+
+WASM_HASH=$(sha256sum "$WASM" | cut -d' ' -f1 | sed 's/../\\&/g')
+printf '(variant { %s }, "%s", vec { %s }, blob "%s")' \
+  "$KIND" "$VERSION" "$(IFS=';'; echo "${HASHES[*]}")" "$WASM_HASH" > "$TMP/arg"
+dfx canister call "$@" streamingservice_manager create_wasm_release --argument-file "$TMP/arg"
@@ -0,0 +1,262 @@
+// 管理者 (モデレーター) の管理と管理者向け API
+//
+// 管理者はコントローラーが add_admin / remove_admin で登録する。コントローラー自身も管理者として扱う。
+// 管理者は任意の動画の削除、全アップロードの一覧、プリンシパルのアップロード凍結ができる。
+// 管理者による操作はすべて追記のみの監査ログに残し、コントローラーだけが参照できる。
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+use crate::error::{ApiError, ApiResult};
+use crate::reports::{ModerationAction, ModerationStatus};
+use crate::{discard_video, ensure_controller, take_video, VIDEOS};
+
+const DEFAULT_LIMIT: u32 = 50;
+const MAX_LIMIT: u32 = 500;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum AdminAction {
+    AddAdmin { principal: Principal },
+    RemoveAdmin { principal: Principal },
+    TakeDownVideo { video_id: String, owner: Principal, reason: String },
+    FreezePrincipal { principal: Principal },
+    UnfreezePrincipal { principal: Principal },
+    ResolveReport { report_id: u64, video_id: String, action: ModerationAction },
+    UnhideVideo { video_id: String },
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct AdminAuditEntry {
+    pub id: u64,
+    pub actor: Principal,
+    pub action: AdminAction,
+    pub timestamp: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct UploadRecord {
+    pub video_id: String,
+    pub title: String,
+    pub owner: Principal,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub finalized: bool,
+    pub published: bool,
+    pub moderation: ModerationStatus,
+    pub stored_bytes: u64,
+    pub owner_frozen: bool,
+}
+
+// アップグレード時に退避する状態
+#[derive(CandidType, Deserialize, Default)]
+pub(crate) struct AdminState {
+    admins: BTreeSet<Principal>,
+    frozen: BTreeSet<Principal>, // アップロードを凍結したプリンシパル
+    audit_log: Vec<AdminAuditEntry>,
+}
+
+thread_local! {
+    static ADMIN_STATE: RefCell<AdminState> = RefCell::new(AdminState::default());
+}
+
+pub(crate) fn save() -> AdminState {
+    ADMIN_STATE.with(|state| state.take())
+}
+
+pub(crate) fn restore(state: AdminState) {
+    ADMIN_STATE.with(|s| *s.borrow_mut() = state);
+}
+
+/// 管理者またはコントローラーであれば true
+pub(crate) fn is_admin(principal: &Principal) -> bool {
+    ic_cdk::api::is_controller(principal)
+        || ADMIN_STATE.with(|state| state.borrow().admins.contains(principal))
+}
+
+// 呼び出し元が管理者であることを確認し、呼び出し元を返す
+pub(crate) fn ensure_admin() -> Result<Principal, ApiError> {
+    let caller = ic_cdk::caller();
+    if is_admin(&caller) {
+        Ok(caller)
+    } else {
+        Err(ApiError::unauthorized("Caller is not an admin"))
+    }
+}
+
+/// 管理者として登録する。既に登録されていれば false
+pub(crate) fn grant(principal: Principal) -> bool {
+    ADMIN_STATE.with(|state| state.borrow_mut().admins.insert(principal))
+}
+
+pub(crate) fn is_frozen(principal: &Principal) -> bool {
+    ADMIN_STATE.with(|state| state.borrow().frozen.contains(principal))
+}
+
+// プリンシパルのアップロードが凍結されていないことを確認する
+pub(crate) fn ensure_not_frozen(principal: &Principal) -> Result<(), ApiError> {
+    if is_frozen(principal) {
+        Err(ApiError::unauthorized("Uploads from this principal are frozen"))
+    } else {
+        Ok(())
+    }
+}
+
+/// プリンシパルのアップロードを凍結する。既に凍結されていれば false
+pub(crate) fn freeze(principal: Principal) -> bool {
+    ADMIN_STATE.with(|state| state.borrow_mut().frozen.insert(principal))
+}
+
+/// 監査ログに管理者の操作を追記する
+pub(crate) fn record(actor: Principal, action: AdminAction) {
+    ADMIN_STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let id = state.audit_log.len() as u64;
+        state.audit_log.push(AdminAuditEntry {
+            id,
+            actor,
+            action,
+            timestamp: ic_cdk::api::time(),
+        });
+    });
+}
+
+/// 管理者を追加する (コントローラーのみ)
+#[update]
+fn add_admin(principal: Principal) -> ApiResult<()> {
+    if let Err(e) = ensure_controller() {
+        return ApiResult::Err(e);
+    }
+    if principal == Principal::anonymous() {
+        return ApiResult::Err(ApiError::invalid_argument(
+            "principal",
+            "Anonymous principal cannot be an admin",
+        ));
+    }
+    if !grant(principal) {
+        return ApiResult::Err(ApiError::conflict("Principal is already an admin"));
+    }
+    record(ic_cdk::caller(), AdminAction::AddAdmin { principal });
+    ApiResult::Ok(())
+}
+
+/// 管理者を削除する (コントローラーのみ)
+#[update]
+fn remove_admin(principal: Principal) -> ApiResult<()> {
+    if let Err(e) = ensure_controller() {
+        return ApiResult::Err(e);
+    }
+    if !ADMIN_STATE.with(|state| state.borrow_mut().admins.remove(&principal)) {
+        return ApiResult::Err(ApiError::not_found("Admin"));
+    }
+    record(ic_cdk::caller(), AdminAction::RemoveAdmin { principal });
+    ApiResult::Ok(())
+}
+
+/// 登録されている管理者を返す (管理者のみ)
+#[query]
+fn list_admins() -> ApiResult<Vec<Principal>> {
+    if let Err(e) = ensure_admin() {
+        return ApiResult::Err(e);
+    }
+    ApiResult::Ok(ADMIN_STATE.with(|state| state.borrow().admins.iter().cloned().collect()))
+}
+
+/// 任意の動画を削除する (管理者のみ)
+/// ゴミ箱を経由せず即座に完全削除するので、所有者は復元できない
+#[update]
+fn take_down_video(video_id: String, reason: String) -> ApiResult<()> {
+    let caller = match ensure_admin() {
+        Ok(caller) => caller,
+        Err(e) => return ApiResult::Err(e),
+    };
+    let Some(video) = take_video(&video_id) else {
+        return ApiResult::Err(ApiError::not_found("Video"));
+    };
+    discard_video(&video);
+    record(caller, AdminAction::TakeDownVideo {
+        video_id,
+        owner: video.owner,
+        reason,
+    });
+    ApiResult::Ok(())
+}
+
+/// すべてのアップロードを所有者付きで新しい順に返す (管理者のみ)
+/// cursor には前回の結果の最後の video_id を渡す
+#[query]
+fn list_all_uploads(limit: Option<u32>, cursor: Option<String>) -> ApiResult<Vec<UploadRecord>> {
+    if let Err(e) = ensure_admin() {
+        return ApiResult::Err(e);
+    }
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT) as usize;
+    let mut records: Vec<UploadRecord> = VIDEOS.with(|videos| {
+        videos
+            .borrow()
+            .values()
+            .filter(|video| cursor.as_ref().is_none_or(|cursor| video.id < *cursor))
+            .map(|video| UploadRecord {
+                video_id: video.id.clone(),
+                title: video.title.clone(),
+                owner: video.owner,
+                created_at: video.created_at,
+                updated_at: video.updated_at,
+                finalized: video.finalized,
+                published: video.published,
+                moderation: video.moderation.clone(),
+                stored_bytes: video.stored_segment_bytes(),
+                owner_frozen: is_frozen(&video.owner),
+            })
+            .collect()
+    });
+    // 動画IDは作成時刻なので、ID の降順が新しい順になる
+    records.sort_by(|a, b| b.video_id.cmp(&a.video_id));
+    records.truncate(limit);
+    ApiResult::Ok(records)
+}
+
+/// プリンシパルのアップロードを凍結・解除する (管理者のみ)
+/// 凍結中は動画の作成と、そのプリンシパルが所有する動画へのアップロードができない
+#[update]
+fn set_principal_frozen(principal: Principal, frozen: bool) -> ApiResult<()> {
+    let caller = match ensure_admin() {
+        Ok(caller) => caller,
+        Err(e) => return ApiResult::Err(e),
+    };
+    let changed = if frozen {
+        freeze(principal)
+    } else {
+        ADMIN_STATE.with(|state| state.borrow_mut().frozen.remove(&principal))
+    };
+    if changed {
+        let action = if frozen {
+            AdminAction::FreezePrincipal { principal }
+        } else {
+            AdminAction::UnfreezePrincipal { principal }
+        };
+        record(caller, action);
+    }
+    ApiResult::Ok(())
+}
+
+/// 管理者の操作の監査ログを古い順に返す (コントローラーのみ)
+/// cursor には前回の結果の最後のエントリの id + 1 を渡す
+#[query]
+fn get_admin_audit_log(limit: Option<u32>, cursor: Option<u64>) -> ApiResult<Vec<AdminAuditEntry>> {
+    if let Err(e) = ensure_controller() {
+        return ApiResult::Err(e);
+    }
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT) as usize;
+    ADMIN_STATE.with(|state| {
+        ApiResult::Ok(
+            state
+                .borrow()
+                .audit_log
+                .iter()
+                .skip(cursor.unwrap_or(0) as usize)
+                .take(limit)
+                .cloned()
+                .collect(),
+        )
+    })
+}
@@ -0,0 +1,261 @@
+// 動画ごとの再生回数と視聴分析
+//
+// 再生イベント (開始 / セグメント到達 / 完了) を集計値だけで保持する。
+// セグメント到達数は 1 回の視聴で各セグメントにつき 1 回送られる前提で、
+// 平均視聴率と離脱位置はそこから算出する。
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+use crate::error::{ApiError, ApiResult};
+use crate::rate_limit::{self, Limit};
+use crate::VIDEOS;
+
+// 匿名の呼び出し元からのイベントは全員で同じプリンシパルになるため、動画ごとにまとめて制限する
+// 1 つのクライアントが上限まで送ると、その動画の匿名の視聴者のイベントはウィンドウが終わるまで記録されない
+// (他の動画のイベントには影響しない)
+const ANONYMOUS_EVENT_LIMIT: Limit = Limit {
+    bucket: "record_playback_event",
+    max_calls: 600,
+    window_secs: 60,
+};
+// 匿名以外の呼び出し元からのイベントはプリンシパルごとに制限する
+// (1 回の視聴でセグメントごとにイベントを送るため、長い動画を再生しても超えない程度にしておく)
+const CALLER_EVENT_LIMIT: Limit = Limit {
+    bucket: "record_playback_event_caller",
+    max_calls: 300,
+    window_secs: 60,
+};
+// 動画ごとに記録する視聴者の上限 (これを超えた視聴者は unique_viewers に数えない)
+const MAX_TRACKED_VIEWERS: usize = 10_000;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum PlaybackEvent {
+    Start,
+    SegmentReached(u32),
+    Completed,
+}
+
+#[derive(CandidType, Deserialize, Default)]
+pub(crate) struct VideoCounters {
+    views: u64,
+    completions: u64,
+    viewers: HashSet<Principal>, // 匿名以外の視聴者 (MAX_TRACKED_VIEWERS まで)
+    segment_reached: Vec<u64>, // セグメントごとの到達回数
+}
+
+// オーナー向けダッシュボードで返す集計結果
+#[derive(CandidType, Deserialize, Clone)]
+pub struct VideoAnalytics {
+    pub video_id: String,
+    pub views: u64,
+    pub unique_viewers: u64, // 匿名以外の視聴者数。unique_viewers_capped が true のときは下限
+    pub unique_viewers_capped: bool, // 記録する視聴者の上限に達し、それ以降の視聴者を数えていない
+    pub completions: u64,
+    pub average_watch_through_percent: f64,
+    pub segment_reached: Vec<u64>,
+    pub segment_drop_off: Vec<u64>, // 各セグメントまで到達し、次のセグメントに進まなかった回数
+}
+
+thread_local! {
+    static ANALYTICS: RefCell<HashMap<String, VideoCounters>> = RefCell::new(HashMap::new());
+}
+
+pub(crate) fn save() -> HashMap<String, VideoCounters> {
+    ANALYTICS.with(|analytics| analytics.take())
+}
+
+pub(crate) fn restore(state: HashMap<String, VideoCounters>) {
+    ANALYTICS.with(|analytics| *analytics.borrow_mut() = state);
+}
+
+pub(crate) fn remove(video_id: &str) {
+    ANALYTICS.with(|analytics| analytics.borrow_mut().remove(video_id));
+}
+
+fn summarize(video_id: &str, counters: &VideoCounters, segment_count: usize) -> VideoAnalytics {
+    let mut segment_reached = counters.segment_reached.clone();
+    segment_reached.resize(segment_reached.len().max(segment_count), 0);
+
+    let reached_total: u64 = segment_reached.iter().sum();
+    let possible_total = counters.views.saturating_mul(segment_reached.len() as u64);
+    let average_watch_through_percent = if possible_total == 0 {
+        0.0
+    } else {
+        (reached_total as f64 / possible_total as f64 * 100.0).min(100.0)
+    };
+
+    let segment_drop_off = segment_reached
+        .iter()
+        .enumerate()
+        .map(|(index, reached)| {
+            let next = segment_reached.get(index + 1).copied().unwrap_or(0);
+            reached.saturating_sub(next)
+        })
+        .collect();
+
+    VideoAnalytics {
+        video_id: video_id.to_string(),
+        views: counters.views,
+        unique_viewers: counters.viewers.len() as u64,
+        unique_viewers_capped: counters.viewers.len() >= MAX_TRACKED_VIEWERS,
+        completions: counters.completions,
+        average_watch_through_percent,
+        segment_reached,
+        segment_drop_off,
+    }
+}
+
+/// 再生イベントを記録する
+#[update]
+fn record_playback_event(video_id: String, event: PlaybackEvent) -> ApiResult<()> {
+    let caller = ic_cdk::caller();
+    let segment_count = VIDEOS.with(|videos| {
+        videos
+            .borrow()
+            .get(&video_id)
+            .filter(|video| video.is_servable_to(&caller))
+            .map(|video| video.segments.len())
+    });
+    let Some(segment_count) = segment_count else {
+        return ApiResult::Err(ApiError::not_found("Video"));
+    };
+    // 存在しない動画や見せない動画へのイベントでは制限の回数を消費しない
+    let limited = if caller == Principal::anonymous() {
+        rate_limit::check_key(&ANONYMOUS_EVENT_LIMIT, video_id.as_bytes())
+    } else {
+        rate_limit::check(&CALLER_EVENT_LIMIT, caller)
+    };
+    if let Err(e) = limited {
+        return ApiResult::Err(e);
+    }
+
+    ANALYTICS.with(|analytics| {
+        let mut analytics = analytics.borrow_mut();
+        let counters = analytics.entry(video_id).or_default();
+        match event {
+            PlaybackEvent::Start => {
+                counters.views += 1;
+                if caller != Principal::anonymous() && counters.viewers.len() < MAX_TRACKED_VIEWERS {
+                    counters.viewers.insert(caller);
+                }
+            }
+            PlaybackEvent::SegmentReached(segment_index) => {
+                if segment_index as usize >= segment_count {
+                    return ApiResult::Err(ApiError::invalid_argument(
+                        "event",
+                        format!("Segment index {} out of bounds", segment_index),
+                    ));
+                }
+                if counters.segment_reached.len() <= segment_index as usize {
+                    counters.segment_reached.resize(segment_index as usize + 1, 0);
+                }
+                counters.segment_reached[segment_index as usize] += 1;
+            }
+            PlaybackEvent::Completed => counters.completions += 1,
+        }
+        ApiResult::Ok(())
+    })
+}
+
+/// 動画の視聴分析を返す (動画の所有者またはコントローラーのみ)
+#[query]
+fn get_video_analytics(video_id: String) -> ApiResult<VideoAnalytics> {
+    let caller = ic_cdk::caller();
+    let segment_count = VIDEOS.with(|videos| {
+        videos.borrow().get(&video_id).map(|video| {
+            if video.can_manage(&caller) {
+                Ok(video.segments.len())
+            } else {
+                Err(ApiError::unauthorized("Caller is not the owner of the video"))
+            }
+        })
+    });
+    match segment_count {
+        Some(Ok(segment_count)) => ANALYTICS.with(|analytics| {
+            let analytics = analytics.borrow();
+            let empty = VideoCounters::default();
+            let counters = analytics.get(&video_id).unwrap_or(&empty);
+            ApiResult::Ok(summarize(&video_id, counters, segment_count))
+        }),
+        Some(Err(e)) => ApiResult::Err(e),
+        None => ApiResult::Err(ApiError::not_found("Video")),
+    }
+}
+
+/// 呼び出し元が所有する全動画の視聴分析を返す
+#[query]
+fn list_my_video_analytics() -> Vec<VideoAnalytics> {
+    let caller = ic_cdk::caller();
+    let empty = VideoCounters::default();
+    VIDEOS.with(|videos| {
+        ANALYTICS.with(|analytics| {
+            let analytics = analytics.borrow();
+            videos
+                .borrow()
+                .values()
+                .filter(|video| video.owner == caller)
+                .map(|video| {
+                    let counters = analytics.get(&video.id).unwrap_or(&empty);
+                    summarize(&video.id, counters, video.segments.len())
+                })
+                .collect()
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counters(views: u64, segment_reached: Vec<u64>) -> VideoCounters {
+        VideoCounters {
+            views,
+            segment_reached,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn watch_through_and_drop_off_are_derived_from_segment_counts() {
+        // 4 回の視聴で 3 セグメント中 4 / 3 / 1 回到達
+        let analytics = summarize("video", &counters(4, vec![4, 3, 1]), 3);
+        assert_eq!(analytics.average_watch_through_percent, 8.0 / 12.0 * 100.0);
+        assert_eq!(analytics.segment_drop_off, [1, 2, 1]);
+    }
+
+    // まだイベントが届いていないセグメントは 0 回として扱う
+    #[test]
+    fn segments_without_events_are_padded_with_zeros() {
+        let analytics = summarize("video", &counters(2, vec![2]), 3);
+        assert_eq!(analytics.segment_reached, [2, 0, 0]);
+        assert_eq!(analytics.segment_drop_off, [2, 0, 0]);
+    }
+
+    #[test]
+    fn video_without_views_has_zero_watch_through() {
+        let analytics = summarize("video", &counters(0, vec![]), 5);
+        assert_eq!(analytics.average_watch_through_percent, 0.0);
+        assert_eq!(analytics.segment_reached, [0; 5]);
+    }
+
+    // 到達数が視聴回数を超えても (Start を送らないクライアント) 100% を上限にする
+    #[test]
+    fn watch_through_is_capped_at_100_percent() {
+        let analytics = summarize("video", &counters(1, vec![3, 3]), 2);
+        assert_eq!(analytics.average_watch_through_percent, 100.0);
+    }
+
+    #[test]
+    fn unique_viewers_is_marked_as_a_lower_bound_at_the_cap() {
+        let mut counters = counters(0, vec![]);
+        counters.viewers = (0..MAX_TRACKED_VIEWERS as u32)
+            .map(|i| Principal::self_authenticating(i.to_le_bytes()))
+            .collect();
+        let analytics = summarize("video", &counters, 0);
+        assert_eq!(analytics.unique_viewers, MAX_TRACKED_VIEWERS as u64);
+        assert!(analytics.unique_viewers_capped);
+        assert!(!summarize("video", &VideoCounters::default(), 0).unique_viewers_capped);
+    }
+}
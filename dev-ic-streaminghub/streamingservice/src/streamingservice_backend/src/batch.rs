@@ -0,0 +1,257 @@
+// チャンクの一括アップロード / 一括取得
+//
+// upload_ts_segment_chunk をチャンクごとに呼ぶと 1 チャンクにつき 1 回の合意が必要になるため、
+// 複数のチャンクを 1 回の update でまとめて送れるようにする。
+// 各エントリは独立して処理し、失敗したエントリがあっても他のエントリは保存される。
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+
+use crate::error::{ApiError, ApiResult};
+use crate::{admin, blobs, channel, place_segment_chunk, Video, VIDEOS};
+
+// 1 回の呼び出しで受け付けるエントリ数の上限
+const MAX_BATCH_ENTRIES: usize = 64;
+// 一括アップロードで受け付けるデータ量の上限 (ingress メッセージの上限 2MiB から引数分の余裕を残す)
+const MAX_UPLOAD_BATCH_BYTES: usize = 1_900_000;
+// 一括取得で返すデータ量の上限 (レスポンスの上限 2MiB から余裕を残す)
+const MAX_FETCH_BATCH_BYTES: usize = 1_900_000;
+
+#[derive(CandidType, Deserialize)]
+pub struct ChunkUploadEntry {
+    pub segment_index: u32,
+    pub chunk_index: u32,
+    pub total_chunk_count: u32,
+    pub segment_chunk_data: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ChunkUploadEntryResult {
+    segment_index: u32,
+    chunk_index: u32,
+    result: ApiResult<()>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ChunkRef {
+    pub segment_index: u32,
+    pub chunk_index: u32,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ChunkFetchEntryResult {
+    segment_index: u32,
+    chunk_index: u32,
+    total_chunk_count: u32,
+    result: ApiResult<Vec<u8>>,
+}
+
+/// 複数のチャンクを一括でアップロードする
+/// 動画が存在しない場合・呼び出し元が動画を管理できない場合・バッチが上限を超える場合は全体をエラーにし、
+/// それ以外はエントリごとの結果を返す (一部のみ成功することがある)
+#[update]
+fn upload_chunks_batch(
+    _version: String,
+    video_id: String,
+    entries: Vec<ChunkUploadEntry>,
+) -> ApiResult<Vec<ChunkUploadEntryResult>> {
+    if let Err(e) = validate_upload_batch(&entries) {
+        return ApiResult::Err(e);
+    }
+    let caller = ic_cdk::caller();
+    let can_manage = VIDEOS.with(|videos| videos.borrow().get(&video_id).map(|video| video.can_manage(&caller)));
+    match can_manage {
+        None => return ApiResult::Err(ApiError::not_found("Video")),
+        Some(false) => return ApiResult::Err(ApiError::unauthorized("Caller is not the owner of the video")),
+        Some(true) => {}
+    }
+    if let Err(e) = admin::ensure_not_frozen(&caller) {
+        return ApiResult::Err(e);
+    }
+
+    let now = ic_cdk::api::time();
+    VIDEOS
+        .with(|videos| {
+            let mut videos = videos.borrow_mut();
+            let video = videos.get_mut(&video_id)?;
+            Some(store_entries(video, entries, caller, now))
+        })
+        .ok_or_else(|| ApiError::not_found("Video"))
+        .into()
+}
+
+/// バッチ全体のエントリ数とデータ量が上限を超えていないことを確認する
+fn validate_upload_batch(entries: &[ChunkUploadEntry]) -> Result<(), ApiError> {
+    if entries.len() > MAX_BATCH_ENTRIES {
+        return Err(ApiError::invalid_argument(
+            "entries",
+            format!("Too many entries: {} (max {})", entries.len(), MAX_BATCH_ENTRIES),
+        ));
+    }
+    let total_bytes: usize = entries.iter().map(|entry| entry.segment_chunk_data.len()).sum();
+    if total_bytes > MAX_UPLOAD_BATCH_BYTES {
+        return Err(ApiError::invalid_argument(
+            "entries",
+            format!("Batch too large: {} bytes (max {})", total_bytes, MAX_UPLOAD_BATCH_BYTES),
+        ));
+    }
+    Ok(())
+}
+
+/// エントリを 1 つずつ保存し、エントリごとの結果を返す
+/// 失敗したエントリ (クォータ超過など) は動画に格納せず、後続のエントリの保存は続ける
+fn store_entries(
+    video: &mut Video,
+    entries: Vec<ChunkUploadEntry>,
+    uploader: Principal,
+    now: u64,
+) -> Vec<ChunkUploadEntryResult> {
+    entries
+        .into_iter()
+        .map(|entry| {
+            let result = channel::put_chunk(entry.segment_chunk_data, uploader)
+                .map(|hash| {
+                    place_segment_chunk(
+                        video,
+                        entry.segment_index,
+                        entry.chunk_index,
+                        entry.total_chunk_count,
+                        hash,
+                        now,
+                    )
+                })
+                .into();
+            ChunkUploadEntryResult {
+                segment_index: entry.segment_index,
+                chunk_index: entry.chunk_index,
+                result,
+            }
+        })
+        .collect()
+}
+
+/// 複数のチャンクを一括で取得する (再生時の先読み用)
+/// レスポンスサイズの上限に達した以降のエントリはエラーになるため、
+/// 呼び出し側はエラーになったエントリを再度要求する
+#[query]
+fn get_chunks_batch(video_id: String, chunks: Vec<ChunkRef>) -> ApiResult<Vec<ChunkFetchEntryResult>> {
+    if chunks.len() > MAX_BATCH_ENTRIES {
+        return ApiResult::Err(ApiError::invalid_argument(
+            "chunks",
+            format!("Too many entries: {} (max {})", chunks.len(), MAX_BATCH_ENTRIES),
+        ));
+    }
+    let caller = ic_cdk::caller();
+    VIDEOS.with(|videos| {
+        let videos = videos.borrow();
+        let Some(video) = videos.get(&video_id).filter(|video| video.is_servable_to(&caller)) else {
+            return ApiResult::Err(ApiError::NotFound {
+                message: format!("Video not found with ID {}", video_id),
+            });
+        };
+
+        let mut response_bytes = 0;
+        let results = chunks
+            .into_iter()
+            .map(|chunk| {
+                let segment = video.segments.get(chunk.segment_index as usize);
+                let total_chunk_count = segment.map_or(0, |segment| segment.total_chunk_count);
+                let data = segment
+                    .and_then(|segment| segment.chunks.get(chunk.chunk_index as usize))
+                    .and_then(|hash| hash.as_ref())
+                    .and_then(blobs::get);
+                let result = match data {
+                    Some(data) if response_bytes + data.len() > MAX_FETCH_BATCH_BYTES => {
+                        ApiResult::Err(ApiError::quota_exceeded("Response size limit reached"))
+                    }
+                    Some(data) => {
+                        response_bytes += data.len();
+                        ApiResult::Ok(data)
+                    }
+                    None => ApiResult::Err(ApiError::NotFound {
+                        message: format!(
+                            "Chunk index {} not found in segment {}",
+                            chunk.chunk_index, chunk.segment_index
+                        ),
+                    }),
+                };
+                ChunkFetchEntryResult {
+                    segment_index: chunk.segment_index,
+                    chunk_index: chunk.chunk_index,
+                    total_chunk_count,
+                    result,
+                }
+            })
+            .collect();
+        ApiResult::Ok(results)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::{ChannelState, Quotas};
+
+    fn alice() -> Principal {
+        Principal::self_authenticating(b"alice")
+    }
+
+    fn entry(segment_index: u32, chunk_index: u32, data: Vec<u8>) -> ChunkUploadEntry {
+        ChunkUploadEntry {
+            segment_index,
+            chunk_index,
+            total_chunk_count: 2,
+            segment_chunk_data: data,
+        }
+    }
+
+    #[test]
+    fn batch_with_too_many_entries_is_rejected() {
+        let entries: Vec<_> = (0..=MAX_BATCH_ENTRIES as u32).map(|i| entry(0, i, vec![1])).collect();
+        assert!(matches!(
+            validate_upload_batch(&entries),
+            Err(ApiError::InvalidArgument { field, .. }) if field == "entries"
+        ));
+        assert!(validate_upload_batch(&entries[..MAX_BATCH_ENTRIES]).is_ok());
+    }
+
+    #[test]
+    fn batch_over_the_byte_limit_is_rejected() {
+        let half = MAX_UPLOAD_BATCH_BYTES / 2;
+        let entries = vec![entry(0, 0, vec![1; half]), entry(0, 1, vec![2; MAX_UPLOAD_BATCH_BYTES - half])];
+        assert!(validate_upload_batch(&entries).is_ok());
+
+        let entries = vec![entry(0, 0, vec![1; half]), entry(0, 1, vec![2; MAX_UPLOAD_BATCH_BYTES - half + 1])];
+        assert!(matches!(
+            validate_upload_batch(&entries),
+            Err(ApiError::InvalidArgument { field, .. }) if field == "entries"
+        ));
+    }
+
+    // クォータを超えたエントリだけが失敗し、前後のエントリは保存される
+    #[test]
+    fn entries_are_stored_independently() {
+        channel::restore(ChannelState::with_quotas(Quotas { max_videos: None, max_stored_bytes: Some(10) }));
+        let mut video = Video::for_test("video", alice(), 0);
+
+        let results = store_entries(
+            &mut video,
+            vec![entry(0, 0, vec![1; 6]), entry(0, 1, vec![2; 6]), entry(1, 0, vec![3; 4])],
+            alice(),
+            5,
+        );
+
+        let outcomes: Vec<_> = results
+            .iter()
+            .map(|r| (r.segment_index, r.chunk_index, matches!(r.result, ApiResult::Ok(()))))
+            .collect();
+        assert_eq!(outcomes, vec![(0, 0, true), (0, 1, false), (1, 0, true)]);
+        assert!(matches!(results[1].result, ApiResult::Err(ApiError::QuotaExceeded { .. })));
+
+        assert_eq!(video.segments.len(), 2);
+        assert_eq!(video.segments[0].chunks.len(), 1);
+        assert_eq!(video.segments[0].total_chunk_count, 2);
+        assert!(video.segments[1].chunks[0].is_some());
+        assert_eq!(video.updated_at, 5);
+        assert_eq!(blobs::stored_bytes(), 10);
+    }
+}
@@ -0,0 +1,268 @@
+// セグメントチャンクのコンテンツアドレス型ストア
+//
+// チャンクの実データは SHA-256 をキーにして 1 つだけ保存し、参照カウントで管理する。
+// 同じ動画の再アップロードや、共通のイントロを持つ別レンディションでは
+// バイト列を重複して保持せず、動画側はハッシュだけを持つ。
+// ハッシュだけで既存のチャンクを参照できるのは、そのバイト列を実際にアップロードしたプリンシパルに限る
+// (ハッシュを知っているだけで他人の非公開の動画のチャンクの有無を確かめたり、参照を固定したりできないようにする)。
+//
+// チャンクはヒープではなく stable memory の BTreeMap に置き (memory.rs)、アップグレードで退避しない。
+// 参照カウントなどのメタデータは実データと別の BTreeMap に置き、参照の増減で実データを書き直さないようにする。
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeSet;
+
+use crate::error::ApiError;
+use crate::memory::{self, Memory};
+
+pub type BlobHash = [u8; 32];
+
+#[derive(CandidType, Deserialize)]
+struct BlobMeta {
+    size: u64,
+    ref_count: u32,
+    uploaders: BTreeSet<Principal>, // このバイト列をアップロードしたプリンシパル
+}
+
+impl Storable for BlobMeta {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode blob metadata"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode blob metadata")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct BlobStats {
+    pub blob_count: u64,
+    pub stored_bytes: u64,     // 実際に保存しているバイト数
+    pub referenced_bytes: u64, // 参照カウントを考慮した論理的なバイト数
+}
+
+// 保存しているバイト数の合計
+// チャンクの保存のたびに全体を走査しないよう put / retain / release で更新し、アップグレード後に数え直す
+#[derive(Clone, Copy, Default)]
+struct Totals {
+    stored_bytes: u64,
+    referenced_bytes: u64,
+}
+
+thread_local! {
+    static BLOB_DATA: RefCell<StableBTreeMap<BlobHash, Vec<u8>, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::BLOB_DATA)));
+    static BLOB_META: RefCell<StableBTreeMap<BlobHash, BlobMeta, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::BLOB_META)));
+    static TOTALS: Cell<Totals> = Cell::new(Totals::default());
+}
+
+/// アップグレード後に stable memory のメタデータから合計を数え直す
+pub(crate) fn recount() {
+    let totals = BLOB_META.with(|meta| {
+        meta.borrow().iter().fold(Totals::default(), |totals, (_, blob)| Totals {
+            stored_bytes: totals.stored_bytes + blob.size,
+            referenced_bytes: totals.referenced_bytes + blob.size * blob.ref_count as u64,
+        })
+    });
+    TOTALS.with(|t| t.set(totals));
+}
+
+fn update_totals(f: impl FnOnce(&mut Totals)) {
+    TOTALS.with(|totals| {
+        let mut value = totals.get();
+        f(&mut value);
+        totals.set(value);
+    });
+}
+
+pub(crate) fn hash_of(data: &[u8]) -> BlobHash {
+    Sha256::digest(data).into()
+}
+
+pub(crate) fn parse_hash(bytes: &[u8]) -> Result<BlobHash, ApiError> {
+    bytes
+        .try_into()
+        .map_err(|_| {
+            ApiError::invalid_argument(
+                "chunk_hash",
+                format!("Invalid chunk hash length {}, expected 32", bytes.len()),
+            )
+        })
+}
+
+/// uploader がアップロードしたデータを保存して参照を 1 つ確保する
+/// 同じハッシュのデータが既にあればバイト列は保存せず参照カウントだけを増やす
+pub(crate) fn put(data: Vec<u8>, uploader: Principal) -> BlobHash {
+    let hash = hash_of(&data);
+    let size = data.len() as u64;
+    let mut blob = match BLOB_META.with(|meta| meta.borrow().get(&hash)) {
+        Some(blob) => blob,
+        None => {
+            BLOB_DATA.with(|blobs| blobs.borrow_mut().insert(hash, data));
+            update_totals(|totals| totals.stored_bytes += size);
+            BlobMeta {
+                size,
+                ref_count: 0,
+                uploaders: BTreeSet::new(),
+            }
+        }
+    };
+    blob.ref_count += 1;
+    blob.uploaders.insert(uploader);
+    update_totals(|totals| totals.referenced_bytes += size);
+    BLOB_META.with(|meta| meta.borrow_mut().insert(hash, blob));
+    hash
+}
+
+/// uploader がアップロードしたことのあるデータへの参照を 1 つ確保する
+/// データが無いか、uploader がアップロードしたデータでなければ false
+pub(crate) fn retain_uploaded(hash: &BlobHash, uploader: &Principal) -> bool {
+    BLOB_META.with(|meta| {
+        let mut meta = meta.borrow_mut();
+        match meta.get(hash) {
+            Some(mut blob) if blob.uploaders.contains(uploader) => {
+                blob.ref_count += 1;
+                update_totals(|totals| totals.referenced_bytes += blob.size);
+                meta.insert(*hash, blob);
+                true
+            }
+            _ => false,
+        }
+    })
+}
+
+/// 参照を 1 つ解放する。参照が無くなったデータは削除し、解放したバイト数を返す
+pub(crate) fn release(hash: &BlobHash) -> u64 {
+    BLOB_META.with(|meta| {
+        let mut meta = meta.borrow_mut();
+        let Some(mut blob) = meta.get(hash) else {
+            return 0;
+        };
+        if blob.ref_count > 0 {
+            blob.ref_count -= 1;
+            update_totals(|totals| totals.referenced_bytes -= blob.size);
+        }
+        if blob.ref_count == 0 {
+            meta.remove(hash);
+            BLOB_DATA.with(|data| data.borrow_mut().remove(hash));
+            update_totals(|totals| totals.stored_bytes -= blob.size);
+            blob.size
+        } else {
+            meta.insert(*hash, blob);
+            0
+        }
+    })
+}
+
+pub(crate) fn get(hash: &BlobHash) -> Option<Vec<u8>> {
+    BLOB_DATA.with(|data| data.borrow().get(hash))
+}
+
+/// 実際に保存しているバイト数の合計
+pub(crate) fn stored_bytes() -> u64 {
+    TOTALS.with(|totals| totals.get().stored_bytes)
+}
+
+pub(crate) fn size_of(hash: &BlobHash) -> Option<u64> {
+    BLOB_META.with(|meta| meta.borrow().get(hash).map(|blob| blob.size))
+}
+
+fn is_uploaded_by(hash: &BlobHash, uploader: &Principal) -> bool {
+    BLOB_META.with(|meta| meta.borrow().get(hash).is_some_and(|blob| blob.uploaders.contains(uploader)))
+}
+
+/// 指定されたハッシュのチャンクを呼び出し元がアップロード済みかどうかを返す
+/// 他のプリンシパルだけがアップロードしたチャンクは false になる
+/// アップロード済みのチャンクは upload_ts_segment_chunk_by_hash でバイト列を送らずに登録できる
+#[query]
+fn has_blobs(hashes: Vec<Vec<u8>>) -> Vec<bool> {
+    let caller = ic_cdk::caller();
+    hashes
+        .iter()
+        .map(|hash| parse_hash(hash).is_ok_and(|hash| is_uploaded_by(&hash, &caller)))
+        .collect()
+}
+
+#[query]
+fn get_blob_stats() -> BlobStats {
+    let totals = TOTALS.with(|totals| totals.get());
+    BlobStats {
+        blob_count: BLOB_META.with(|meta| meta.borrow().len()),
+        stored_bytes: totals.stored_bytes,
+        referenced_bytes: totals.referenced_bytes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alice() -> Principal {
+        Principal::self_authenticating(b"alice")
+    }
+
+    fn bob() -> Principal {
+        Principal::self_authenticating(b"bob")
+    }
+
+    fn ref_count(hash: &BlobHash) -> Option<u32> {
+        BLOB_META.with(|meta| meta.borrow().get(hash).map(|blob| blob.ref_count))
+    }
+
+    #[test]
+    fn same_data_is_stored_once_and_counted_per_reference() {
+        let first = put(vec![1; 100], alice());
+        let second = put(vec![1; 100], bob());
+        assert_eq!(first, second);
+        assert_eq!(ref_count(&first), Some(2));
+        assert_eq!(stored_bytes(), 100);
+        assert_eq!(get_blob_stats().referenced_bytes, 200);
+    }
+
+    #[test]
+    fn data_is_removed_when_the_last_reference_is_released() {
+        let hash = put(vec![2; 50], alice());
+        assert!(retain_uploaded(&hash, &alice()));
+        assert_eq!(release(&hash), 0);
+        assert_eq!(get(&hash), Some(vec![2; 50]));
+        assert_eq!(release(&hash), 50);
+        assert_eq!(get(&hash), None);
+        assert_eq!(ref_count(&hash), None);
+        assert_eq!(stored_bytes(), 0);
+        assert_eq!(get_blob_stats().referenced_bytes, 0);
+        // 既に削除したデータの解放は何もしない
+        assert_eq!(release(&hash), 0);
+    }
+
+    // ハッシュだけで参照できるのはアップロードしたプリンシパルに限る
+    #[test]
+    fn only_uploaders_can_retain_by_hash() {
+        let hash = put(vec![3; 10], alice());
+        assert!(!retain_uploaded(&hash, &bob()));
+        assert!(!retain_uploaded(&hash_of(b"missing"), &alice()));
+        assert_eq!(ref_count(&hash), Some(1));
+        assert!(is_uploaded_by(&hash, &alice()));
+        assert!(!is_uploaded_by(&hash, &bob()));
+    }
+
+    #[test]
+    fn recount_matches_running_totals() {
+        put(vec![4; 30], alice());
+        let hash = put(vec![5; 20], alice());
+        retain_uploaded(&hash, &alice());
+        let before = get_blob_stats();
+        recount();
+        let after = get_blob_stats();
+        assert_eq!(after.stored_bytes, before.stored_bytes);
+        assert_eq!(after.referenced_bytes, before.referenced_bytes);
+        assert_eq!((after.stored_bytes, after.referenced_bytes), (50, 70));
+    }
+}
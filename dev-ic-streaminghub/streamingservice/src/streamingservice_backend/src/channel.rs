@@ -0,0 +1,146 @@
+// キャニスター (チャンネル) の情報とクォータ
+//
+// streamingservice_manager はクリエイターごとに専用のキャニスターを作成し、
+// 所有者・タイトル・説明文・クォータを初期化引数 InitArgs で渡す。
+// 所有者は管理者として登録し、動画を作成できるのは所有者とコントローラーだけにする。
+// クォータは動画の作成時とチャンクの保存時に確認する。
+// 初期化引数なしでデプロイした場合 (dfx deploy など) は所有者もクォータも持たない。
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+
+use crate::admin::{self, AdminAction};
+use crate::blobs::{self, BlobHash};
+use crate::error::ApiError;
+use crate::VIDEOS;
+
+// None の項目は無制限
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct Quotas {
+    pub max_videos: Option<u64>,       // 保持できる動画の本数
+    pub max_stored_bytes: Option<u64>, // 保存できるチャンクの合計バイト数
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct InitArgs {
+    pub owner: Principal,
+    pub title: String,
+    pub description: String,
+    pub quotas: Quotas,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ChannelInfo {
+    pub owner: Option<Principal>,
+    pub title: String,
+    pub description: String,
+    pub quotas: Quotas,
+    pub video_count: u64,
+    pub stored_bytes: u64,
+}
+
+// アップグレード時に退避する状態
+#[derive(CandidType, Deserialize, Default)]
+pub(crate) struct ChannelState {
+    owner: Option<Principal>,
+    title: String,
+    description: String,
+    quotas: Quotas,
+}
+
+#[cfg(test)]
+impl ChannelState {
+    pub(crate) fn with_quotas(quotas: Quotas) -> ChannelState {
+        ChannelState { quotas, ..ChannelState::default() }
+    }
+}
+
+thread_local! {
+    static CHANNEL: RefCell<ChannelState> = RefCell::new(ChannelState::default());
+}
+
+pub(crate) fn save() -> ChannelState {
+    CHANNEL.with(|channel| channel.take())
+}
+
+pub(crate) fn restore(state: ChannelState) {
+    CHANNEL.with(|channel| *channel.borrow_mut() = state);
+}
+
+/// 初期化引数からチャンネルを設定し、所有者を管理者にする
+pub(crate) fn init(args: InitArgs) {
+    if admin::grant(args.owner) {
+        admin::record(ic_cdk::caller(), AdminAction::AddAdmin { principal: args.owner });
+    }
+    CHANNEL.with(|channel| {
+        *channel.borrow_mut() = ChannelState {
+            owner: Some(args.owner),
+            title: args.title,
+            description: args.description,
+            quotas: args.quotas,
+        }
+    });
+}
+
+fn quotas() -> Quotas {
+    CHANNEL.with(|channel| channel.borrow().quotas.clone())
+}
+
+/// 呼び出し元がこのチャンネルに動画を作成できることを確認する
+/// 所有者のいるチャンネルでは所有者とコントローラーだけが作成できる
+/// 初期化引数なしでデプロイしたチャンネルには所有者がいないため、これまでどおり誰でも作成できる
+pub(crate) fn ensure_can_create_video(caller: &Principal) -> Result<(), ApiError> {
+    match CHANNEL.with(|channel| channel.borrow().owner) {
+        Some(owner) if owner != *caller && !ic_cdk::api::is_controller(caller) => {
+            Err(ApiError::unauthorized("Only the channel owner can create videos"))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// 動画をもう 1 本作成できることを確認する
+pub(crate) fn ensure_video_quota() -> Result<(), ApiError> {
+    let Some(max_videos) = quotas().max_videos else {
+        return Ok(());
+    };
+    let video_count = VIDEOS.with(|videos| videos.borrow().len() as u64);
+    if video_count >= max_videos {
+        return Err(ApiError::quota_exceeded(format!(
+            "This channel can hold at most {} videos",
+            max_videos
+        )));
+    }
+    Ok(())
+}
+
+/// uploader がアップロードしたチャンクを保存して参照を 1 つ確保する
+/// 保存後の合計がクォータを超える場合は保存を取り消してエラーにする
+pub(crate) fn put_chunk(data: Vec<u8>, uploader: Principal) -> Result<BlobHash, ApiError> {
+    let hash = blobs::put(data, uploader);
+    if let Some(max_stored_bytes) = quotas().max_stored_bytes {
+        if blobs::stored_bytes() > max_stored_bytes {
+            blobs::release(&hash);
+            return Err(ApiError::quota_exceeded(format!(
+                "This channel can store at most {} bytes",
+                max_stored_bytes
+            )));
+        }
+    }
+    Ok(hash)
+}
+
+/// チャンネルの情報と使用量を返す
+#[query]
+fn get_channel_info() -> ChannelInfo {
+    CHANNEL.with(|channel| {
+        let channel = channel.borrow();
+        ChannelInfo {
+            owner: channel.owner,
+            title: channel.title.clone(),
+            description: channel.description.clone(),
+            quotas: channel.quotas.clone(),
+            video_count: VIDEOS.with(|videos| videos.borrow().len() as u64),
+            stored_bytes: blobs::stored_bytes(),
+        }
+    })
+}
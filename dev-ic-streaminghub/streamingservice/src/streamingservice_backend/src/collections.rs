@@ -0,0 +1,519 @@
+// ユーザーが作成する動画のコレクション (プレイリスト)
+//
+// コレクションは所有者・タイトル・並び順付きの動画ID一覧・公開範囲を持つ。
+// 動画が削除されてもコレクションからは取り除かず、参照時に missing として返す。
+// ゴミ箱から復元された動画はそのままコレクションに戻る。
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+
+use crate::error::{ApiError, ApiResult};
+use crate::{Video, VideoSummary, VIDEOS};
+
+const MAX_TITLE_LEN: usize = 100;
+const MAX_DESCRIPTION_LEN: usize = 2_000;
+const MAX_ITEMS_PER_COLLECTION: usize = 500;
+const MAX_COLLECTIONS_PER_OWNER: usize = 100;
+
+// 結合プレイリストを作るときに動画ごとのヘッダーから取り除くタグ
+const PLAYLIST_HEADER_TAGS: [&str; 6] = [
+    "#EXTM3U",
+    "#EXT-X-VERSION",
+    "#EXT-X-TARGETDURATION",
+    "#EXT-X-MEDIA-SEQUENCE",
+    "#EXT-X-PLAYLIST-TYPE",
+    "#EXT-X-ENDLIST",
+];
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Visibility {
+    Public,   // 一覧に表示され、誰でも参照できる
+    Unlisted, // 一覧には表示されないが、IDを知っていれば参照できる
+    Private,  // 所有者だけが参照できる
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub(crate) struct Collection {
+    id: u64,
+    owner: Principal,
+    title: String,
+    description: String,
+    video_ids: Vec<String>, // 再生順
+    visibility: Visibility,
+    created_at: u64,
+    updated_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CollectionItem {
+    pub video_id: String,
+    pub missing: bool, // 動画が削除されているか、呼び出し元に表示できない場合は true
+    pub video: Option<VideoSummary>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CollectionView {
+    pub id: u64,
+    pub owner: Principal,
+    pub title: String,
+    pub description: String,
+    pub visibility: Visibility,
+    pub items: Vec<CollectionItem>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CollectionSummary {
+    pub id: u64,
+    pub owner: Principal,
+    pub title: String,
+    pub visibility: Visibility,
+    pub item_count: u64,
+    pub updated_at: u64,
+}
+
+// アップグレード時に退避する状態
+#[derive(CandidType, Deserialize, Default)]
+pub(crate) struct CollectionsState {
+    collections: BTreeMap<u64, Collection>,
+    next_id: u64,
+}
+
+thread_local! {
+    static COLLECTIONS: RefCell<CollectionsState> = RefCell::new(CollectionsState::default());
+}
+
+pub(crate) fn save() -> CollectionsState {
+    COLLECTIONS.with(|collections| collections.take())
+}
+
+pub(crate) fn restore(state: CollectionsState) {
+    COLLECTIONS.with(|collections| *collections.borrow_mut() = state);
+}
+
+impl Collection {
+    fn can_manage(&self, caller: &Principal) -> bool {
+        self.owner == *caller || ic_cdk::api::is_controller(caller)
+    }
+
+    fn can_view(&self, caller: &Principal) -> bool {
+        self.visibility != Visibility::Private || self.can_manage(caller)
+    }
+
+    fn summary(&self) -> CollectionSummary {
+        CollectionSummary {
+            id: self.id,
+            owner: self.owner,
+            title: self.title.clone(),
+            visibility: self.visibility,
+            item_count: self.video_ids.len() as u64,
+            updated_at: self.updated_at,
+        }
+    }
+
+    /// 並び順を video_ids に置き換える。現在の動画IDと過不足がある場合はエラー
+    fn reorder(&mut self, video_ids: Vec<String>) -> Result<(), ApiError> {
+        let current: HashSet<&String> = self.video_ids.iter().collect();
+        let requested: HashSet<&String> = video_ids.iter().collect();
+        if video_ids.len() != self.video_ids.len() || current != requested {
+            return Err(ApiError::invalid_argument(
+                "video_ids",
+                "video_ids must contain exactly the videos in the collection",
+            ));
+        }
+        self.video_ids = video_ids;
+        Ok(())
+    }
+
+    /// 呼び出し元に見せない動画 (公開期間外・非表示) とゴミ箱の中の動画は missing として返す
+    fn view(&self, caller: &Principal) -> CollectionView {
+        let items = VIDEOS.with(|videos| {
+            let videos = videos.borrow();
+            self.video_ids
+                .iter()
+                .map(|video_id| {
+                    let video = videos
+                        .get(video_id)
+                        .filter(|video| video.is_servable_to(caller))
+                        .map(Video::summary);
+                    CollectionItem {
+                        video_id: video_id.clone(),
+                        missing: video.is_none(),
+                        video,
+                    }
+                })
+                .collect()
+        });
+        CollectionView {
+            id: self.id,
+            owner: self.owner,
+            title: self.title.clone(),
+            description: self.description.clone(),
+            visibility: self.visibility,
+            items,
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+        }
+    }
+}
+
+fn validate_text(title: &str, description: &str) -> Result<(String, String), ApiError> {
+    let title = title.trim();
+    if title.is_empty() {
+        return Err(ApiError::invalid_argument("title", "Title must not be empty"));
+    }
+    if title.chars().count() > MAX_TITLE_LEN {
+        return Err(ApiError::invalid_argument(
+            "title",
+            format!("Title is longer than {} characters", MAX_TITLE_LEN),
+        ));
+    }
+    if description.chars().count() > MAX_DESCRIPTION_LEN {
+        return Err(ApiError::invalid_argument(
+            "description",
+            format!("Description is longer than {} characters", MAX_DESCRIPTION_LEN),
+        ));
+    }
+    Ok((title.to_string(), description.to_string()))
+}
+
+/// 所有者の確認をしてからコレクションを更新する
+fn update_collection_with(
+    collection_id: u64,
+    update: impl FnOnce(&mut Collection) -> Result<(), ApiError>,
+) -> ApiResult<()> {
+    let caller = ic_cdk::caller();
+    COLLECTIONS.with(|collections| {
+        let mut state = collections.borrow_mut();
+        let Some(collection) = state.collections.get_mut(&collection_id) else {
+            return ApiResult::Err(ApiError::not_found("Collection"));
+        };
+        if !collection.can_manage(&caller) {
+            return ApiResult::Err(ApiError::unauthorized("Caller is not the owner of the collection"));
+        }
+        match update(collection) {
+            Ok(()) => {
+                collection.updated_at = ic_cdk::api::time();
+                ApiResult::Ok(())
+            }
+            Err(e) => ApiResult::Err(e),
+        }
+    })
+}
+
+/// コレクションを作成する (匿名の呼び出し元は不可)
+#[update]
+fn create_collection(title: String, description: String, visibility: Visibility) -> ApiResult<u64> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return ApiResult::Err(ApiError::unauthorized("Anonymous callers cannot create collections"));
+    }
+    let (title, description) = match validate_text(&title, &description) {
+        Ok(text) => text,
+        Err(e) => return ApiResult::Err(e),
+    };
+    COLLECTIONS.with(|collections| {
+        let mut state = collections.borrow_mut();
+        let owned = state.collections.values().filter(|c| c.owner == caller).count();
+        if owned >= MAX_COLLECTIONS_PER_OWNER {
+            return ApiResult::Err(ApiError::quota_exceeded(format!(
+                "A principal can own at most {} collections",
+                MAX_COLLECTIONS_PER_OWNER
+            )));
+        }
+        let id = state.next_id;
+        let now = ic_cdk::api::time();
+        state.collections.insert(id, Collection {
+            id,
+            owner: caller,
+            title,
+            description,
+            video_ids: Vec::new(),
+            visibility,
+            created_at: now,
+            updated_at: now,
+        });
+        state.next_id += 1;
+        ApiResult::Ok(id)
+    })
+}
+
+/// コレクションのタイトル・説明・公開範囲を更新する
+#[update]
+fn update_collection(
+    collection_id: u64,
+    title: String,
+    description: String,
+    visibility: Visibility,
+) -> ApiResult<()> {
+    let (title, description) = match validate_text(&title, &description) {
+        Ok(text) => text,
+        Err(e) => return ApiResult::Err(e),
+    };
+    update_collection_with(collection_id, |collection| {
+        collection.title = title;
+        collection.description = description;
+        collection.visibility = visibility;
+        Ok(())
+    })
+}
+
+/// コレクションを削除する (動画自体は削除しない)
+#[update]
+fn delete_collection(collection_id: u64) -> ApiResult<()> {
+    let caller = ic_cdk::caller();
+    COLLECTIONS.with(|collections| {
+        let mut state = collections.borrow_mut();
+        match state.collections.get(&collection_id) {
+            Some(collection) if !collection.can_manage(&caller) => {
+                ApiResult::Err(ApiError::unauthorized("Caller is not the owner of the collection"))
+            }
+            Some(_) => {
+                state.collections.remove(&collection_id);
+                ApiResult::Ok(())
+            }
+            None => ApiResult::Err(ApiError::not_found("Collection")),
+        }
+    })
+}
+
+/// コレクションに動画を追加する。position を省略すると末尾に追加する
+#[update]
+fn add_to_collection(collection_id: u64, video_id: String, position: Option<u32>) -> ApiResult<()> {
+    if !VIDEOS.with(|videos| videos.borrow().contains_key(&video_id)) {
+        return ApiResult::Err(ApiError::not_found("Video"));
+    }
+    update_collection_with(collection_id, |collection| {
+        if collection.video_ids.contains(&video_id) {
+            return Err(ApiError::conflict("Video is already in the collection"));
+        }
+        if collection.video_ids.len() >= MAX_ITEMS_PER_COLLECTION {
+            return Err(ApiError::quota_exceeded(format!(
+                "A collection can hold at most {} videos",
+                MAX_ITEMS_PER_COLLECTION
+            )));
+        }
+        let position = position.map_or(collection.video_ids.len(), |p| {
+            (p as usize).min(collection.video_ids.len())
+        });
+        collection.video_ids.insert(position, video_id);
+        Ok(())
+    })
+}
+
+/// コレクションから動画を取り除く (削除済みの動画も取り除ける)
+#[update]
+fn remove_from_collection(collection_id: u64, video_id: String) -> ApiResult<()> {
+    update_collection_with(collection_id, |collection| {
+        let before = collection.video_ids.len();
+        collection.video_ids.retain(|id| *id != video_id);
+        if collection.video_ids.len() == before {
+            return Err(ApiError::not_found("Video in the collection"));
+        }
+        Ok(())
+    })
+}
+
+/// コレクションの並び順を変更する
+/// video_ids には現在の動画IDをすべて、新しい順番で渡す
+#[update]
+fn reorder_collection(collection_id: u64, video_ids: Vec<String>) -> ApiResult<()> {
+    update_collection_with(collection_id, |collection| collection.reorder(video_ids))
+}
+
+/// コレクションを返す。非公開のコレクションは所有者だけが参照できる
+#[query]
+fn get_collection(collection_id: u64) -> ApiResult<CollectionView> {
+    let caller = ic_cdk::caller();
+    COLLECTIONS.with(|collections| {
+        match collections.borrow().collections.get(&collection_id) {
+            Some(collection) if collection.can_view(&caller) => ApiResult::Ok(collection.view(&caller)),
+            _ => ApiResult::Err(ApiError::not_found("Collection")),
+        }
+    })
+}
+
+/// 呼び出し元が所有するコレクションを返す
+#[query]
+fn list_my_collections() -> Vec<CollectionSummary> {
+    let caller = ic_cdk::caller();
+    COLLECTIONS.with(|collections| {
+        collections
+            .borrow()
+            .collections
+            .values()
+            .filter(|collection| collection.owner == caller)
+            .map(Collection::summary)
+            .collect()
+    })
+}
+
+/// 公開コレクションを返す。owner を指定するとそのプリンシパルのものだけを返す
+#[query]
+fn list_public_collections(owner: Option<Principal>) -> Vec<CollectionSummary> {
+    COLLECTIONS.with(|collections| {
+        collections
+            .borrow()
+            .collections
+            .values()
+            .filter(|collection| collection.visibility == Visibility::Public)
+            .filter(|collection| owner.is_none_or(|owner| collection.owner == owner))
+            .map(Collection::summary)
+            .collect()
+    })
+}
+
+/// コレクションの動画を続けて再生するための HLS プレイリストを返す
+/// 動画の境目に EXT-X-DISCONTINUITY を入れ、セグメントは icsegment://<動画ID>/<セグメント名> で参照する
+/// 削除済み・非公開の動画やアップロードが完了していない動画は飛ばす
+#[query]
+fn get_collection_hls_playlist(collection_id: u64) -> ApiResult<String> {
+    let caller = ic_cdk::caller();
+    let video_ids = COLLECTIONS.with(|collections| {
+        match collections.borrow().collections.get(&collection_id) {
+            Some(collection) if collection.can_view(&caller) => Some(collection.video_ids.clone()),
+            _ => None,
+        }
+    });
+    let Some(video_ids) = video_ids else {
+        return ApiResult::Err(ApiError::not_found("Collection"));
+    };
+
+    let playlist = VIDEOS.with(|videos| {
+        let videos = videos.borrow();
+        combined_hls_playlist(video_ids.iter().filter_map(|id| videos.get(id)))
+    });
+    match playlist {
+        Some(playlist) => ApiResult::Ok(playlist),
+        None => ApiResult::Err(ApiError::conflict("Collection has no playable videos")),
+    }
+}
+
+/// 再生できる動画のプレイリストを順に結合する。再生できる動画が 1 本もなければ None
+fn combined_hls_playlist<'a>(videos: impl IntoIterator<Item = &'a Video>) -> Option<String> {
+    let mut target_duration: u64 = 1;
+    let mut body = String::new();
+    let mut playable = 0;
+    for video in videos {
+        let Some(playlist) = video
+            .playlist
+            .as_ref()
+            .filter(|_| video.is_listed() && video.is_upload_complete())
+        else {
+            continue;
+        };
+        if playable > 0 {
+            body.push_str("#EXT-X-DISCONTINUITY\n");
+        }
+        playable += 1;
+        for line in playlist.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            if let Some(duration) = line.strip_prefix("#EXT-X-TARGETDURATION:") {
+                target_duration = target_duration.max(duration.trim().parse().unwrap_or(0));
+            } else if PLAYLIST_HEADER_TAGS.iter().any(|tag| line.starts_with(tag)) {
+                continue;
+            } else if line.starts_with('#') {
+                body.push_str(line);
+                body.push('\n');
+            } else {
+                body.push_str(&format!("icsegment://{}/{}\n", video.id, line));
+            }
+        }
+    }
+    if playable == 0 {
+        return None;
+    }
+    Some(format!(
+        "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n{}#EXT-X-ENDLIST\n",
+        target_duration, body
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SegmentInfo;
+
+    fn alice() -> Principal {
+        Principal::self_authenticating(b"alice")
+    }
+
+    fn collection(video_ids: &[&str]) -> Collection {
+        Collection {
+            id: 0,
+            owner: alice(),
+            title: "Mix".to_string(),
+            description: String::new(),
+            video_ids: video_ids.iter().map(|id| id.to_string()).collect(),
+            visibility: Visibility::Public,
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    // アップロードが完了した動画
+    fn playable(id: &str, playlist: &str) -> Video {
+        let mut video = Video::for_test(id, alice(), 0);
+        video.playlist = Some(playlist.to_string());
+        video.segments = vec![SegmentInfo { chunks: vec![Some([0; 32])], total_chunk_count: 1 }];
+        video
+    }
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn reorder_requires_exactly_the_current_videos() {
+        let mut collection = collection(&["a", "b", "c"]);
+        for video_ids in [ids(&["a", "b"]), ids(&["a", "b", "d"]), ids(&["a", "b", "b"]), ids(&["a", "b", "c", "a"])] {
+            assert!(matches!(
+                collection.reorder(video_ids),
+                Err(ApiError::InvalidArgument { field, .. }) if field == "video_ids"
+            ));
+        }
+        assert_eq!(collection.video_ids, ids(&["a", "b", "c"]));
+
+        assert!(collection.reorder(ids(&["c", "a", "b"])).is_ok());
+        assert_eq!(collection.video_ids, ids(&["c", "a", "b"]));
+    }
+
+    // 削除された動画はコレクションに残したまま missing として返す
+    #[test]
+    fn deleted_videos_are_reported_as_missing() {
+        VIDEOS.with(|videos| videos.borrow_mut().insert("a".to_string(), Video::for_test("a", alice(), 0)));
+
+        let view = collection(&["a", "deleted"]).view(&alice());
+
+        let items: Vec<_> = view.items.iter().map(|item| (item.video_id.as_str(), item.missing)).collect();
+        assert_eq!(items, vec![("a", false), ("deleted", true)]);
+        assert!(view.items[1].video.is_none());
+    }
+
+    #[test]
+    fn playlists_are_joined_with_discontinuities() {
+        let first = playable("a", "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:4\n#EXTINF:4.0,\nseg0.ts\n#EXT-X-ENDLIST\n");
+        let second = playable("b", "#EXTM3U\n#EXT-X-TARGETDURATION:6\n#EXTINF:6.0,\nseg0.ts\n#EXT-X-ENDLIST\n");
+        // アップロードが完了していない動画は飛ばす
+        let incomplete = Video::for_test("c", alice(), 0);
+
+        let playlist = combined_hls_playlist([&first, &incomplete, &second]).unwrap();
+
+        assert_eq!(
+            playlist,
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:6\n#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n\
+             #EXTINF:4.0,\nicsegment://a/seg0.ts\n\
+             #EXT-X-DISCONTINUITY\n\
+             #EXTINF:6.0,\nicsegment://b/seg0.ts\n\
+             #EXT-X-ENDLIST\n"
+        );
+    }
+
+    #[test]
+    fn collection_without_playable_videos_has_no_playlist() {
+        let mut unpublished = playable("a", "#EXTINF:4.0,\nseg0.ts\n");
+        unpublished.published = false;
+        assert!(combined_hls_playlist([&unpublished, &Video::for_test("b", alice(), 0)]).is_none());
+    }
+}
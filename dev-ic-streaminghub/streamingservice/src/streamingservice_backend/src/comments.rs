@@ -0,0 +1,355 @@
+// 動画へのコメント
+//
+// ログインしたプリンシパルだけが投稿でき、再生位置を付けたり、既存のコメントに返信してスレッドにできる。
+// 投稿者は自分のコメントを編集・削除でき、動画の所有者はコメントを非表示にできる。
+// 削除したコメントは返信のスレッドを保つため、本文だけを消して残す。
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::error::{ApiError, ApiResult};
+use crate::rate_limit::{self, Limit};
+use crate::{authenticated_caller, ensure_video_visible, VIDEOS};
+
+const MAX_COMMENT_LEN: usize = 2_000;
+const DEFAULT_LIMIT: u32 = 20;
+const MAX_LIMIT: u32 = 100;
+const ANONYMOUS_CALLER: &str = "Anonymous callers cannot comment";
+const POST_COMMENT_LIMIT: Limit = Limit {
+    bucket: "post_comment",
+    max_calls: 10,
+    window_secs: 60,
+};
+
+#[derive(CandidType, Deserialize, Clone)]
+pub(crate) struct Comment {
+    id: u64,
+    author: Principal,
+    parent_id: Option<u64>,
+    body: String,
+    playback_position_ms: Option<u64>, // コメントを付けた再生位置
+    created_at: u64,
+    edited_at: Option<u64>,
+    deleted: bool,
+    hidden: bool, // 動画の所有者が非表示にしたかどうか
+    reply_count: u64,        // 返信の数 (非表示の返信を含む)
+    hidden_reply_count: u64, // 非表示の返信の数
+}
+
+impl Comment {
+    /// 呼び出し元に見せる返信の数 (非表示の返信は動画の所有者にだけ数える)
+    fn visible_reply_count(&self, is_manager: bool) -> u64 {
+        if is_manager {
+            self.reply_count
+        } else {
+            self.reply_count - self.hidden_reply_count
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CommentView {
+    pub id: u64,
+    pub author: Principal,
+    pub parent_id: Option<u64>,
+    pub body: String,
+    pub playback_position_ms: Option<u64>,
+    pub created_at: u64,
+    pub edited_at: Option<u64>,
+    pub deleted: bool,
+    pub hidden: bool,
+    pub reply_count: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CommentPage {
+    pub comments: Vec<CommentView>,
+    pub next_cursor: Option<u64>, // 続きがある場合に次の呼び出しで渡す
+}
+
+// アップグレード時に退避する状態
+#[derive(CandidType, Deserialize, Default)]
+pub(crate) struct CommentsState {
+    comments: HashMap<String, BTreeMap<u64, Comment>>, // 動画ID -> (コメントID -> コメント)
+    next_id: u64,
+}
+
+thread_local! {
+    static COMMENTS: RefCell<CommentsState> = RefCell::new(CommentsState::default());
+}
+
+pub(crate) fn save() -> CommentsState {
+    COMMENTS.with(|comments| comments.take())
+}
+
+pub(crate) fn restore(state: CommentsState) {
+    COMMENTS.with(|comments| *comments.borrow_mut() = state);
+}
+
+pub(crate) fn remove(video_id: &str) {
+    COMMENTS.with(|comments| comments.borrow_mut().comments.remove(video_id));
+}
+
+fn validate_body(body: &str) -> Result<String, ApiError> {
+    let body = body.trim();
+    if body.is_empty() {
+        return Err(ApiError::invalid_argument("body", "Comment must not be empty"));
+    }
+    if body.chars().count() > MAX_COMMENT_LEN {
+        return Err(ApiError::invalid_argument(
+            "body",
+            format!("Comment is longer than {} characters", MAX_COMMENT_LEN),
+        ));
+    }
+    Ok(body.to_string())
+}
+
+/// 投稿者本人であることを確認してからコメントを更新する
+fn update_own_comment(
+    video_id: &str,
+    comment_id: u64,
+    update: impl FnOnce(&mut Comment),
+) -> ApiResult<()> {
+    let caller = match authenticated_caller(ANONYMOUS_CALLER) {
+        Ok(caller) => caller,
+        Err(e) => return ApiResult::Err(e),
+    };
+    COMMENTS.with(|comments| {
+        let mut comments = comments.borrow_mut();
+        match comments.comments.get_mut(video_id).and_then(|c| c.get_mut(&comment_id)) {
+            Some(comment) if comment.author != caller => {
+                ApiResult::Err(ApiError::unauthorized("Caller is not the author of the comment"))
+            }
+            Some(comment) if comment.deleted => {
+                ApiResult::Err(ApiError::conflict("Comment has been deleted"))
+            }
+            Some(comment) => {
+                update(comment);
+                ApiResult::Ok(())
+            }
+            None => ApiResult::Err(ApiError::not_found("Comment")),
+        }
+    })
+}
+
+/// コメントを投稿する。parent_id を指定すると返信になる
+#[update]
+fn post_comment(
+    video_id: String,
+    body: String,
+    parent_id: Option<u64>,
+    playback_position_ms: Option<u64>,
+) -> ApiResult<u64> {
+    let caller = match authenticated_caller(ANONYMOUS_CALLER) {
+        Ok(caller) => caller,
+        Err(e) => return ApiResult::Err(e),
+    };
+    let body = match validate_body(&body) {
+        Ok(body) => body,
+        Err(e) => return ApiResult::Err(e),
+    };
+    if let Err(e) = ensure_video_visible(&video_id, &caller) {
+        return ApiResult::Err(e);
+    }
+    if let Err(e) = rate_limit::check(&POST_COMMENT_LIMIT, caller) {
+        return ApiResult::Err(e);
+    }
+
+    COMMENTS.with(|comments| {
+        let mut state = comments.borrow_mut();
+        let id = state.next_id;
+        let video_comments = state.comments.entry(video_id).or_default();
+        if let Some(parent_id) = parent_id {
+            match video_comments.get_mut(&parent_id) {
+                Some(parent) => parent.reply_count += 1,
+                None => return ApiResult::Err(ApiError::not_found("Parent comment")),
+            }
+        }
+        video_comments.insert(id, Comment {
+            id,
+            author: caller,
+            parent_id,
+            body,
+            playback_position_ms,
+            created_at: ic_cdk::api::time(),
+            edited_at: None,
+            deleted: false,
+            hidden: false,
+            reply_count: 0,
+            hidden_reply_count: 0,
+        });
+        state.next_id += 1;
+        ApiResult::Ok(id)
+    })
+}
+
+/// 自分のコメントを編集する
+#[update]
+fn edit_comment(video_id: String, comment_id: u64, body: String) -> ApiResult<()> {
+    let body = match validate_body(&body) {
+        Ok(body) => body,
+        Err(e) => return ApiResult::Err(e),
+    };
+    update_own_comment(&video_id, comment_id, |comment| {
+        comment.body = body;
+        comment.edited_at = Some(ic_cdk::api::time());
+    })
+}
+
+/// 自分のコメントを削除する (返信は残る)
+#[update]
+fn delete_comment(video_id: String, comment_id: u64) -> ApiResult<()> {
+    update_own_comment(&video_id, comment_id, |comment| {
+        comment.body.clear();
+        comment.deleted = true;
+    })
+}
+
+/// コメントの表示・非表示を切り替える (動画の所有者またはコントローラーのみ)
+#[update]
+fn hide_comment(video_id: String, comment_id: u64, hidden: bool) -> ApiResult<()> {
+    let caller = ic_cdk::caller();
+    match VIDEOS.with(|videos| videos.borrow().get(&video_id).map(|video| video.can_manage(&caller))) {
+        Some(true) => {}
+        Some(false) => return ApiResult::Err(ApiError::unauthorized("Caller is not the owner of the video")),
+        None => return ApiResult::Err(ApiError::not_found("Video")),
+    }
+    COMMENTS.with(|comments| {
+        let mut comments = comments.borrow_mut();
+        match comments.comments.get_mut(&video_id) {
+            Some(video_comments) => set_hidden(video_comments, comment_id, hidden).into(),
+            None => ApiResult::Err(ApiError::not_found("Comment")),
+        }
+    })
+}
+
+/// コメントの表示・非表示を切り替え、返信であれば親の非表示の返信の数を合わせる
+fn set_hidden(video_comments: &mut BTreeMap<u64, Comment>, comment_id: u64, hidden: bool) -> Result<(), ApiError> {
+    let comment = video_comments.get_mut(&comment_id).ok_or_else(|| ApiError::not_found("Comment"))?;
+    if comment.hidden == hidden {
+        return Ok(());
+    }
+    comment.hidden = hidden;
+    if let Some(parent) = comment.parent_id.and_then(|parent_id| video_comments.get_mut(&parent_id)) {
+        if hidden {
+            parent.hidden_reply_count += 1;
+        } else {
+            parent.hidden_reply_count -= 1;
+        }
+    }
+    Ok(())
+}
+
+/// 動画のコメントを古い順に返す
+/// parent_id を省略するとトップレベルのコメント、指定するとその返信を返す
+/// 非表示にされたコメントは動画の所有者にだけ返す
+#[query]
+fn list_comments(
+    video_id: String,
+    parent_id: Option<u64>,
+    limit: Option<u32>,
+    cursor: Option<u64>,
+) -> ApiResult<CommentPage> {
+    let caller = ic_cdk::caller();
+    let is_manager = VIDEOS.with(|videos| {
+        videos
+            .borrow()
+            .get(&video_id)
+            .filter(|video| video.is_servable_to(&caller))
+            .map(|video| video.can_manage(&caller))
+    });
+    let Some(is_manager) = is_manager else {
+        return ApiResult::Err(ApiError::not_found("Video"));
+    };
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT) as usize;
+
+    COMMENTS.with(|comments| {
+        let comments = comments.borrow();
+        let Some(video_comments) = comments.comments.get(&video_id) else {
+            return ApiResult::Ok(CommentPage { comments: Vec::new(), next_cursor: None });
+        };
+        let visible = |comment: &&Comment| is_manager || !comment.hidden;
+        let mut page: Vec<CommentView> = video_comments
+            .range(cursor.unwrap_or(0)..)
+            .map(|(_, comment)| comment)
+            .filter(|comment| comment.parent_id == parent_id)
+            .filter(visible)
+            .take(limit + 1)
+            .map(|comment| CommentView {
+                id: comment.id,
+                author: comment.author,
+                parent_id: comment.parent_id,
+                body: comment.body.clone(),
+                playback_position_ms: comment.playback_position_ms,
+                created_at: comment.created_at,
+                edited_at: comment.edited_at,
+                deleted: comment.deleted,
+                hidden: comment.hidden,
+                reply_count: comment.visible_reply_count(is_manager),
+            })
+            .collect();
+        let next_cursor = if page.len() > limit { page.pop().map(|comment| comment.id) } else { None };
+        ApiResult::Ok(CommentPage { comments: page, next_cursor })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn comment(id: u64, parent_id: Option<u64>) -> Comment {
+        Comment {
+            id,
+            author: Principal::self_authenticating(b"alice"),
+            parent_id,
+            body: "hello".to_string(),
+            playback_position_ms: None,
+            created_at: 0,
+            edited_at: None,
+            deleted: false,
+            hidden: false,
+            reply_count: 0,
+            hidden_reply_count: 0,
+        }
+    }
+
+    // 親コメント 0 に返信 1, 2 が付いたスレッド
+    fn thread() -> BTreeMap<u64, Comment> {
+        let mut parent = comment(0, None);
+        parent.reply_count = 2;
+        BTreeMap::from([(0, parent), (1, comment(1, Some(0))), (2, comment(2, Some(0)))])
+    }
+
+    #[test]
+    fn hidden_replies_are_counted_only_for_managers() {
+        let mut comments = thread();
+        set_hidden(&mut comments, 1, true).unwrap();
+
+        assert_eq!(comments[&0].visible_reply_count(true), 2);
+        assert_eq!(comments[&0].visible_reply_count(false), 1);
+    }
+
+    // 同じ状態を繰り返し指定しても数はずれない
+    #[test]
+    fn hiding_twice_and_unhiding_keeps_the_count_consistent() {
+        let mut comments = thread();
+        set_hidden(&mut comments, 1, true).unwrap();
+        set_hidden(&mut comments, 1, true).unwrap();
+        assert_eq!(comments[&0].hidden_reply_count, 1);
+
+        set_hidden(&mut comments, 1, false).unwrap();
+        set_hidden(&mut comments, 1, false).unwrap();
+        assert_eq!(comments[&0].hidden_reply_count, 0);
+        assert_eq!(comments[&0].visible_reply_count(false), 2);
+    }
+
+    #[test]
+    fn hiding_a_top_level_comment_does_not_touch_reply_counts() {
+        let mut comments = thread();
+        set_hidden(&mut comments, 0, true).unwrap();
+        assert!(comments[&0].hidden);
+        assert_eq!(comments[&0].visible_reply_count(false), 2);
+        assert!(matches!(set_hidden(&mut comments, 9, true), Err(ApiError::NotFound { .. })));
+    }
+}
@@ -0,0 +1,96 @@
+// API 共通のエラー型と結果型
+//
+// エンドポイントは ApiResult<T> を返し、失敗の種類を ApiError で表す。
+// 以前の ok / err(text) 形式は LegacyResult<T> として、非推奨の旧エンドポイントの互換用にだけ残す。
+use candid::{CandidType, Deserialize};
+use std::fmt;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ApiError {
+    NotFound { message: String },
+    Unauthorized { message: String },
+    InvalidArgument { field: String, message: String },
+    QuotaExceeded { message: String },
+    Conflict { message: String },
+    Internal { message: String },
+}
+
+impl ApiError {
+    /// "<what> not found" というメッセージの NotFound
+    pub fn not_found(what: &str) -> Self {
+        ApiError::NotFound { message: format!("{} not found", what) }
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        ApiError::Unauthorized { message: message.into() }
+    }
+
+    pub fn invalid_argument(field: &str, message: impl Into<String>) -> Self {
+        ApiError::InvalidArgument { field: field.to_string(), message: message.into() }
+    }
+
+    pub fn quota_exceeded(message: impl Into<String>) -> Self {
+        ApiError::QuotaExceeded { message: message.into() }
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        ApiError::Conflict { message: message.into() }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::NotFound { message }
+            | ApiError::Unauthorized { message }
+            | ApiError::InvalidArgument { message, .. }
+            | ApiError::QuotaExceeded { message }
+            | ApiError::Conflict { message }
+            | ApiError::Internal { message } => f.write_str(message),
+        }
+    }
+}
+
+// エンドポイント共通の結果型
+#[derive(CandidType, Deserialize)]
+pub enum ApiResult<T> {
+    #[serde(rename = "ok")]
+    Ok(T),
+    #[serde(rename = "err")]
+    Err(ApiError),
+}
+
+impl<T> From<Result<T, ApiError>> for ApiResult<T> {
+    fn from(result: Result<T, ApiError>) -> Self {
+        match result {
+            Ok(value) => ApiResult::Ok(value),
+            Err(e) => ApiResult::Err(e),
+        }
+    }
+}
+
+impl<T> ApiResult<T> {
+    /// 旧エンドポイント用の結果に変換する。成功時の値は f で変換する
+    pub fn legacy<U>(self, f: impl FnOnce(T) -> U) -> LegacyResult<U> {
+        match self {
+            ApiResult::Ok(value) => LegacyResult::Ok(f(value)),
+            ApiResult::Err(e) => LegacyResult::Err(e.to_string()),
+        }
+    }
+}
+
+impl<T> From<ApiResult<T>> for LegacyResult<T> {
+    fn from(result: ApiResult<T>) -> Self {
+        result.legacy(|value| value)
+    }
+}
+
+// 非推奨の旧エンドポイントが返す ok / err(text) 形式の結果
+#[derive(CandidType, Deserialize)]
+pub enum LegacyResult<T> {
+    //NOTE: #[serde(rename = "ok")] をつけないと Cannot find field hash _17724_ になる
+    #[serde(rename = "ok")]
+    Ok(T),
+    #[serde(rename = "err")]
+    Err(String),
+}
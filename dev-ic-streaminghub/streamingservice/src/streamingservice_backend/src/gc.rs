@@ -0,0 +1,254 @@
+// アップロードが途中で放棄された動画のガベージコレクション
+//
+// finalize されず、チャンクも揃わないまま TTL を超えて更新の無い動画を
+// 定期タイマーで削除し、削除内容を監査ログに残す。
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::*;
+use ic_cdk_timers::TimerId;
+use std::cell::RefCell;
+use std::time::Duration;
+
+use crate::error::{ApiError, ApiResult};
+use crate::{discard_video, ensure_controller, take_video, Video, VIDEOS};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+// 監査ログの最大保持件数 (古いものから捨てる)
+const MAX_AUDIT_LOG_LEN: usize = 1_000;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct GcConfig {
+    pub upload_ttl_secs: u64, // 最後のアップロードからこの秒数を超えたものが対象
+    pub interval_secs: u64,   // タイマーの実行間隔
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        GcConfig {
+            upload_ttl_secs: 24 * 60 * 60,
+            interval_secs: 60 * 60,
+        }
+    }
+}
+
+// GC 対象の動画情報
+#[derive(CandidType, Deserialize, Clone)]
+pub struct GcCandidate {
+    pub video_id: String,
+    pub title: String,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub stored_bytes: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum GcTrigger {
+    Timer,
+    Manual,
+}
+
+// 削除した動画の監査ログ
+#[derive(CandidType, Deserialize, Clone)]
+pub struct GcAuditEntry {
+    pub video_id: String,
+    pub title: String,
+    pub reclaimed_bytes: u64,
+    pub deleted_at: u64,
+    pub trigger: GcTrigger,
+}
+
+// アップグレード時に退避する状態
+#[derive(CandidType, Deserialize, Default)]
+pub(crate) struct GcState {
+    config: GcConfig,
+    audit_log: Vec<GcAuditEntry>,
+}
+
+thread_local! {
+    static GC_CONFIG: RefCell<GcConfig> = RefCell::new(GcConfig::default());
+    static GC_AUDIT_LOG: RefCell<Vec<GcAuditEntry>> = const { RefCell::new(Vec::new()) };
+    static GC_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+}
+
+pub(crate) fn save() -> GcState {
+    GcState {
+        config: GC_CONFIG.with(|config| config.take()),
+        audit_log: GC_AUDIT_LOG.with(|log| log.take()),
+    }
+}
+
+pub(crate) fn restore(state: GcState) {
+    GC_CONFIG.with(|config| *config.borrow_mut() = state.config);
+    GC_AUDIT_LOG.with(|log| *log.borrow_mut() = state.audit_log);
+}
+
+/// GC タイマーを (再) 設定する
+/// init / post_upgrade と設定変更時に呼ばれる
+pub(crate) fn start_timer() {
+    let interval = GC_CONFIG.with(|config| config.borrow().interval_secs).max(1);
+    let timer_id = ic_cdk_timers::set_timer_interval(Duration::from_secs(interval), || {
+        let deleted = collect(GcTrigger::Timer);
+        if !deleted.is_empty() {
+            ic_cdk::println!("GC deleted {} abandoned uploads", deleted.len());
+        }
+    });
+    GC_TIMER.with(|timer| {
+        if let Some(old_timer_id) = timer.borrow_mut().replace(timer_id) {
+            ic_cdk_timers::clear_timer(old_timer_id);
+        }
+    });
+}
+
+fn is_abandoned(video: &Video, now: u64, ttl_secs: u64) -> bool {
+    !video.finalized
+        && !video.is_upload_complete()
+        && now.saturating_sub(video.updated_at) > ttl_secs.saturating_mul(NANOS_PER_SEC)
+}
+
+fn find_candidates(now: u64) -> Vec<GcCandidate> {
+    let ttl_secs = GC_CONFIG.with(|config| config.borrow().upload_ttl_secs);
+    VIDEOS.with(|videos| {
+        videos
+            .borrow()
+            .values()
+            .filter(|video| is_abandoned(video, now, ttl_secs))
+            .map(|video| GcCandidate {
+                video_id: video.id.clone(),
+                title: video.title.clone(),
+                created_at: video.created_at,
+                updated_at: video.updated_at,
+                stored_bytes: video.stored_segment_bytes(),
+            })
+            .collect()
+    })
+}
+
+fn collect(trigger: GcTrigger) -> Vec<GcAuditEntry> {
+    let now = ic_cdk::api::time();
+    let deleted: Vec<GcAuditEntry> = find_candidates(now)
+        .into_iter()
+        .filter_map(|candidate| take_video(&candidate.video_id))
+        .map(|video| GcAuditEntry {
+            reclaimed_bytes: discard_video(&video),
+            video_id: video.id,
+            title: video.title,
+            deleted_at: now,
+            trigger: trigger.clone(),
+        })
+        .collect();
+
+    GC_AUDIT_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        log.extend(deleted.iter().cloned());
+        if log.len() > MAX_AUDIT_LOG_LEN {
+            let overflow = log.len() - MAX_AUDIT_LOG_LEN;
+            log.drain(..overflow);
+        }
+    });
+    deleted
+}
+
+/// GC を即時実行し、削除した動画の監査ログを返す (コントローラーのみ)
+#[update]
+fn gc_now() -> ApiResult<Vec<GcAuditEntry>> {
+    match ensure_controller() {
+        Ok(()) => ApiResult::Ok(collect(GcTrigger::Manual)),
+        Err(e) => ApiResult::Err(e),
+    }
+}
+
+/// 現時点で GC 対象となる動画を削除せずに返す (dry-run, コントローラーのみ)
+#[query]
+fn list_gc_candidates() -> ApiResult<Vec<GcCandidate>> {
+    match ensure_controller() {
+        Ok(()) => ApiResult::Ok(find_candidates(ic_cdk::api::time())),
+        Err(e) => ApiResult::Err(e),
+    }
+}
+
+/// GC で削除した動画の監査ログを返す (コントローラーのみ)
+#[query]
+fn get_gc_audit_log() -> ApiResult<Vec<GcAuditEntry>> {
+    match ensure_controller() {
+        Ok(()) => ApiResult::Ok(GC_AUDIT_LOG.with(|log| log.borrow().clone())),
+        Err(e) => ApiResult::Err(e),
+    }
+}
+
+#[query]
+fn get_gc_config() -> GcConfig {
+    GC_CONFIG.with(|config| config.borrow().clone())
+}
+
+/// TTL と実行間隔を変更し、タイマーを再設定する (コントローラーのみ)
+#[update]
+fn set_gc_config(config: GcConfig) -> ApiResult<GcConfig> {
+    if let Err(e) = ensure_controller() {
+        return ApiResult::Err(e);
+    }
+    if config.upload_ttl_secs == 0 || config.interval_secs == 0 {
+        return ApiResult::Err(ApiError::invalid_argument(
+            "config",
+            "upload_ttl_secs and interval_secs must be greater than 0",
+        ));
+    }
+    GC_CONFIG.with(|current| *current.borrow_mut() = config.clone());
+    start_timer();
+    ApiResult::Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{insert_video, SegmentInfo};
+    use candid::Principal;
+
+    const TTL_SECS: u64 = 24 * 60 * 60;
+    const NOW: u64 = 10 * TTL_SECS * NANOS_PER_SEC;
+
+    fn uploaded_at(id: &str, updated_at: u64) -> Video {
+        Video::for_test(id, Principal::self_authenticating(b"owner"), updated_at)
+    }
+
+    #[test]
+    fn stale_incomplete_upload_is_abandoned() {
+        let video = uploaded_at("stale", NOW - TTL_SECS * NANOS_PER_SEC - 1);
+        assert!(is_abandoned(&video, NOW, TTL_SECS));
+    }
+
+    // TTL ちょうどの動画はまだ対象にしない
+    #[test]
+    fn upload_within_ttl_is_not_abandoned() {
+        let video = uploaded_at("recent", NOW - TTL_SECS * NANOS_PER_SEC);
+        assert!(!is_abandoned(&video, NOW, TTL_SECS));
+    }
+
+    #[test]
+    fn finalized_or_complete_videos_are_not_abandoned() {
+        let mut finalized = uploaded_at("finalized", 0);
+        finalized.finalized = true;
+        assert!(!is_abandoned(&finalized, NOW, TTL_SECS));
+
+        let mut complete = uploaded_at("complete", 0);
+        complete.playlist = Some("#EXTM3U".to_string());
+        complete.segments = vec![SegmentInfo {
+            chunks: vec![Some([0; 32])],
+            total_chunk_count: 1,
+        }];
+        assert!(!is_abandoned(&complete, NOW, TTL_SECS));
+    }
+
+    // 更新日時が未来 (時計の巻き戻り) でもオーバーフローせず対象にしない
+    #[test]
+    fn upload_updated_in_the_future_is_not_abandoned() {
+        let video = uploaded_at("future", NOW + 1);
+        assert!(!is_abandoned(&video, NOW, TTL_SECS));
+    }
+
+    #[test]
+    fn candidates_are_the_abandoned_videos() {
+        insert_video(uploaded_at("stale", 0));
+        insert_video(uploaded_at("recent", NOW));
+        let candidates: Vec<String> = find_candidates(NOW).into_iter().map(|c| c.video_id).collect();
+        assert_eq!(candidates, ["stale"]);
+    }
+}
@@ -0,0 +1,149 @@
+// プリンシパルごとの視聴履歴
+//
+// 再生位置を保存して「続きから見る」を実現する。履歴は本人だけが参照でき、
+// 匿名の呼び出し元の履歴は保存しない。
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::error::ApiResult;
+use crate::{authenticated_caller, ensure_video_visible, VideoSummary, VIDEOS};
+
+// 1 人あたりの履歴の最大件数 (古いものから捨てる)
+const MAX_HISTORY_LEN: usize = 200;
+const DEFAULT_LIMIT: u32 = 20;
+const MAX_LIMIT: u32 = 100;
+const ANONYMOUS_CALLER: &str = "Anonymous callers have no watch history";
+
+#[derive(CandidType, Deserialize, Clone)]
+pub(crate) struct WatchEntry {
+    video_id: String,
+    position_ms: u64, // 再開する再生位置
+    completed: bool,
+    watched_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct WatchHistoryItem {
+    pub video: VideoSummary,
+    pub position_ms: u64,
+    pub completed: bool,
+    pub watched_at: u64,
+}
+
+thread_local! {
+    // プリンシパル -> 視聴履歴 (新しいものが末尾)
+    static WATCH_HISTORY: RefCell<HashMap<Principal, Vec<WatchEntry>>> = RefCell::new(HashMap::new());
+}
+
+pub(crate) fn save() -> HashMap<Principal, Vec<WatchEntry>> {
+    WATCH_HISTORY.with(|history| history.take())
+}
+
+pub(crate) fn restore(state: HashMap<Principal, Vec<WatchEntry>>) {
+    WATCH_HISTORY.with(|history| *history.borrow_mut() = state);
+}
+
+pub(crate) fn remove(video_id: &str) {
+    WATCH_HISTORY.with(|history| {
+        history.borrow_mut().retain(|_, entries| {
+            entries.retain(|entry| entry.video_id != video_id);
+            !entries.is_empty()
+        });
+    });
+}
+
+fn list_history(only_unfinished: bool, limit: Option<u32>) -> ApiResult<Vec<WatchHistoryItem>> {
+    let caller = match authenticated_caller(ANONYMOUS_CALLER) {
+        Ok(caller) => caller,
+        Err(e) => return ApiResult::Err(e),
+    };
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT) as usize;
+    let items = WATCH_HISTORY.with(|history| {
+        VIDEOS.with(|videos| {
+            let videos = videos.borrow();
+            history
+                .borrow()
+                .get(&caller)
+                .into_iter()
+                .flat_map(|entries| entries.iter().rev())
+                .filter(|entry| !only_unfinished || (!entry.completed && entry.position_ms > 0))
+                .filter_map(|entry| {
+                    let video = videos.get(&entry.video_id).filter(|video| video.is_servable_to(&caller));
+                    video.map(|video| WatchHistoryItem {
+                        video: video.summary(),
+                        position_ms: entry.position_ms,
+                        completed: entry.completed,
+                        watched_at: entry.watched_at,
+                    })
+                })
+                .take(limit)
+                .collect()
+        })
+    });
+    ApiResult::Ok(items)
+}
+
+/// 再生位置を記録する。同じ動画の履歴は最新の位置で置き換える
+#[update]
+fn update_watch_progress(video_id: String, position_ms: u64, completed: bool) -> ApiResult<()> {
+    let caller = match authenticated_caller(ANONYMOUS_CALLER) {
+        Ok(caller) => caller,
+        Err(e) => return ApiResult::Err(e),
+    };
+    if let Err(e) = ensure_video_visible(&video_id, &caller) {
+        return ApiResult::Err(e);
+    }
+    WATCH_HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        let entries = history.entry(caller).or_default();
+        entries.retain(|entry| entry.video_id != video_id);
+        entries.push(WatchEntry {
+            video_id,
+            position_ms,
+            completed,
+            watched_at: ic_cdk::api::time(),
+        });
+        if entries.len() > MAX_HISTORY_LEN {
+            let overflow = entries.len() - MAX_HISTORY_LEN;
+            entries.drain(..overflow);
+        }
+    });
+    ApiResult::Ok(())
+}
+
+/// 呼び出し元の視聴履歴を新しい順に返す
+#[query]
+fn get_watch_history(limit: Option<u32>) -> ApiResult<Vec<WatchHistoryItem>> {
+    list_history(false, limit)
+}
+
+/// 途中まで視聴した動画を新しい順に返す (「続きから見る」用)
+#[query]
+fn get_continue_watching(limit: Option<u32>) -> ApiResult<Vec<WatchHistoryItem>> {
+    list_history(true, limit)
+}
+
+/// 呼び出し元の視聴履歴から動画を削除する。video_id を省略すると全件削除する
+#[update]
+fn clear_watch_history(video_id: Option<String>) -> ApiResult<()> {
+    let caller = match authenticated_caller(ANONYMOUS_CALLER) {
+        Ok(caller) => caller,
+        Err(e) => return ApiResult::Err(e),
+    };
+    WATCH_HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        match video_id {
+            Some(video_id) => {
+                if let Some(entries) = history.get_mut(&caller) {
+                    entries.retain(|entry| entry.video_id != video_id);
+                }
+            }
+            None => {
+                history.remove(&caller);
+            }
+        }
+    });
+    ApiResult::Ok(())
+}
@@ -1,8 +1,48 @@
 use ic_cdk_macros::*;
-use candid::{CandidType, Deserialize};
+use candid::{CandidType, Deserialize, Principal};
 use std::cell::RefCell;
 use std::collections::HashMap;
 
+mod admin;
+mod analytics;
+mod batch;
+mod blobs;
+mod channel;
+mod collections;
+mod comments;
+mod error;
+mod gc;
+mod history;
+mod memory;
+mod rate_limit;
+mod reactions;
+mod reports;
+mod schedule;
+mod search;
+mod tags;
+mod trash;
+
+use blobs::BlobHash;
+use error::{ApiError, ApiResult, LegacyResult};
+
+// export_candid! はエンドポイントのシグネチャに現れる型名をこのモジュールで解決する
+use admin::{AdminAuditEntry, UploadRecord};
+use analytics::{PlaybackEvent, VideoAnalytics};
+use batch::{ChunkFetchEntryResult, ChunkRef, ChunkUploadEntry, ChunkUploadEntryResult};
+use blobs::BlobStats;
+use channel::{ChannelInfo, InitArgs};
+use collections::{CollectionSummary, CollectionView, Visibility};
+use comments::CommentPage;
+use gc::{GcAuditEntry, GcCandidate, GcConfig};
+use history::WatchHistoryItem;
+use reactions::{Reaction, ReactionCounts};
+use reports::{ModerationAction, ModerationNotice, ModerationStatus, Report, ReportStatus};
+use schedule::VideoSchedule;
+use search::SearchVideosResponse;
+use tags::{TagCount, VideosByTagResponse};
+use trash::{TrashConfig, TrashedVideoInfo};
+
+// アップグレードでは VersionedState に含めて退避するため、フィールドを変えるときは VersionedState のバージョンを上げる
 #[derive(CandidType, Deserialize)]
 struct Video {
     id: String,
@@ -12,57 +52,127 @@ struct Video {
     hash: String,
     playlist: Option<String>,
     thumbnail: Option<Vec<u8>>,
-    version: String
+    version: String,
+    owner: Principal, // create_video を呼び出したプリンシパル
+    created_at: u64, // 作成日時 (ns)
+    updated_at: u64, // 最後にアップロードがあった日時 (ns)
+    finalized: bool, // finalize_video で完了が確定したかどうか
+    tags: Vec<String>, // 正規化済みのタグ (昇順)
+    category: Option<String>,
+    publish_at: Option<u64>, // この時刻 (ns) になるまで一覧に表示しない
+    unpublish_at: Option<u64>, // この時刻 (ns) 以降は一覧に表示しない
+    published: bool, // 公開スケジュールに従ってタイマーが切り替える
+    moderation: reports::ModerationStatus, // 通報への対応状況
 }
 
-// 各セグメントのアップロード状態を保持する構造体
-#[derive(CandidType, Deserialize, Clone, Default)] // Defaultを追加しておくと初期化が楽になる
-pub struct SegmentInfo {
-    pub chunks: Vec<Vec<u8>>, // このセグメントに属するチャンクデータのリスト
-    pub total_chunk_count: u32, // このセグメントで期待されるチャンクの総数
-}
-
-#[derive(CandidType, Deserialize)]
-enum UploadResult {
-    //NOTE: #[serde(rename = "ok")] をつけないと Cannot find field hash _17724_ になる
-    //Cannot find field hash となるときはClassをResponse に設定したほうが良い
-    #[serde(rename = "ok")]
-    Ok(String),
-    #[serde(rename = "err")]
-    Err(String),
-}
-
-#[derive(CandidType, Deserialize)]
-enum VideoChunkResult {
-    #[serde(rename = "ok")]
-    Ok(Vec<u8>),
-    #[serde(rename = "err")]
-    Err(String),
+// 一覧系 API で返す動画の概要
+#[derive(CandidType, Deserialize, Clone)]
+pub struct VideoSummary {
+    pub video_id: String,
+    pub title: String,
+    pub description: String,
+    pub owner: Principal,
+    pub tags: Vec<String>,
+    pub category: Option<String>,
+    pub created_at: u64,
+    pub published: bool,
+    pub reactions: reactions::ReactionCounts,
 }
 
-#[derive(CandidType, Deserialize)]
-enum GetHlsPlaylistResult {
-    #[serde(rename = "ok")]
-    Ok(String),
-    #[serde(rename = "err")]
-    Err(String),
+impl Video {
+    fn summary(&self) -> VideoSummary {
+        VideoSummary {
+            video_id: self.id.clone(),
+            title: self.title.clone(),
+            description: self.description.clone(),
+            owner: self.owner,
+            tags: self.tags.clone(),
+            category: self.category.clone(),
+            created_at: self.created_at,
+            published: self.published,
+            reactions: reactions::counts(&self.id),
+        }
+    }
+
+    /// 一覧・検索に表示してよいかどうか
+    fn is_listed(&self) -> bool {
+        self.published && !self.moderation.is_hidden()
+    }
+
+    /// 呼び出し元に動画の情報や再生用のデータを返してよいかどうか
+    /// 非表示にされた動画と公開期間外の動画は所有者と管理者にだけ返す
+    fn is_servable_to(&self, caller: &Principal) -> bool {
+        self.is_listed() || self.can_manage(caller) || admin::is_admin(caller)
+    }
+
+    /// 動画の所有者、またはコントローラーであれば true
+    fn can_manage(&self, caller: &Principal) -> bool {
+        self.owner == *caller || ic_cdk::api::is_controller(caller)
+    }
+
+    /// プレイリストと全セグメントの全チャンクが揃っているかを返す
+    fn is_upload_complete(&self) -> bool {
+        self.playlist.is_some()
+            && !self.segments.is_empty()
+            && self.segments.iter().all(|segment| {
+                segment.total_chunk_count > 0
+                    && segment.chunks.len() >= segment.total_chunk_count as usize
+                    && segment.chunks.iter().all(|chunk| chunk.is_some())
+            })
+    }
+
+    /// セグメントとして参照しているバイト数 (他の動画と共有しているチャンクも含む)
+    fn stored_segment_bytes(&self) -> u64 {
+        self.chunk_hashes().filter_map(blobs::size_of).sum()
+    }
+
+    /// 参照している全チャンクのハッシュ
+    fn chunk_hashes(&self) -> impl Iterator<Item = &BlobHash> {
+        self.segments
+            .iter()
+            .flat_map(|segment| segment.chunks.iter())
+            .flatten()
+    }
+
+    /// 参照している全チャンクの参照カウントを減らし、解放されたバイト数を返す
+    /// 動画を完全に削除するときに呼ぶ
+    fn release_chunks(&self) -> u64 {
+        self.chunk_hashes().map(blobs::release).sum()
+    }
 }
 
-
-#[derive(CandidType, Deserialize)]
-enum GetHlsSegmentChunkResult {
-    #[serde(rename = "ok")]
-    Ok(Vec<u8>),
-    #[serde(rename = "err")]
-    Err(String),
+#[cfg(test)]
+impl Video {
+    /// テスト用の作成直後の動画 (created_at / updated_at は now)
+    pub(crate) fn for_test(id: &str, owner: Principal, now: u64) -> Video {
+        Video {
+            id: id.to_string(),
+            title: format!("Video {}", id),
+            description: String::new(),
+            segments: Vec::new(),
+            hash: String::new(),
+            playlist: None,
+            thumbnail: None,
+            version: "1".to_string(),
+            owner,
+            created_at: now,
+            updated_at: now,
+            finalized: false,
+            tags: Vec::new(),
+            category: None,
+            publish_at: None,
+            unpublish_at: None,
+            published: true,
+            moderation: reports::ModerationStatus::Visible,
+        }
+    }
 }
 
-#[derive(CandidType, Deserialize)]
-enum SegmentChunkResult {
-    #[serde(rename = "ok")]
-    Ok(SegmentChunkResponse),
-    #[serde(rename = "err")]
-    Err(String),
+// 各セグメントのアップロード状態を保持する構造体
+#[derive(CandidType, Deserialize, Clone, Default)] // Defaultを追加しておくと初期化が楽になる
+pub struct SegmentInfo {
+    pub chunks: Vec<Option<BlobHash>>, // このセグメントに属するチャンクのハッシュ (実データは blobs に保存)
+    pub total_chunk_count: u32, // このセグメントで期待されるチャンクの総数
 }
 
 // セグメントチャンク取得成功時のレスポンスデータ構造
@@ -72,13 +182,6 @@ pub struct SegmentChunkResponse {
     pub total_chunk_count: u32, // そのセグメントのチャンク総数
 }
 
-#[derive(CandidType, Deserialize)]
-enum SegmentChunkInfoResult {
-    #[serde(rename = "ok")]
-    Ok(Vec<SegmentChunkInfo>),
-    #[serde(rename = "err")]
-    Err(String),
-}
 // セグメント情報
 #[derive(CandidType, Deserialize, Clone)]
 pub struct SegmentChunkInfo {
@@ -86,48 +189,179 @@ pub struct SegmentChunkInfo {
     pub total_chunk_count: u32, // そのセグメントのチャンク総数
 }
 
+thread_local! {
+    static VIDEOS: RefCell<HashMap<String, Video>> = RefCell::new(HashMap::new());
+}
 
-#[derive(CandidType, Deserialize)]
-struct VideoInfo {
-    title: String,
-    description: String,
-    hash: String,
+// アップグレードの間だけ stable memory に退避するヒープ上の状態 (チャンクは blobs.rs が stable memory に直接置く)
+#[derive(CandidType, Deserialize, Default)]
+struct StableState {
+    videos: HashMap<String, Video>,
+    trash: trash::TrashState,
+    gc: gc::GcState,
+    analytics: HashMap<String, analytics::VideoCounters>,
+    comments: comments::CommentsState,
+    reactions: HashMap<String, HashMap<Principal, reactions::Reaction>>,
+    watch_history: HashMap<Principal, Vec<history::WatchEntry>>,
+    collections: collections::CollectionsState,
+    admin: admin::AdminState,
+    reports: reports::ReportsState,
+    channel: channel::ChannelState,
 }
 
+// 退避する状態のバージョン
+// candid は opt 以外のフィールドが欠けているとデコードに失敗する (#[serde(default)] は効かない) ため、
+// Video を含めて状態の形を変えるときは新しいバージョンを追加し、load_state で古いバージョンから移行する
 #[derive(CandidType, Deserialize)]
-enum VideoInfoResult {
-    #[serde(rename = "ok")]
-    Ok(String),
-    #[serde(rename = "err")]
-    Err(String),
+enum VersionedState {
+    V1(StableState),
 }
 
-#[derive(CandidType, Deserialize)]
-enum DeleteVideoResult {
-    #[serde(rename = "ok")]
-    Ok(String),
-    #[serde(rename = "err")]
-    Err(String),
+fn load_state() -> Result<StableState, String> {
+    let bytes = memory::load_upgrade_state()?;
+    match candid::decode_one(&bytes).map_err(|e| format!("Failed to decode the upgrade state: {}", e))? {
+        VersionedState::V1(state) => Ok(state),
+    }
 }
 
-#[derive(CandidType, Deserialize)]
-enum ThumbnailResult {
-    #[serde(rename = "ok")]
-    Ok(Vec<u8>),
-    #[serde(rename = "err")]
-    Err(String),
+/// VIDEOS に動画を登録し、検索・タグのインデックスを更新する
+fn insert_video(video: Video) {
+    search::index_video(&video);
+    tags::index_video(&video);
+    VIDEOS.with(|videos| videos.borrow_mut().insert(video.id.clone(), video));
 }
 
-#[derive(CandidType, Deserialize)]
-enum DownloadVideoResult {
-    #[serde(rename = "ok")]
-    Ok(Vec<u8>),
-    #[serde(rename = "err")]
-    Err(String),
+/// VIDEOS から動画を取り除き、検索・タグのインデックスからも削除する
+fn take_video(video_id: &str) -> Option<Video> {
+    let video = VIDEOS.with(|videos| videos.borrow_mut().remove(video_id));
+    if let Some(video) = &video {
+        search::unindex_video(video_id);
+        tags::unindex_video(video);
+    }
+    video
 }
 
-thread_local! {
-    static VIDEOS: RefCell<HashMap<String, Video>> = RefCell::new(HashMap::new());
+/// 動画を完全に削除するときの後始末
+/// チャンクの参照を解放して付随するデータを削除し、解放されたバイト数を返す
+fn discard_video(video: &Video) -> u64 {
+    analytics::remove(&video.id);
+    comments::remove(&video.id);
+    reactions::remove(&video.id);
+    history::remove(&video.id);
+    video.release_chunks()
+}
+
+// 呼び出し元がこのキャニスターのコントローラーであることを確認する
+fn ensure_controller() -> Result<(), ApiError> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err(ApiError::unauthorized("Caller is not a controller"))
+    }
+}
+
+// 匿名でない呼び出し元を返す。匿名の場合は message の Unauthorized にする
+fn authenticated_caller(message: &str) -> Result<Principal, ApiError> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        Err(ApiError::unauthorized(message))
+    } else {
+        Ok(caller)
+    }
+}
+
+/// 動画が存在し、呼び出し元に見せてよい (is_servable_to) ことを確認する
+/// 見せない動画は存在しない動画と同じく NotFound にする
+fn ensure_video_visible(video_id: &str, caller: &Principal) -> Result<(), ApiError> {
+    let visible = VIDEOS.with(|videos| {
+        videos
+            .borrow()
+            .get(video_id)
+            .is_some_and(|video| video.is_servable_to(caller))
+    });
+    if visible {
+        Ok(())
+    } else {
+        Err(ApiError::not_found("Video"))
+    }
+}
+
+/// 動画を参照し、呼び出し元が所有者またはコントローラーであることを確認してから f を呼ぶ
+fn with_managed_video<T>(
+    video_id: &str,
+    f: impl FnOnce(&mut Video) -> Result<T, ApiError>,
+) -> Result<T, ApiError> {
+    let caller = ic_cdk::caller();
+    VIDEOS.with(|videos| {
+        let mut videos = videos.borrow_mut();
+        let video = videos.get_mut(video_id).ok_or_else(|| ApiError::not_found("Video"))?;
+        if !video.can_manage(&caller) {
+            return Err(ApiError::unauthorized("Caller is not the owner of the video"));
+        }
+        f(video)
+    })
+}
+
+// streamingservice_manager から作成された場合は所有者・タイトル・クォータを受け取る
+#[init]
+fn init(args: Option<InitArgs>) {
+    if let Some(args) = args {
+        channel::init(args);
+    }
+    gc::start_timer();
+    trash::start_timer();
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    let state = StableState {
+        videos: VIDEOS.with(|videos| videos.take()),
+        trash: trash::save(),
+        gc: gc::save(),
+        analytics: analytics::save(),
+        comments: comments::save(),
+        reactions: reactions::save(),
+        watch_history: history::save(),
+        collections: collections::save(),
+        admin: admin::save(),
+        reports: reports::save(),
+        channel: channel::save(),
+    };
+    let bytes = candid::encode_one(VersionedState::V1(state)).expect("Failed to encode the upgrade state");
+    memory::save_upgrade_state(&bytes);
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    // 復元できなければトラップしてアップグレードを取り消す (空の状態で続けるとすべての動画が消える)
+    let state = match memory::layout() {
+        // 状態を保存していなかった最初のバージョンからのアップグレードでは空の状態から始める
+        Ok(memory::Layout::Empty) => Ok(StableState::default()),
+        Ok(memory::Layout::Managed) => load_state(),
+        Err(e) => Err(e),
+    }
+    .unwrap_or_else(|e| ic_cdk::trap(&e));
+    blobs::recount();
+    trash::restore(state.trash);
+    gc::restore(state.gc);
+    analytics::restore(state.analytics);
+    comments::restore(state.comments);
+    reactions::restore(state.reactions);
+    history::restore(state.watch_history);
+    collections::restore(state.collections);
+    admin::restore(state.admin);
+    reports::restore(state.reports);
+    channel::restore(state.channel);
+    // 検索・タグのインデックスは動画から再構築する
+    for (_, video) in state.videos {
+        insert_video(video);
+    }
+
+    gc::start_timer();
+    trash::start_timer();
+    // 停止中に期限が来た切り替えを反映してからタイマーを張り直す
+    schedule::apply_due_transitions(ic_cdk::api::time());
+    schedule::rearm_timer();
 }
 
 //dfx canister call streamingservice_backend greet everyone
@@ -222,9 +456,18 @@ fn greet(name: String) -> String {
 //     })
 // }
 
+/// 動画を作成し、動画IDを返す
 #[update]
-fn create_video(version: String, title: String, description: String) -> String {
-    let video_id = ic_cdk::api::time().to_string();
+fn create_video_v2(version: String, title: String, description: String) -> ApiResult<String> {
+    let caller = ic_cdk::caller();
+    if let Err(e) = channel::ensure_can_create_video(&caller)
+        .and_then(|()| admin::ensure_not_frozen(&caller))
+        .and_then(|()| channel::ensure_video_quota())
+    {
+        return ApiResult::Err(e);
+    }
+    let now = ic_cdk::api::time();
+    let video_id = now.to_string();
     let hash = "";
     let video = Video {
         id: video_id.clone(),
@@ -234,39 +477,74 @@ fn create_video(version: String, title: String, description: String) -> String {
         hash: hash.to_string(),
         playlist: None,
         thumbnail: None,
-        version: version.to_string()
+        version: version.to_string(),
+        owner: caller,
+        created_at: now,
+        updated_at: now,
+        finalized: false,
+        tags: Vec::new(),
+        category: None,
+        publish_at: None,
+        unpublish_at: None,
+        published: true,
+        moderation: reports::ModerationStatus::Visible,
     };
     
-    VIDEOS.with(|videos| {
-        videos.borrow_mut().insert(video_id.clone(), video);
-    });
+    insert_video(video);
     
-    video_id
+    ApiResult::Ok(video_id)
+}
+
+/// 非推奨: create_video_v2 を使うこと (次のリリースで削除する)
+/// エラーを返せないため、失敗した場合はトラップする
+#[update]
+fn create_video(version: String, title: String, description: String) -> String {
+    match create_video_v2(version, title, description) {
+        ApiResult::Ok(video_id) => video_id,
+        ApiResult::Err(e) => ic_cdk::trap(&e.to_string()),
+    }
 }
 
+/// 動画のタイトルと説明文を更新する (動画の所有者またはコントローラーのみ)
+#[update]
+fn update_video_info(video_id: String, title: String, description: String) -> ApiResult<()> {
+    with_managed_video(&video_id, |video| {
+        video.title = title;
+        video.description = description;
+        search::index_video(video);
+        Ok(())
+    })
+    .into()
+}
+
+/// 動画のタイトルを返す
 #[query]
-fn get_video_info(video_id: String) -> VideoInfoResult {
+fn get_video_info_v2(video_id: String) -> ApiResult<String> {
     ic_cdk::println!("Starting get_video_info for video_id: {}", video_id);
+    let caller = ic_cdk::caller();
     VIDEOS.with(|videos| {
         let videos = videos.borrow();
-        videos.iter()
-            .map(|(_id, video)| (
-                ic_cdk::println!("{}", format!("title: {}, description: {}", video.title.clone(), video.description.clone())
-            )));
-        if let Some(video) = videos.get(&video_id) {
+        if let Some(video) = videos.get(&video_id).filter(|video| video.is_servable_to(&caller)) {
             ic_cdk::println!("video.title: {}", video.title);
-            VideoInfoResult::Ok(video.title.clone())
+            ApiResult::Ok(video.title.clone())
         } else {
-            VideoInfoResult::Err("Video not found".to_string())
+            ApiResult::Err(ApiError::not_found("Video"))
         }
     })
 }
 
+/// 非推奨: get_video_info_v2 を使うこと (次のリリースで削除する)
+#[query]
+fn get_video_info(video_id: String) -> LegacyResult<String> {
+    get_video_info_v2(video_id).into()
+}
+
 #[query]
 fn get_video_list() -> Vec<(String, String, String, String)> {
     VIDEOS.with(|videos| {
         let videos = videos.borrow();
         videos.iter()
+            .filter(|(_, video)| video.is_listed())
             .map(|(id, video)| (
                 id.clone(),
                 video.title.clone(),
@@ -277,27 +555,49 @@ fn get_video_list() -> Vec<(String, String, String, String)> {
     })
 }
 
+/// 公開中の動画の概要 (タグ・リアクション数を含む) を新しい順に返す
+#[query]
+fn list_video_summaries() -> Vec<VideoSummary> {
+    VIDEOS.with(|videos| {
+        let mut summaries: Vec<VideoSummary> = videos
+            .borrow()
+            .values()
+            .filter(|video| video.is_listed())
+            .map(Video::summary)
+            .collect();
+        summaries.sort_by_key(|summary| std::cmp::Reverse(summary.created_at));
+        summaries
+    })
+}
+
 
 
 // HLS用プレイリスト(m3u8)を返すAPI
 #[query]
-fn get_hls_playlist(video_id: String, _canister_id: String) -> GetHlsPlaylistResult {
+fn get_hls_playlist_v2(video_id: String) -> ApiResult<String> {
     ic_cdk::println!("get_hls_playlist: {}", video_id);
+    let caller = ic_cdk::caller();
     VIDEOS.with(|videos| {
         let videos = videos.borrow();
-        if let Some(video) = videos.get(&video_id) {
+        if let Some(video) = videos.get(&video_id).filter(|video| video.is_servable_to(&caller)) {
             if let Some(playlist) = &video.playlist {
                 ic_cdk::println!("playlist: {}", playlist);
-                GetHlsPlaylistResult::Ok(playlist.clone())
+                ApiResult::Ok(playlist.clone())
             } else {
-                GetHlsPlaylistResult::Err("Playlist not found".to_string())
+                ApiResult::Err(ApiError::not_found("Playlist"))
             }
         } else {
-            GetHlsPlaylistResult::Err("Video not found".to_string())
+            ApiResult::Err(ApiError::not_found("Video"))
         }
     })
 }
 
+/// 非推奨: get_hls_playlist_v2 を使うこと (次のリリースで削除する)
+#[query]
+fn get_hls_playlist(video_id: String, _canister_id: String) -> LegacyResult<String> {
+    get_hls_playlist_v2(video_id).into()
+}
+
 // // HLS用セグメント(ts)を返すAPI（現状はmp4チャンクそのまま返却）
 // #[query]
 // fn get_hls_segment(video_id: String, segment_index: u32) -> GetHlsSegmentChunkResult {
@@ -317,77 +617,160 @@ fn get_hls_playlist(video_id: String, _canister_id: String) -> GetHlsPlaylistRes
 // }
 
 #[update]
-fn upload_playlist(version: String,video_id: String, playlist_text: String) -> UploadResult {
-    VIDEOS.with(|videos| {
-        let mut videos: std::cell::RefMut<'_, HashMap<String, Video>> = videos.borrow_mut();
-        if let Some(video) = videos.get_mut(&video_id) {
-            ic_cdk::println!("Upload playlist: {}", playlist_text);
-            video.playlist = Some(playlist_text.clone());
-            ic_cdk::println!("Uploaded playlist");
-            UploadResult::Ok("OK".to_string())
-        } else {
-            UploadResult::Err("Video not found".to_string())
-        }
+fn upload_playlist_v2(_version: String, video_id: String, playlist_text: String) -> ApiResult<()> {
+    with_managed_video(&video_id, |video| {
+        admin::ensure_not_frozen(&ic_cdk::caller())?;
+        ic_cdk::println!("Upload playlist: {}", playlist_text);
+        video.playlist = Some(playlist_text.clone());
+        video.updated_at = ic_cdk::api::time();
+        ic_cdk::println!("Uploaded playlist");
+        Ok(())
     })
+    .into()
+}
+
+/// 非推奨: upload_playlist_v2 を使うこと (次のリリースで削除する)
+#[update]
+fn upload_playlist(version: String, video_id: String, playlist_text: String) -> LegacyResult<String> {
+    upload_playlist_v2(version, video_id, playlist_text).legacy(|()| "OK".to_string())
 }
 
 //TODO: セグメントファイルがチャンクに分かれているので、チャンクを結合してセグメントファイルにしなければならない
 #[update]
-fn upload_ts_segment_chunk(
-    version: String, 
+fn upload_ts_segment_chunk_v2(
+    _version: String,
     video_id: String, 
     segment_index: u32, 
     chunk_index: u32, 
     total_chunk_count: u32,
     segment_chunk_data: Vec<u8>
-) -> UploadResult {
-
-    VIDEOS.with(|videos| {
-        let mut videos = videos.borrow_mut();
-        if let Some(video) = videos.get_mut(&video_id) {
-
-            // video.segments が segment_index まで格納できるように Vec を拡張
-            // ここでの video.segments は Vec<Vec<Vec<u8>>> です
-            while video.segments.len() <= segment_index as usize {
-                video.segments.push(SegmentInfo::default());
-            }
+) -> ApiResult<()> {
+    // 同じ内容のチャンクが既にあればバイト列は保存せず参照だけを増やす
+    let caller = ic_cdk::caller();
+    store_segment_chunk(&video_id, segment_index, chunk_index, total_chunk_count, || {
+        channel::put_chunk(segment_chunk_data, caller)
+    })
+    .into()
+}
 
-            // 指定された segment_index に対応するチャンクリストを取得
-            let segment_chunks = &mut video.segments[segment_index as usize];
-            if segment_chunks.total_chunk_count == 0 { // 初めて設定する場合のみ
-                segment_chunks.total_chunk_count = total_chunk_count;
-            }
-            // segment_chunks が chunk_index まで格納できるように Vec を拡張
-            // ここでの segment_chunks は Vec<Vec<u8>> (チャンクリスト) です
-            while segment_chunks.chunks.len() <= chunk_index as usize {
-                segment_chunks.chunks.push(Vec::new()); // 各チャンク用に新しいデータVec<u8>を初期化
-            }
+/// 非推奨: upload_ts_segment_chunk_v2 を使うこと (次のリリースで削除する)
+#[update]
+fn upload_ts_segment_chunk(
+    version: String,
+    video_id: String,
+    segment_index: u32,
+    chunk_index: u32,
+    total_chunk_count: u32,
+    segment_chunk_data: Vec<u8>
+) -> LegacyResult<String> {
+    upload_ts_segment_chunk_v2(version, video_id, segment_index, chunk_index, total_chunk_count, segment_chunk_data)
+        .legacy(|()| "OK".to_string())
+}
 
-            // 指定された chunk_index の位置にチャンクデータを格納
-            segment_chunks.chunks[chunk_index as usize] = segment_chunk_data;
+/// 呼び出し元が以前アップロードしたチャンクをハッシュで参照して登録する
+/// has_blobs で確認したチャンクはバイト列を送らずに済む
+/// 他のプリンシパルだけがアップロードしたチャンクは参照できない
+#[update]
+fn upload_ts_segment_chunk_by_hash(
+    _version: String,
+    video_id: String,
+    segment_index: u32,
+    chunk_index: u32,
+    total_chunk_count: u32,
+    chunk_hash: Vec<u8>
+) -> ApiResult<()> {
+    let caller = ic_cdk::caller();
+    store_segment_chunk(&video_id, segment_index, chunk_index, total_chunk_count, || {
+        let hash = blobs::parse_hash(&chunk_hash)?;
+        if blobs::retain_uploaded(&hash, &caller) {
+            Ok(hash)
+        } else {
+            Err(ApiError::not_found("Chunk for the given hash"))
+        }
+    })
+    .into()
+}
 
-            // ic_cdk::println!(ts_data.len()); // チャンクのサイズ
-            ic_cdk::println!("Uploaded TS chunk for segment {}, chunk {}", segment_index, chunk_index);
+/// セグメントの指定位置にチャンクの参照を格納する
+/// acquire は呼び出し元が管理できる動画の場合にのみ呼ばれ、参照を 1 つ確保したハッシュを返す
+/// 置き換えられた古いチャンクの参照は解放する
+fn store_segment_chunk(
+    video_id: &str,
+    segment_index: u32,
+    chunk_index: u32,
+    total_chunk_count: u32,
+    acquire: impl FnOnce() -> Result<BlobHash, ApiError>,
+) -> Result<(), ApiError> {
+    with_managed_video(video_id, |video| {
+        admin::ensure_not_frozen(&ic_cdk::caller())?;
+        let hash = acquire()?;
+        place_segment_chunk(video, segment_index, chunk_index, total_chunk_count, hash, ic_cdk::api::time());
+
+        ic_cdk::println!("Uploaded TS chunk for segment {}, chunk {}", segment_index, chunk_index);
+
+        // 注: ここではチャンクを格納しただけで、結合はしていません。
+        // 結合処理は別途必要になります。
+        Ok(())
+    })
+}
 
-            // 注: ここではチャンクを格納しただけで、結合はしていません。
-            // 結合処理は別途必要になります。
+/// 参照を確保済みのチャンクのハッシュを動画のセグメントの指定位置に格納する
+/// 置き換えられた古いチャンクの参照は解放する
+fn place_segment_chunk(
+    video: &mut Video,
+    segment_index: u32,
+    chunk_index: u32,
+    total_chunk_count: u32,
+    hash: BlobHash,
+    now: u64,
+) {
+    // video.segments が segment_index まで格納できるように Vec を拡張
+    while video.segments.len() <= segment_index as usize {
+        video.segments.push(SegmentInfo::default());
+    }
+
+    // 指定された segment_index に対応するチャンクリストを取得
+    let segment_chunks = &mut video.segments[segment_index as usize];
+    if segment_chunks.total_chunk_count == 0 { // 初めて設定する場合のみ
+        segment_chunks.total_chunk_count = total_chunk_count;
+    }
+    // segment_chunks が chunk_index まで格納できるように Vec を拡張
+    while segment_chunks.chunks.len() <= chunk_index as usize {
+        segment_chunks.chunks.push(None);
+    }
+
+    // 指定された chunk_index の位置にチャンクのハッシュを格納
+    if let Some(old_hash) = segment_chunks.chunks[chunk_index as usize].replace(hash) {
+        blobs::release(&old_hash);
+    }
+    video.updated_at = now;
+}
 
-            UploadResult::Ok("OK".to_string())
-        } else {
-            UploadResult::Err("Video not found".to_string())
+/// アップロード完了を確定する
+/// プレイリストと全チャンクが揃っていない場合はエラーを返す
+/// 確定済みの動画は GC の対象にならない
+#[update]
+fn finalize_video(_version: String, video_id: String) -> ApiResult<()> {
+    with_managed_video(&video_id, |video| {
+        if !video.is_upload_complete() {
+            return Err(ApiError::conflict("Upload is not complete"));
         }
+        video.finalized = true;
+        video.updated_at = ic_cdk::api::time();
+        Ok(())
     })
+    .into()
 }
 
-
 /// 指定された video_id のセグメントの情報を返却する
 /// video_id: 動画のID
 #[query]
-fn get_segment_info(video_id: String) -> SegmentChunkInfoResult {
+fn get_segment_info_v2(video_id: String) -> ApiResult<Vec<SegmentChunkInfo>> {
+    let caller = ic_cdk::caller();
     VIDEOS.with(|videos| {
         let videos = videos.borrow();
 
-        if let Some(video) = videos.get(&video_id) {
+        if let Some(video) = videos.get(&video_id).filter(|video| video.is_servable_to(&caller)) {
             let mut segment_chunk_info_list = Vec::new(); // Changed variable name for clarity
             for (index, segment_info) in video.segments.iter().enumerate() {
                 segment_chunk_info_list.push(SegmentChunkInfo {
@@ -395,47 +778,74 @@ fn get_segment_info(video_id: String) -> SegmentChunkInfoResult {
                     total_chunk_count: segment_info.total_chunk_count,
                 });
             }
-            SegmentChunkInfoResult::Ok(segment_chunk_info_list)
+            ApiResult::Ok(segment_chunk_info_list)
         } else {
             // 指定された動画が存在しない
-            SegmentChunkInfoResult::Err(format!("Video not found with ID {}", video_id))
+            ApiResult::Err(ApiError::NotFound { message: format!("Video not found with ID {}", video_id) })
         }
     })
 }
 
+/// 非推奨: get_segment_info_v2 を使うこと (次のリリースで削除する)
+#[query]
+fn get_segment_info(video_id: String) -> LegacyResult<Vec<SegmentChunkInfo>> {
+    get_segment_info_v2(video_id).into()
+}
+
 /// 指定されたセグメントのチャンクを結合して完全なセグメントデータを取得する
 /// video_id: 動画のID
 /// segment_index: 結合したいセグメントのインデックス
 /// 戻り値: 成功した場合は結合された Vec<u8>、失敗した場合はエラーメッセージ
 #[query] // データの読み取りのみ行う場合は #[query] を使用 (状態を変更しない場合)
-fn get_segment_chunk(video_id: String, segment_index: u32, chunk_index: u32) -> SegmentChunkResult {
+fn get_segment_chunk_v2(video_id: String, segment_index: u32, chunk_index: u32) -> ApiResult<SegmentChunkResponse> {
+    let caller = ic_cdk::caller();
     VIDEOS.with(|videos| {
         let videos = videos.borrow(); // 読み取り専用でアクセス
 
-        // 1. 動画が存在するか確認
-        if let Some(video) = videos.get(&video_id) {
+        // 1. 動画が存在し、再生できる状態か確認
+        if let Some(video) = videos.get(&video_id).filter(|video| video.is_servable_to(&caller)) {
 
             // 2. セグメントインデックスが有効か確認
             if (segment_index as usize) < video.segments.len() {
                 let segment_chunks = &video.segments[segment_index as usize];
-                let chunk_data_vec = &segment_chunks.chunks[chunk_index as usize]; // これは &Vec<u8> 型
+                // チャンクはハッシュで参照しているため、ブロブストアから実データを取り出す
+                let chunk_data = segment_chunks
+                    .chunks
+                    .get(chunk_index as usize)
+                    .and_then(|hash| hash.as_ref())
+                    .and_then(blobs::get);
                 ic_cdk::println!("segment index: {} chunk index: {}", segment_index, chunk_index);
-                SegmentChunkResult::Ok(SegmentChunkResponse {
-                    segment_chunk_data: chunk_data_vec.clone(),
-                    total_chunk_count: segment_chunks.total_chunk_count,
-                })
+                match chunk_data {
+                    Some(segment_chunk_data) => ApiResult::Ok(SegmentChunkResponse {
+                        segment_chunk_data,
+                        total_chunk_count: segment_chunks.total_chunk_count,
+                    }),
+                    None => ApiResult::Err(ApiError::NotFound { message: format!(
+                        "Chunk index {} not found in segment {} for video {}",
+                        chunk_index, segment_index, video_id
+                    ) }),
+                }
 
             } else {
                 // 指定されたセグメントが存在しない
-                SegmentChunkResult::Err(format!("Segment index {} out of bounds for video {}", segment_index, video_id))
+                ApiResult::Err(ApiError::invalid_argument(
+                    "segment_index",
+                    format!("Segment index {} out of bounds for video {}", segment_index, video_id),
+                ))
             }
         } else {
             // 指定された動画が存在しない
-            SegmentChunkResult::Err(format!("Video not found with ID {}", video_id))
+            ApiResult::Err(ApiError::NotFound { message: format!("Video not found with ID {}", video_id) })
         }
     })
 }
 
+/// 非推奨: get_segment_chunk_v2 を使うこと (次のリリースで削除する)
+#[query]
+fn get_segment_chunk(video_id: String, segment_index: u32, chunk_index: u32) -> LegacyResult<SegmentChunkResponse> {
+    get_segment_chunk_v2(video_id, segment_index, chunk_index).into()
+}
+
 // /// 指定されたセグメントのチャンクを結合して完全なセグメントデータを取得する
 // /// video_id: 動画のID
 // /// segment_index: 結合したいセグメントのインデックス
@@ -479,47 +889,64 @@ fn get_segment_chunk(video_id: String, segment_index: u32, chunk_index: u32) ->
 // }
 
 // 動画を削除するAPI
+// 動画はゴミ箱へ移動し、保持期間内であれば restore_video で復元できる
 #[update]
-fn delete_video(video_id: String) -> DeleteVideoResult {
-    VIDEOS.with(|videos| {
-        let mut videos = videos.borrow_mut();
-        if videos.remove(&video_id).is_some() {
-            DeleteVideoResult::Ok("Video deleted successfully".to_string())
-        } else {
-            DeleteVideoResult::Err("Video not found".to_string())
-        }
-    })
+fn delete_video_v2(video_id: String) -> ApiResult<()> {
+    let caller = ic_cdk::caller();
+    if let Err(e) = with_managed_video(&video_id, |_| Ok(())) {
+        return ApiResult::Err(e);
+    }
+    let video = take_video(&video_id).unwrap();
+    trash::move_to_trash(video, caller);
+    ApiResult::Ok(())
 }
 
+/// 非推奨: delete_video_v2 を使うこと (次のリリースで削除する)
 #[update]
-fn upload_thumbnail(version: String, video_id: String, thumbnail_data: Vec<u8>) -> UploadResult {
-    VIDEOS.with(|videos| {
-        let mut videos = videos.borrow_mut();
-        if let Some(video) = videos.get_mut(&video_id) {
-            video.thumbnail = Some(thumbnail_data);
-            UploadResult::Ok("Thumbnail uploaded successfully".to_string())
-        } else {
-            UploadResult::Err("Video not found".to_string())
-        }
+fn delete_video(video_id: String) -> LegacyResult<String> {
+    delete_video_v2(video_id).legacy(|()| "Video moved to trash".to_string())
+}
+
+#[update]
+fn upload_thumbnail_v2(_version: String, video_id: String, thumbnail_data: Vec<u8>) -> ApiResult<()> {
+    with_managed_video(&video_id, |video| {
+        admin::ensure_not_frozen(&ic_cdk::caller())?;
+        video.thumbnail = Some(thumbnail_data);
+        video.updated_at = ic_cdk::api::time();
+        Ok(())
     })
+    .into()
+}
+
+/// 非推奨: upload_thumbnail_v2 を使うこと (次のリリースで削除する)
+#[update]
+fn upload_thumbnail(version: String, video_id: String, thumbnail_data: Vec<u8>) -> LegacyResult<String> {
+    upload_thumbnail_v2(version, video_id, thumbnail_data).legacy(|()| "Thumbnail uploaded successfully".to_string())
 }
 
 #[query]
-fn get_thumbnail(video_id: String) -> ThumbnailResult {
+fn get_thumbnail_v2(video_id: String) -> ApiResult<Vec<u8>> {
+    let caller = ic_cdk::caller();
     VIDEOS.with(|videos| {
         let videos = videos.borrow();
-        if let Some(video) = videos.get(&video_id) {
+        if let Some(video) = videos.get(&video_id).filter(|video| video.is_servable_to(&caller)) {
             if let Some(thumbnail) = &video.thumbnail {
-                ThumbnailResult::Ok(thumbnail.clone())
+                ApiResult::Ok(thumbnail.clone())
             } else {
-                ThumbnailResult::Err("Thumbnail not found".to_string())
+                ApiResult::Err(ApiError::not_found("Thumbnail"))
             }
         } else {
-            ThumbnailResult::Err("Video not found".to_string())
+            ApiResult::Err(ApiError::not_found("Video"))
         }
     })
 }
 
+/// 非推奨: get_thumbnail_v2 を使うこと (次のリリースで削除する)
+#[query]
+fn get_thumbnail(video_id: String) -> LegacyResult<Vec<u8>> {
+    get_thumbnail_v2(video_id).into()
+}
+
 // #[query]
 // fn download_video(video_id: String) -> DownloadVideoResult {
 //     VIDEOS.with(|videos| {
@@ -555,4 +982,40 @@ fn get_thumbnail(video_id: String) -> ThumbnailResult {
 //             DownloadVideoResult::Err("Video not found".to_string())
 //         }
 //     })
-// }
\ No newline at end of file
+// }
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use candid_parser::utils::{service_compatible, CandidSource};
+    use std::path::Path;
+
+    // コードから生成したインターフェースがコミット済みの .did と後方互換であること
+    // (既存のクライアントを壊さない部分型であること) を確認する
+    #[test]
+    fn candid_interface_is_backward_compatible() {
+        let generated = super::__export_service();
+        let committed = Path::new(env!("CARGO_MANIFEST_DIR")).join("streamingservice_backend.did");
+        service_compatible(CandidSource::Text(&generated), CandidSource::File(&committed))
+            .unwrap_or_else(|e| panic!("Generated interface is not compatible with the committed .did: {e:?}"));
+    }
+
+    // pre_upgrade で stable memory に書き込んだ状態を post_upgrade で読み戻せること
+    #[test]
+    fn upgrade_state_round_trips_through_stable_memory() {
+        let mut state = super::StableState::default();
+        state.analytics.insert("video".to_string(), Default::default());
+        let bytes = candid::encode_one(super::VersionedState::V1(state)).unwrap();
+        super::memory::save_upgrade_state(&bytes);
+
+        let restored = super::load_state().unwrap();
+        assert!(restored.analytics.contains_key("video"));
+    }
+
+    // 読み戻せない状態は空の状態として扱わずエラーにする (post_upgrade はトラップする)
+    #[test]
+    fn corrupted_upgrade_state_is_an_error() {
+        super::memory::save_upgrade_state(b"not candid");
+        assert!(super::load_state().is_err());
+    }
+}
@@ -0,0 +1,80 @@
+// stable memory の割り当て
+//
+// stable memory は MemoryManager で仮想メモリに分割して使う。
+// - UPGRADES: アップグレードの間だけ退避するヒープ上の状態 (candid でエンコードした VersionedState)
+// - BLOB_DATA / BLOB_META: チャンクの実データと参照カウント (blobs.rs)
+//   チャンクはヒープに載せずに stable memory に直接置くため、アップグレードのたびにエンコードしない。
+//
+// stable memory を使っていなかった最初のバージョンからのアップグレードでは退避した状態が無いため、
+// post_upgrade では MemoryManager を初期化する前に layout() で確認する
+// (MemoryManager は最初に触れたときに初期化され、stable memory の先頭にヘッダーを書く)。
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, Memory as _};
+
+pub(crate) type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const UPGRADES: MemoryId = MemoryId::new(0);
+pub(crate) const BLOB_DATA: MemoryId = MemoryId::new(1);
+pub(crate) const BLOB_META: MemoryId = MemoryId::new(2);
+
+const WASM_PAGE_SIZE: u64 = 65536;
+// UPGRADES の先頭に置く、状態の長さ (u64) のバイト数
+const LENGTH_BYTES: u64 = 8;
+
+thread_local! {
+    static MEMORY_MANAGER: MemoryManager<DefaultMemoryImpl> = MemoryManager::init(DefaultMemoryImpl::default());
+}
+
+pub(crate) fn get(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|manager| manager.get(id))
+}
+
+// アップグレード前の stable memory の使われ方
+pub(crate) enum Layout {
+    Empty,   // 状態を保存していなかったバージョン (stable memory を使っていない)
+    Managed, // MemoryManager で分割しているバージョン
+}
+
+/// stable memory の先頭を読んでレイアウトを判定する
+/// MemoryManager を初期化する前 (post_upgrade の最初) に呼ぶこと
+pub(crate) fn layout() -> Result<Layout, String> {
+    if ic_cdk::api::stable::stable_size() == 0 {
+        return Ok(Layout::Empty);
+    }
+    let mut magic = [0u8; 4];
+    ic_cdk::api::stable::stable_read(0, &mut magic);
+    if magic.starts_with(b"MGR") {
+        Ok(Layout::Managed)
+    } else {
+        Err(format!("Unknown stable memory layout (header {:?})", magic))
+    }
+}
+
+/// アップグレードの間だけ保持する状態を UPGRADES に書き込む
+pub(crate) fn save_upgrade_state(bytes: &[u8]) {
+    let memory = get(UPGRADES);
+    let length = bytes.len() as u64;
+    let required_pages = (LENGTH_BYTES + length).div_ceil(WASM_PAGE_SIZE);
+    if memory.size() < required_pages && memory.grow(required_pages - memory.size()) < 0 {
+        ic_cdk::trap("Failed to grow stable memory for the upgrade state");
+    }
+    memory.write(0, &length.to_le_bytes());
+    memory.write(LENGTH_BYTES, bytes);
+}
+
+/// save_upgrade_state で書き込んだ状態を読み出す
+pub(crate) fn load_upgrade_state() -> Result<Vec<u8>, String> {
+    let memory = get(UPGRADES);
+    if memory.size() == 0 {
+        return Err("No upgrade state in stable memory".to_string());
+    }
+    let mut length = [0u8; LENGTH_BYTES as usize];
+    memory.read(0, &mut length);
+    let length = u64::from_le_bytes(length);
+    if LENGTH_BYTES + length > memory.size() * WASM_PAGE_SIZE {
+        return Err(format!("Upgrade state length {} exceeds the stable memory", length));
+    }
+    let mut bytes = vec![0u8; length as usize];
+    memory.read(LENGTH_BYTES, &mut bytes);
+    Ok(bytes)
+}
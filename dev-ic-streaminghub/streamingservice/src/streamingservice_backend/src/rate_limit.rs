@@ -0,0 +1,103 @@
+// プリンシパルごと (または任意のキーごと) の簡易レート制限 (固定ウィンドウ方式)
+use candid::Principal;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::error::ApiError;
+
+// これを超えたら期限切れのウィンドウを掃除する
+const PRUNE_THRESHOLD: usize = 10_000;
+
+pub(crate) struct Limit {
+    pub bucket: &'static str, // API ごとの識別子
+    pub max_calls: u32,       // ウィンドウ内で許可する呼び出し回数
+    pub window_secs: u64,
+}
+
+struct Window {
+    resets_at: u64, // このウィンドウが終わる日時 (ns)
+    count: u32,
+}
+
+thread_local! {
+    static WINDOWS: RefCell<HashMap<(&'static str, Vec<u8>), Window>> = RefCell::new(HashMap::new());
+}
+
+/// 呼び出しを 1 回分記録し、上限を超えていればエラーを返す
+pub(crate) fn check(limit: &Limit, caller: Principal) -> Result<(), ApiError> {
+    check_key(limit, caller.as_slice())
+}
+
+/// プリンシパル以外のキー (動画IDなど) ごとに呼び出しを 1 回分記録し、上限を超えていればエラーを返す
+pub(crate) fn check_key(limit: &Limit, key: &[u8]) -> Result<(), ApiError> {
+    check_at(limit, key, ic_cdk::api::time())
+}
+
+fn check_at(limit: &Limit, key: &[u8], now: u64) -> Result<(), ApiError> {
+    let window_nanos = limit.window_secs.saturating_mul(1_000_000_000);
+    WINDOWS.with(|windows| {
+        let mut windows = windows.borrow_mut();
+        if windows.len() > PRUNE_THRESHOLD {
+            windows.retain(|_, window| now < window.resets_at);
+        }
+        let window = windows
+            .entry((limit.bucket, key.to_vec()))
+            .or_insert(Window { resets_at: 0, count: 0 });
+        if now >= window.resets_at {
+            window.resets_at = now.saturating_add(window_nanos);
+            window.count = 0;
+        }
+        if window.count >= limit.max_calls {
+            return Err(ApiError::quota_exceeded(format!(
+                "Rate limit exceeded: at most {} calls per {} seconds",
+                limit.max_calls, limit.window_secs
+            )));
+        }
+        window.count += 1;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEC: u64 = 1_000_000_000;
+    const LIMIT: Limit = Limit {
+        bucket: "test",
+        max_calls: 2,
+        window_secs: 60,
+    };
+
+    #[test]
+    fn calls_over_the_limit_are_rejected_until_the_window_ends() {
+        assert!(check_at(&LIMIT, b"key", SEC).is_ok());
+        assert!(check_at(&LIMIT, b"key", 2 * SEC).is_ok());
+        assert!(check_at(&LIMIT, b"key", 3 * SEC).is_err());
+        assert!(check_at(&LIMIT, b"key", 61 * SEC - 1).is_err());
+        // ウィンドウは最初の呼び出しから window_secs で終わり、回数が戻る
+        assert!(check_at(&LIMIT, b"key", 61 * SEC).is_ok());
+        assert!(check_at(&LIMIT, b"key", 62 * SEC).is_ok());
+        assert!(check_at(&LIMIT, b"key", 63 * SEC).is_err());
+    }
+
+    #[test]
+    fn keys_and_buckets_are_limited_separately() {
+        let other_bucket = Limit { bucket: "other", ..LIMIT };
+        for _ in 0..2 {
+            assert!(check_at(&LIMIT, b"a", SEC).is_ok());
+        }
+        assert!(check_at(&LIMIT, b"a", SEC).is_err());
+        assert!(check_at(&LIMIT, b"b", SEC).is_ok());
+        assert!(check_at(&other_bucket, b"a", SEC).is_ok());
+    }
+
+    #[test]
+    fn expired_windows_are_pruned() {
+        for key in 0..=PRUNE_THRESHOLD as u32 {
+            assert!(check_at(&LIMIT, &key.to_le_bytes(), SEC).is_ok());
+        }
+        assert!(check_at(&LIMIT, b"late", 61 * SEC).is_ok());
+        WINDOWS.with(|windows| assert_eq!(windows.borrow().len(), 1));
+    }
+}
@@ -0,0 +1,109 @@
+// 動画へのリアクション (いいね・よくない・絵文字)
+//
+// プリンシパルごとに動画 1 本につき 1 つのリアクションを持ち、付け直すと置き換わる。
+// 匿名の呼び出し元はリアクションできない。
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::error::{ApiError, ApiResult};
+use crate::ensure_video_visible;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Reaction {
+    Like,
+    Dislike,
+    Heart,
+    Laugh,
+    Surprised,
+    Sad,
+}
+
+// 一覧のメタデータに含めるリアクションの集計
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct ReactionCounts {
+    pub like: u64,
+    pub dislike: u64,
+    pub heart: u64,
+    pub laugh: u64,
+    pub surprised: u64,
+    pub sad: u64,
+}
+
+thread_local! {
+    static REACTIONS: RefCell<HashMap<String, HashMap<Principal, Reaction>>> = RefCell::new(HashMap::new());
+}
+
+pub(crate) fn save() -> HashMap<String, HashMap<Principal, Reaction>> {
+    REACTIONS.with(|reactions| reactions.take())
+}
+
+pub(crate) fn restore(state: HashMap<String, HashMap<Principal, Reaction>>) {
+    REACTIONS.with(|reactions| *reactions.borrow_mut() = state);
+}
+
+pub(crate) fn remove(video_id: &str) {
+    REACTIONS.with(|reactions| reactions.borrow_mut().remove(video_id));
+}
+
+/// 動画のリアクション数を集計する
+pub(crate) fn counts(video_id: &str) -> ReactionCounts {
+    REACTIONS.with(|reactions| {
+        let mut counts = ReactionCounts::default();
+        for reaction in reactions.borrow().get(video_id).into_iter().flat_map(|r| r.values()) {
+            let count = match reaction {
+                Reaction::Like => &mut counts.like,
+                Reaction::Dislike => &mut counts.dislike,
+                Reaction::Heart => &mut counts.heart,
+                Reaction::Laugh => &mut counts.laugh,
+                Reaction::Surprised => &mut counts.surprised,
+                Reaction::Sad => &mut counts.sad,
+            };
+            *count += 1;
+        }
+        counts
+    })
+}
+
+/// 動画にリアクションを付ける。None を渡すとリアクションを取り消す
+/// 成功した場合は更新後の集計を返す
+#[update]
+fn set_reaction(video_id: String, reaction: Option<Reaction>) -> ApiResult<ReactionCounts> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return ApiResult::Err(ApiError::unauthorized("Anonymous callers cannot react"));
+    }
+    if let Err(e) = ensure_video_visible(&video_id, &caller) {
+        return ApiResult::Err(e);
+    }
+    REACTIONS.with(|reactions| {
+        let mut reactions = reactions.borrow_mut();
+        match reaction {
+            Some(reaction) => {
+                reactions.entry(video_id.clone()).or_default().insert(caller, reaction);
+            }
+            None => {
+                if let Some(video_reactions) = reactions.get_mut(&video_id) {
+                    video_reactions.remove(&caller);
+                    if video_reactions.is_empty() {
+                        reactions.remove(&video_id);
+                    }
+                }
+            }
+        }
+    });
+    ApiResult::Ok(counts(&video_id))
+}
+
+/// 呼び出し元が動画に付けているリアクションを返す
+#[query]
+fn get_my_reaction(video_id: String) -> Option<Reaction> {
+    let caller = ic_cdk::caller();
+    REACTIONS.with(|reactions| {
+        reactions
+            .borrow()
+            .get(&video_id)
+            .and_then(|video_reactions| video_reactions.get(&caller).copied())
+    })
+}
@@ -0,0 +1,502 @@
+// 視聴者からの通報とモデレーション
+//
+// ログインした視聴者は report_video で動画を通報でき、通報は管理者のキューに入る。
+// 管理者は通報ごとに却下・非表示・削除・投稿者の凍結のいずれかで対応する。
+// 動画の状態は Video::moderation に持ち、所有者は get_moderation_status で確認できる。
+// 非表示の動画は一覧にも配信にも出さない (所有者と管理者を除く)。
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::error::{ApiError, ApiResult};
+use crate::admin::{self, AdminAction};
+use crate::rate_limit::{self, Limit};
+use crate::{discard_video, take_video, VIDEOS};
+
+const MAX_REASON_LEN: usize = 500;
+const DEFAULT_LIMIT: u32 = 50;
+const MAX_LIMIT: u32 = 200;
+const REPORT_VIDEO_LIMIT: Limit = Limit {
+    bucket: "report_video",
+    max_calls: 5,
+    window_secs: 60,
+};
+
+// 動画のモデレーション状態
+#[derive(CandidType, Deserialize, Clone, Default, PartialEq, Eq)]
+pub enum ModerationStatus {
+    #[default]
+    Visible,
+    UnderReview,              // 未対応の通報がある (表示は続ける)
+    Hidden { reason: String }, // 管理者が非表示にした
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ReportStatus {
+    Open,
+    Dismissed,
+    Hidden,
+    Deleted,
+    UploaderBanned,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ModerationAction {
+    Dismiss,     // 通報を却下する
+    Hide,        // 動画を非表示にする
+    Delete,      // 動画を完全削除する
+    BanUploader, // 動画を非表示にし、投稿者のアップロードを凍結する
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Report {
+    pub id: u64,
+    pub video_id: String,
+    pub video_owner: Principal,
+    pub reporter: Principal,
+    pub reason: String,
+    pub created_at: u64,
+    pub status: ReportStatus,
+    pub resolved_by: Option<Principal>,
+    pub resolved_at: Option<u64>,
+}
+
+// 所有者に見せる通報の対応結果 (通報者は含めない)
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ModerationNotice {
+    pub report_id: u64,
+    pub video_id: String,
+    pub reason: String,
+    pub status: ReportStatus,
+    pub resolved_at: Option<u64>,
+}
+
+// アップグレード時に退避する状態
+#[derive(CandidType, Deserialize, Default)]
+pub(crate) struct ReportsState {
+    reports: BTreeMap<u64, Report>,
+    next_id: u64,
+}
+
+thread_local! {
+    static REPORTS: RefCell<ReportsState> = RefCell::new(ReportsState::default());
+}
+
+pub(crate) fn save() -> ReportsState {
+    REPORTS.with(|reports| reports.take())
+}
+
+pub(crate) fn restore(state: ReportsState) {
+    REPORTS.with(|reports| *reports.borrow_mut() = state);
+}
+
+impl ModerationStatus {
+    pub(crate) fn is_hidden(&self) -> bool {
+        matches!(self, ModerationStatus::Hidden { .. })
+    }
+}
+
+/// 動画の未対応の通報をすべて指定された状態で閉じる
+fn close_open_reports(video_id: &str, status: ReportStatus, resolved_by: Principal, now: u64) {
+    REPORTS.with(|reports| {
+        for report in reports.borrow_mut().reports.values_mut() {
+            if report.video_id == video_id && report.status == ReportStatus::Open {
+                report.status = status;
+                report.resolved_by = Some(resolved_by);
+                report.resolved_at = Some(now);
+            }
+        }
+    });
+}
+
+fn has_open_reports(video_id: &str) -> bool {
+    REPORTS.with(|reports| {
+        reports
+            .borrow()
+            .reports
+            .values()
+            .any(|report| report.video_id == video_id && report.status == ReportStatus::Open)
+    })
+}
+
+fn set_moderation(video_id: &str, status: ModerationStatus) {
+    VIDEOS.with(|videos| {
+        if let Some(video) = videos.borrow_mut().get_mut(video_id) {
+            video.moderation = status;
+        }
+    });
+}
+
+/// 動画を通報する (匿名の呼び出し元は不可)
+/// 同じ動画に対する未対応の通報が既にある場合はエラー
+#[update]
+fn report_video(video_id: String, reason: String) -> ApiResult<u64> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return ApiResult::Err(ApiError::unauthorized("Anonymous callers cannot report videos"));
+    }
+    let reason = reason.trim().to_string();
+    if reason.is_empty() {
+        return ApiResult::Err(ApiError::invalid_argument("reason", "Reason must not be empty"));
+    }
+    if reason.chars().count() > MAX_REASON_LEN {
+        return ApiResult::Err(ApiError::invalid_argument(
+            "reason",
+            format!("Reason is longer than {} characters", MAX_REASON_LEN),
+        ));
+    }
+    let Some(video_owner) = VIDEOS.with(|videos| videos.borrow().get(&video_id).map(|video| video.owner)) else {
+        return ApiResult::Err(ApiError::not_found("Video"));
+    };
+    let already_reported = REPORTS.with(|reports| {
+        reports.borrow().reports.values().any(|report| {
+            report.video_id == video_id && report.reporter == caller && report.status == ReportStatus::Open
+        })
+    });
+    if already_reported {
+        return ApiResult::Err(ApiError::conflict("Video has already been reported by the caller"));
+    }
+    if let Err(e) = rate_limit::check(&REPORT_VIDEO_LIMIT, caller) {
+        return ApiResult::Err(e);
+    }
+
+    let id = REPORTS.with(|reports| {
+        let mut state = reports.borrow_mut();
+        let id = state.next_id;
+        state.reports.insert(id, Report {
+            id,
+            video_id: video_id.clone(),
+            video_owner,
+            reporter: caller,
+            reason,
+            created_at: ic_cdk::api::time(),
+            status: ReportStatus::Open,
+            resolved_by: None,
+            resolved_at: None,
+        });
+        state.next_id += 1;
+        id
+    });
+    VIDEOS.with(|videos| {
+        if let Some(video) = videos.borrow_mut().get_mut(&video_id) {
+            if video.moderation == ModerationStatus::Visible {
+                video.moderation = ModerationStatus::UnderReview;
+            }
+        }
+    });
+    ApiResult::Ok(id)
+}
+
+/// 通報を古い順に返す (管理者のみ)
+/// status を省略すると未対応の通報だけを返す。cursor には前回の結果の最後の id + 1 を渡す
+#[query]
+fn list_reports(status: Option<ReportStatus>, limit: Option<u32>, cursor: Option<u64>) -> ApiResult<Vec<Report>> {
+    if let Err(e) = admin::ensure_admin() {
+        return ApiResult::Err(e);
+    }
+    let status = status.unwrap_or(ReportStatus::Open);
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT) as usize;
+    REPORTS.with(|reports| {
+        ApiResult::Ok(
+            reports
+                .borrow()
+                .reports
+                .range(cursor.unwrap_or(0)..)
+                .map(|(_, report)| report)
+                .filter(|report| report.status == status)
+                .take(limit)
+                .cloned()
+                .collect(),
+        )
+    })
+}
+
+/// 通報に対応する (管理者のみ)
+/// 却下以外の対応は、同じ動画に対する未対応の通報をまとめて閉じる
+#[update]
+fn resolve_report(report_id: u64, action: ModerationAction) -> ApiResult<()> {
+    let caller = match admin::ensure_admin() {
+        Ok(caller) => caller,
+        Err(e) => return ApiResult::Err(e),
+    };
+    let resolution = match apply_action(report_id, action, caller, ic_cdk::api::time()) {
+        Ok(resolution) => resolution,
+        Err(e) => return ApiResult::Err(e),
+    };
+    if let Some(principal) = resolution.frozen {
+        admin::record(caller, AdminAction::FreezePrincipal { principal });
+    }
+    admin::record(caller, AdminAction::ResolveReport {
+        report_id,
+        video_id: resolution.video_id,
+        action,
+    });
+    ApiResult::Ok(())
+}
+
+// apply_action の結果 (監査ログに記録する内容)
+struct Resolution {
+    video_id: String,
+    frozen: Option<Principal>, // 新たに凍結した投稿者
+}
+
+/// 時刻 now に resolved_by が通報 report_id に action で対応した結果を反映する
+fn apply_action(
+    report_id: u64,
+    action: ModerationAction,
+    resolved_by: Principal,
+    now: u64,
+) -> Result<Resolution, ApiError> {
+    let report = REPORTS.with(|reports| reports.borrow().reports.get(&report_id).cloned());
+    let Some(report) = report else {
+        return Err(ApiError::not_found("Report"));
+    };
+    if report.status != ReportStatus::Open {
+        return Err(ApiError::conflict("Report has already been resolved"));
+    }
+    let video_exists = VIDEOS.with(|videos| videos.borrow().contains_key(&report.video_id));
+    if !video_exists && action != ModerationAction::Dismiss {
+        return Err(ApiError::not_found("Video"));
+    }
+
+    let hidden = ModerationStatus::Hidden { reason: report.reason.clone() };
+    let mut frozen = None;
+    match action {
+        ModerationAction::Dismiss => {
+            REPORTS.with(|reports| {
+                if let Some(report) = reports.borrow_mut().reports.get_mut(&report_id) {
+                    report.status = ReportStatus::Dismissed;
+                    report.resolved_by = Some(resolved_by);
+                    report.resolved_at = Some(now);
+                }
+            });
+            // 他に未対応の通報がなければ通常の表示に戻す
+            if !has_open_reports(&report.video_id) {
+                VIDEOS.with(|videos| {
+                    if let Some(video) = videos.borrow_mut().get_mut(&report.video_id) {
+                        if video.moderation == ModerationStatus::UnderReview {
+                            video.moderation = ModerationStatus::Visible;
+                        }
+                    }
+                });
+            }
+        }
+        ModerationAction::Hide => {
+            set_moderation(&report.video_id, hidden);
+            close_open_reports(&report.video_id, ReportStatus::Hidden, resolved_by, now);
+        }
+        ModerationAction::Delete => {
+            if let Some(video) = take_video(&report.video_id) {
+                discard_video(&video);
+            }
+            close_open_reports(&report.video_id, ReportStatus::Deleted, resolved_by, now);
+        }
+        ModerationAction::BanUploader => {
+            set_moderation(&report.video_id, hidden);
+            if admin::freeze(report.video_owner) {
+                frozen = Some(report.video_owner);
+            }
+            close_open_reports(&report.video_id, ReportStatus::UploaderBanned, resolved_by, now);
+        }
+    }
+    Ok(Resolution { video_id: report.video_id, frozen })
+}
+
+/// 非表示にした動画を再び表示する (管理者のみ)
+#[update]
+fn unhide_video(video_id: String) -> ApiResult<()> {
+    let caller = match admin::ensure_admin() {
+        Ok(caller) => caller,
+        Err(e) => return ApiResult::Err(e),
+    };
+    let result = VIDEOS.with(|videos| match videos.borrow_mut().get_mut(&video_id) {
+        Some(video) if video.moderation.is_hidden() => {
+            video.moderation = ModerationStatus::Visible;
+            Ok(())
+        }
+        Some(_) => Err(ApiError::conflict("Video is not hidden")),
+        None => Err(ApiError::not_found("Video")),
+    });
+    match result {
+        Ok(()) => {
+            admin::record(caller, AdminAction::UnhideVideo { video_id });
+            ApiResult::Ok(())
+        }
+        Err(e) => ApiResult::Err(e),
+    }
+}
+
+/// 動画のモデレーション状態を返す (動画の所有者またはコントローラーのみ)
+#[query]
+fn get_moderation_status(video_id: String) -> ApiResult<ModerationStatus> {
+    let caller = ic_cdk::caller();
+    VIDEOS.with(|videos| match videos.borrow().get(&video_id) {
+        Some(video) if !video.can_manage(&caller) => {
+            ApiResult::Err(ApiError::unauthorized("Caller is not the owner of the video"))
+        }
+        Some(video) => ApiResult::Ok(video.moderation.clone()),
+        None => ApiResult::Err(ApiError::not_found("Video")),
+    })
+}
+
+/// 呼び出し元の動画に対する通報の対応結果を新しい順に返す
+/// 削除された動画についても確認できる
+#[query]
+fn list_my_moderation_notices() -> Vec<ModerationNotice> {
+    notices_for(&ic_cdk::caller())
+}
+
+fn notices_for(owner: &Principal) -> Vec<ModerationNotice> {
+    REPORTS.with(|reports| {
+        reports
+            .borrow()
+            .reports
+            .values()
+            .rev()
+            .filter(|report| report.video_owner == *owner && report.status != ReportStatus::Open)
+            .map(|report| ModerationNotice {
+                report_id: report.id,
+                video_id: report.video_id.clone(),
+                reason: report.reason.clone(),
+                status: report.status,
+                resolved_at: report.resolved_at,
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Video;
+
+    const NOW: u64 = 1_000;
+
+    fn admin() -> Principal {
+        Principal::self_authenticating(b"admin")
+    }
+
+    fn uploader() -> Principal {
+        Principal::self_authenticating(b"uploader")
+    }
+
+    fn viewer(n: u8) -> Principal {
+        Principal::self_authenticating([b'v', n])
+    }
+
+    // 動画 "video" に 2 件の未対応の通報がある状態を作り、通報の ID を返す
+    fn reported_video() -> (u64, u64) {
+        let mut video = Video::for_test("video", uploader(), 0);
+        video.moderation = ModerationStatus::UnderReview;
+        VIDEOS.with(|videos| videos.borrow_mut().insert("video".to_string(), video));
+        REPORTS.with(|reports| {
+            let mut state = reports.borrow_mut();
+            for (id, reason) in [(0, "spam"), (1, "abuse")] {
+                state.reports.insert(id, Report {
+                    id,
+                    video_id: "video".to_string(),
+                    video_owner: uploader(),
+                    reporter: viewer(id as u8),
+                    reason: reason.to_string(),
+                    created_at: 0,
+                    status: ReportStatus::Open,
+                    resolved_by: None,
+                    resolved_at: None,
+                });
+            }
+            state.next_id = 2;
+        });
+        (0, 1)
+    }
+
+    fn status_of(report_id: u64) -> ReportStatus {
+        REPORTS.with(|reports| reports.borrow().reports[&report_id].status)
+    }
+
+    fn moderation() -> Option<ModerationStatus> {
+        VIDEOS.with(|videos| videos.borrow().get("video").map(|video| video.moderation.clone()))
+    }
+
+    fn notice_statuses() -> Vec<(u64, ReportStatus, Option<u64>)> {
+        notices_for(&uploader())
+            .into_iter()
+            .map(|notice| (notice.report_id, notice.status, notice.resolved_at))
+            .collect()
+    }
+
+    // 却下は対象の通報だけを閉じ、最後の通報を却下したときに表示を戻す
+    #[test]
+    fn dismiss_closes_one_report_and_restores_visibility_last() {
+        let (first, second) = reported_video();
+
+        apply_action(first, ModerationAction::Dismiss, admin(), NOW).unwrap();
+        assert!(status_of(first) == ReportStatus::Dismissed);
+        assert!(status_of(second) == ReportStatus::Open);
+        assert!(moderation() == Some(ModerationStatus::UnderReview));
+
+        apply_action(second, ModerationAction::Dismiss, admin(), NOW).unwrap();
+        assert!(moderation() == Some(ModerationStatus::Visible));
+        assert!(notice_statuses() == vec![
+            (second, ReportStatus::Dismissed, Some(NOW)),
+            (first, ReportStatus::Dismissed, Some(NOW)),
+        ]);
+    }
+
+    #[test]
+    fn hide_closes_every_open_report_for_the_video() {
+        let (first, second) = reported_video();
+
+        let resolution = apply_action(second, ModerationAction::Hide, admin(), NOW).unwrap();
+
+        assert_eq!(resolution.video_id, "video");
+        assert!(resolution.frozen.is_none());
+        assert!(moderation() == Some(ModerationStatus::Hidden { reason: "abuse".to_string() }));
+        assert!(notice_statuses() == vec![
+            (second, ReportStatus::Hidden, Some(NOW)),
+            (first, ReportStatus::Hidden, Some(NOW)),
+        ]);
+    }
+
+    // 削除した動画についても所有者は対応結果を確認できる
+    #[test]
+    fn delete_removes_the_video_and_keeps_the_notices() {
+        let (first, second) = reported_video();
+
+        apply_action(first, ModerationAction::Delete, admin(), NOW).unwrap();
+
+        assert!(moderation().is_none());
+        assert!(notice_statuses() == vec![
+            (second, ReportStatus::Deleted, Some(NOW)),
+            (first, ReportStatus::Deleted, Some(NOW)),
+        ]);
+    }
+
+    #[test]
+    fn ban_uploader_hides_the_video_and_freezes_the_owner() {
+        let (first, second) = reported_video();
+
+        let resolution = apply_action(first, ModerationAction::BanUploader, admin(), NOW).unwrap();
+
+        assert_eq!(resolution.frozen, Some(uploader()));
+        assert!(admin::is_frozen(&uploader()));
+        assert!(moderation() == Some(ModerationStatus::Hidden { reason: "spam".to_string() }));
+        assert!(notice_statuses() == vec![
+            (second, ReportStatus::UploaderBanned, Some(NOW)),
+            (first, ReportStatus::UploaderBanned, Some(NOW)),
+        ]);
+    }
+
+    #[test]
+    fn resolved_reports_cannot_be_resolved_again() {
+        let (first, _) = reported_video();
+        apply_action(first, ModerationAction::Hide, admin(), NOW).unwrap();
+
+        assert!(matches!(
+            apply_action(first, ModerationAction::Dismiss, admin(), NOW),
+            Err(ApiError::Conflict { .. })
+        ));
+        // 他のプリンシパルの動画の通報は所有者への通知に含めない
+        assert!(notices_for(&viewer(0)).is_empty());
+    }
+}
@@ -0,0 +1,174 @@
+// 動画の公開予約と公開終了
+//
+// 動画ごとに publish_at / unpublish_at (ns) を設定でき、その時刻に published を切り替える。
+// 切り替えは次に期限が来る時刻に合わせた 1 回限りのタイマーで行い、
+// 実行後やスケジュールの変更時、post_upgrade で次の時刻に合わせてタイマーを張り直す。
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::*;
+use ic_cdk_timers::TimerId;
+use std::cell::RefCell;
+use std::time::Duration;
+
+use crate::error::{ApiError, ApiResult};
+use crate::{Video, VIDEOS};
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct VideoSchedule {
+    pub publish_at: Option<u64>,
+    pub unpublish_at: Option<u64>,
+    pub published: bool, // 現在一覧に表示されるかどうか
+}
+
+thread_local! {
+    static SCHEDULE_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+}
+
+impl Video {
+    /// 指定された時刻にスケジュール上公開されているべきかどうか
+    fn should_be_published(&self, now: u64) -> bool {
+        self.publish_at.is_none_or(|at| now >= at) && self.unpublish_at.is_none_or(|at| now < at)
+    }
+}
+
+/// 期限が来た動画の公開状態を切り替え、切り替えた件数を返す
+pub(crate) fn apply_due_transitions(now: u64) -> usize {
+    VIDEOS.with(|videos| {
+        let mut changed = 0;
+        for video in videos.borrow_mut().values_mut() {
+            let published = video.should_be_published(now);
+            if video.published != published {
+                video.published = published;
+                changed += 1;
+            }
+        }
+        changed
+    })
+}
+
+/// 次に公開状態が変わる時刻に合わせてタイマーを (再) 設定する
+/// init / post_upgrade とスケジュールの変更時に呼ばれる
+pub(crate) fn rearm_timer() {
+    let now = ic_cdk::api::time();
+    let next = VIDEOS.with(|videos| {
+        videos
+            .borrow()
+            .values()
+            .flat_map(|video| [video.publish_at, video.unpublish_at])
+            .flatten()
+            .filter(|at| *at > now)
+            .min()
+    });
+    let timer_id = next.map(|at| {
+        ic_cdk_timers::set_timer(Duration::from_nanos(at - now), || {
+            let changed = apply_due_transitions(ic_cdk::api::time());
+            if changed > 0 {
+                ic_cdk::println!("Changed publishing state of {} videos", changed);
+            }
+            rearm_timer();
+        })
+    });
+    SCHEDULE_TIMER.with(|timer| {
+        let mut timer = timer.borrow_mut();
+        if let Some(old_timer_id) = std::mem::replace(&mut *timer, timer_id) {
+            ic_cdk_timers::clear_timer(old_timer_id);
+        }
+    });
+}
+
+/// 動画の公開開始・終了時刻 (ns) を設定する。None を渡すとその制限を外す
+/// (動画の所有者またはコントローラーのみ)
+#[update]
+fn set_video_schedule(video_id: String, publish_at: Option<u64>, unpublish_at: Option<u64>) -> ApiResult<()> {
+    if let (Some(publish_at), Some(unpublish_at)) = (publish_at, unpublish_at) {
+        if unpublish_at <= publish_at {
+            return ApiResult::Err(ApiError::invalid_argument(
+                "unpublish_at",
+                "unpublish_at must be later than publish_at",
+            ));
+        }
+    }
+    let caller = ic_cdk::caller();
+    let result = VIDEOS.with(|videos| {
+        let mut videos = videos.borrow_mut();
+        match videos.get_mut(&video_id) {
+            Some(video) if !video.can_manage(&caller) => {
+                Err(ApiError::unauthorized("Caller is not the owner of the video"))
+            }
+            Some(video) => {
+                video.publish_at = publish_at;
+                video.unpublish_at = unpublish_at;
+                video.published = video.should_be_published(ic_cdk::api::time());
+                Ok(())
+            }
+            None => Err(ApiError::not_found("Video")),
+        }
+    });
+    match result {
+        Ok(()) => {
+            rearm_timer();
+            ApiResult::Ok(())
+        }
+        Err(e) => ApiResult::Err(e),
+    }
+}
+
+/// 動画の公開スケジュールと現在の公開状態を返す (動画の所有者またはコントローラーのみ)
+#[query]
+fn get_video_schedule(video_id: String) -> ApiResult<VideoSchedule> {
+    let caller = ic_cdk::caller();
+    VIDEOS.with(|videos| match videos.borrow().get(&video_id) {
+        Some(video) if !video.can_manage(&caller) => {
+            ApiResult::Err(ApiError::unauthorized("Caller is not the owner of the video"))
+        }
+        Some(video) => ApiResult::Ok(VideoSchedule {
+            publish_at: video.publish_at,
+            unpublish_at: video.unpublish_at,
+            published: video.published,
+        }),
+        None => ApiResult::Err(ApiError::not_found("Video")),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::insert_video;
+    use candid::Principal;
+
+    fn scheduled(id: &str, publish_at: Option<u64>, unpublish_at: Option<u64>) -> Video {
+        let mut video = Video::for_test(id, Principal::self_authenticating(b"owner"), 0);
+        video.publish_at = publish_at;
+        video.unpublish_at = unpublish_at;
+        video
+    }
+
+    #[test]
+    fn unscheduled_video_is_always_published() {
+        assert!(scheduled("video", None, None).should_be_published(0));
+    }
+
+    #[test]
+    fn video_is_published_from_publish_at_until_unpublish_at() {
+        let video = scheduled("video", Some(100), Some(200));
+        assert!(!video.should_be_published(99));
+        assert!(video.should_be_published(100));
+        assert!(video.should_be_published(199));
+        assert!(!video.should_be_published(200));
+    }
+
+    #[test]
+    fn due_transitions_are_applied_once() {
+        insert_video(scheduled("publishing", Some(100), None));
+        insert_video(scheduled("expiring", None, Some(100)));
+        VIDEOS.with(|videos| videos.borrow_mut().get_mut("publishing").unwrap().published = false);
+
+        assert_eq!(apply_due_transitions(99), 0);
+        assert_eq!(apply_due_transitions(100), 2);
+        assert_eq!(apply_due_transitions(100), 0);
+        VIDEOS.with(|videos| {
+            let videos = videos.borrow();
+            assert!(videos["publishing"].published);
+            assert!(!videos["expiring"].published);
+        });
+    }
+}
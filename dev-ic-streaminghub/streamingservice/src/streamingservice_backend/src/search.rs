@@ -0,0 +1,253 @@
+// 動画のタイトル・説明文・タグに対する全文検索
+//
+// キャニスター内に転置インデックスを持ち、動画の登録・更新・削除に合わせて更新する。
+// 英数字は単語単位、日本語などの CJK 文字は 2-gram と 1 文字ずつの単位でトークン化するため、
+// 分かち書きの無い日本語でも部分一致で検索でき、1 文字だけの検索語でも一致する。
+// インデックスは VIDEOS から再構築できるので、アップグレード時には保存しない。
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::error::{ApiError, ApiResult};
+use crate::{Video, VIDEOS};
+
+// フィールドごとの重み (タイトルへの一致を優先する)
+const TITLE_WEIGHT: u32 = 3;
+const TAG_WEIGHT: u32 = 2;
+const DESCRIPTION_WEIGHT: u32 = 1;
+const DEFAULT_LIMIT: u32 = 20;
+const MAX_LIMIT: u32 = 100;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct SearchHit {
+    pub video_id: String,
+    pub title: String,
+    pub description: String,
+    pub score: u32,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct SearchVideosResponse {
+    pub hits: Vec<SearchHit>,
+    pub next_cursor: Option<String>, // 続きがある場合に次の呼び出しで渡す
+}
+
+#[derive(Default)]
+struct SearchIndex {
+    postings: HashMap<String, HashMap<String, u32>>, // トークン -> (動画ID -> 重み付き出現回数)
+    video_tokens: HashMap<String, Vec<String>>,      // 動画ID -> 登録したトークン (削除用)
+}
+
+thread_local! {
+    static SEARCH_INDEX: RefCell<SearchIndex> = RefCell::new(SearchIndex::default());
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30FF}'   // ひらがな・カタカナ
+        | '\u{3400}'..='\u{4DBF}' // CJK 統合漢字拡張 A
+        | '\u{4E00}'..='\u{9FFF}' // CJK 統合漢字
+        | '\u{F900}'..='\u{FAFF}' // CJK 互換漢字
+        | '\u{AC00}'..='\u{D7AF}' // ハングル
+        | '\u{FF66}'..='\u{FF9F}' // 半角カタカナ
+    )
+}
+
+fn push_cjk_run(run: &mut Vec<char>, tokens: &mut Vec<String>) {
+    tokens.extend(run.windows(2).map(|pair| pair.iter().collect()));
+    tokens.extend(run.iter().map(char::to_string));
+    run.clear();
+}
+
+/// テキストをトークン列に分割する
+/// 英数字の連続は小文字化した単語、CJK 文字の連続は 2-gram と 1 文字ずつのトークンにする
+pub(crate) fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut cjk_run = Vec::new();
+    for c in text.chars() {
+        if is_cjk(c) {
+            if !word.is_empty() {
+                tokens.push(std::mem::take(&mut word));
+            }
+            cjk_run.push(c);
+        } else if c.is_alphanumeric() {
+            push_cjk_run(&mut cjk_run, &mut tokens);
+            word.extend(c.to_lowercase());
+        } else {
+            push_cjk_run(&mut cjk_run, &mut tokens);
+            if !word.is_empty() {
+                tokens.push(std::mem::take(&mut word));
+            }
+        }
+    }
+    push_cjk_run(&mut cjk_run, &mut tokens);
+    if !word.is_empty() {
+        tokens.push(word);
+    }
+    tokens
+}
+
+fn weighted_tokens(video: &Video) -> HashMap<String, u32> {
+    let mut weights: HashMap<String, u32> = HashMap::new();
+    let fields = [
+        (&video.title, TITLE_WEIGHT),
+        (&video.description, DESCRIPTION_WEIGHT),
+    ];
+    let tags = video.tags.iter().map(|tag| (tag, TAG_WEIGHT));
+    for (text, weight) in fields.into_iter().chain(tags) {
+        for token in tokenize(text) {
+            *weights.entry(token).or_default() += weight;
+        }
+    }
+    weights
+}
+
+/// 動画をインデックスに登録する (登録済みの場合は置き換える)
+pub(crate) fn index_video(video: &Video) {
+    unindex_video(&video.id);
+    let weights = weighted_tokens(video);
+    SEARCH_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        let tokens = weights.keys().cloned().collect();
+        for (token, weight) in weights {
+            index.postings.entry(token).or_default().insert(video.id.clone(), weight);
+        }
+        index.video_tokens.insert(video.id.clone(), tokens);
+    });
+}
+
+/// 動画をインデックスから削除する
+pub(crate) fn unindex_video(video_id: &str) {
+    SEARCH_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        let Some(tokens) = index.video_tokens.remove(video_id) else {
+            return;
+        };
+        for token in tokens {
+            if let Some(postings) = index.postings.get_mut(&token) {
+                postings.remove(video_id);
+                if postings.is_empty() {
+                    index.postings.remove(&token);
+                }
+            }
+        }
+    });
+}
+
+/// 動画を検索し、スコアの高い順に返す
+/// query のすべてのトークンを含む動画だけが対象になる
+/// cursor には前回のレスポンスの next_cursor を渡す
+#[query]
+fn search_videos(query: String, limit: Option<u32>, cursor: Option<String>) -> ApiResult<SearchVideosResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT) as usize;
+    let offset = match cursor.map(|cursor| cursor.parse::<usize>()) {
+        None => 0,
+        Some(Ok(offset)) => offset,
+        Some(Err(_)) => return ApiResult::Err(ApiError::invalid_argument("cursor", "Invalid cursor")),
+    };
+
+    let mut query_tokens = tokenize(&query);
+    query_tokens.sort();
+    query_tokens.dedup();
+    if query_tokens.is_empty() {
+        return ApiResult::Err(ApiError::invalid_argument(
+            "query",
+            "Query must contain at least one searchable word",
+        ));
+    }
+
+    let mut scored: Vec<(String, u32)> = SEARCH_INDEX.with(|index| {
+        let index = index.borrow();
+        let Some(postings) = query_tokens
+            .iter()
+            .map(|token| index.postings.get(token))
+            .collect::<Option<Vec<_>>>()
+        else {
+            return Vec::new();
+        };
+        // 最も件数の少ないトークンから候補を絞り込む
+        let smallest = postings.iter().min_by_key(|p| p.len()).unwrap();
+        smallest
+            .keys()
+            .filter_map(|video_id| {
+                postings
+                    .iter()
+                    .map(|p| p.get(video_id))
+                    .sum::<Option<u32>>()
+                    .map(|score| (video_id.clone(), score))
+            })
+            .collect()
+    });
+    // 公開されていない動画は結果に含めない
+    VIDEOS.with(|videos| {
+        let videos = videos.borrow();
+        scored.retain(|(video_id, _)| videos.get(video_id).is_some_and(Video::is_listed));
+    });
+    // スコアの高い順、同点の場合は新しい動画 (ID は作成時刻) を先にする
+    scored.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| b.0.cmp(&a.0)));
+
+    let total = scored.len();
+    let hits = VIDEOS.with(|videos| {
+        let videos = videos.borrow();
+        scored
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .filter_map(|(video_id, score)| {
+                videos.get(&video_id).map(|video| SearchHit {
+                    video_id,
+                    title: video.title.clone(),
+                    description: video.description.clone(),
+                    score,
+                })
+            })
+            .collect()
+    });
+    let next_offset = offset + limit;
+    ApiResult::Ok(SearchVideosResponse {
+        hits,
+        next_cursor: (next_offset < total).then(|| next_offset.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::tokenize;
+
+    #[test]
+    fn latin_text_is_split_into_lowercase_words() {
+        assert_eq!(tokenize("Hello World 2024"), ["hello", "world", "2024"]);
+    }
+
+    #[test]
+    fn punctuation_separates_words_and_is_dropped() {
+        assert_eq!(tokenize("rust, ic-cdk! (v0.17)"), ["rust", "ic", "cdk", "v0", "17"]);
+        assert!(tokenize("!?、。 ...").is_empty());
+    }
+
+    #[test]
+    fn single_cjk_character_is_a_token() {
+        assert_eq!(tokenize("猫"), ["猫"]);
+    }
+
+    #[test]
+    fn cjk_run_yields_bigrams_and_characters() {
+        assert_eq!(tokenize("子猫"), ["子猫", "子", "猫"]);
+        assert_eq!(tokenize("動画配信"), ["動画", "画配", "配信", "動", "画", "配", "信"]);
+    }
+
+    #[test]
+    fn mixed_text_splits_at_script_boundaries() {
+        assert_eq!(tokenize("Rustで猫"), ["rust", "で猫", "で", "猫"]);
+        assert_eq!(tokenize("猫 cat"), ["猫", "cat"]);
+    }
+
+    // 1 文字の検索語も、その文字を含むテキストのトークンに一致する
+    #[test]
+    fn single_character_query_matches_indexed_text() {
+        let indexed = tokenize("かわいい猫の動画");
+        assert!(tokenize("猫").iter().all(|token| indexed.contains(token)));
+    }
+}
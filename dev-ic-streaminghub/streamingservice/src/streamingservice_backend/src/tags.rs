@@ -0,0 +1,275 @@
+// 動画のタグとカテゴリ
+//
+// タグは正規化 (前後の空白と先頭の # を除去、小文字化、空白を - に置換) してから保存する。
+// タグごとの動画ID一覧をインデックスとして持ち、VIDEOS への登録・削除に合わせて更新する。
+// インデックスは VIDEOS から再構築できるので、アップグレード時には保存しない。
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+
+use crate::error::{ApiError, ApiResult};
+use crate::{search, Video, VideoSummary, VIDEOS};
+
+const MAX_TAGS_PER_VIDEO: usize = 20;
+const MAX_TAG_LEN: usize = 32;
+const MAX_CATEGORY_LEN: usize = 64;
+const DEFAULT_LIMIT: u32 = 20;
+const MAX_LIMIT: u32 = 100;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TagCount {
+    pub tag: String,
+    pub count: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct VideosByTagResponse {
+    pub videos: Vec<VideoSummary>,
+    pub next_cursor: Option<String>, // 続きがある場合に次の呼び出しで渡す
+}
+
+thread_local! {
+    static TAG_INDEX: RefCell<HashMap<String, BTreeSet<String>>> = RefCell::new(HashMap::new());
+}
+
+/// タグを正規化する。空になる場合や長すぎる場合はエラー
+pub(crate) fn normalize_tag(tag: &str) -> Result<String, ApiError> {
+    let normalized = tag
+        .trim()
+        .trim_start_matches('#')
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join("-")
+        .to_lowercase();
+    if normalized.is_empty() {
+        return Err(ApiError::invalid_argument("tags", "Tag must not be empty"));
+    }
+    if normalized.chars().count() > MAX_TAG_LEN {
+        return Err(ApiError::invalid_argument(
+            "tags",
+            format!("Tag '{}' is longer than {} characters", normalized, MAX_TAG_LEN),
+        ));
+    }
+    Ok(normalized)
+}
+
+fn normalize_category(category: &str) -> Result<String, ApiError> {
+    let normalized = category.split_whitespace().collect::<Vec<_>>().join(" ");
+    if normalized.is_empty() {
+        return Err(ApiError::invalid_argument("category", "Category must not be empty"));
+    }
+    if normalized.chars().count() > MAX_CATEGORY_LEN {
+        return Err(ApiError::invalid_argument(
+            "category",
+            format!("Category is longer than {} characters", MAX_CATEGORY_LEN),
+        ));
+    }
+    Ok(normalized)
+}
+
+/// 動画のタグをインデックスに登録する
+pub(crate) fn index_video(video: &Video) {
+    TAG_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for tag in &video.tags {
+            index.entry(tag.clone()).or_default().insert(video.id.clone());
+        }
+    });
+}
+
+/// 動画のタグをインデックスから削除する
+pub(crate) fn unindex_video(video: &Video) {
+    TAG_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for tag in &video.tags {
+            if let Some(video_ids) = index.get_mut(tag) {
+                video_ids.remove(&video.id);
+                if video_ids.is_empty() {
+                    index.remove(tag);
+                }
+            }
+        }
+    });
+}
+
+/// 所有者の確認をしてから動画を更新し、タグと検索のインデックスを付け直す
+fn update_video(video_id: &str, update: impl FnOnce(&mut Video) -> Result<(), ApiError>) -> ApiResult<()> {
+    let caller = ic_cdk::caller();
+    VIDEOS.with(|videos| {
+        let mut videos = videos.borrow_mut();
+        let Some(video) = videos.get_mut(video_id) else {
+            return ApiResult::Err(ApiError::not_found("Video"));
+        };
+        if !video.can_manage(&caller) {
+            return ApiResult::Err(ApiError::unauthorized("Caller is not the owner of the video"));
+        }
+        unindex_video(video);
+        let result = update(video);
+        index_video(video);
+        search::index_video(video);
+        match result {
+            Ok(()) => ApiResult::Ok(()),
+            Err(e) => ApiResult::Err(e),
+        }
+    })
+}
+
+/// 動画にタグを追加する (動画の所有者またはコントローラーのみ)
+#[update]
+fn add_tags(video_id: String, tags: Vec<String>) -> ApiResult<()> {
+    let tags = match tags.iter().map(|tag| normalize_tag(tag)).collect::<Result<Vec<_>, _>>() {
+        Ok(tags) => tags,
+        Err(e) => return ApiResult::Err(e),
+    };
+    update_video(&video_id, |video| {
+        let mut merged: BTreeSet<String> = video.tags.iter().cloned().collect();
+        merged.extend(tags);
+        if merged.len() > MAX_TAGS_PER_VIDEO {
+            return Err(ApiError::quota_exceeded(format!(
+                "A video can have at most {} tags",
+                MAX_TAGS_PER_VIDEO
+            )));
+        }
+        video.tags = merged.into_iter().collect();
+        Ok(())
+    })
+}
+
+/// 動画からタグを削除する (動画の所有者またはコントローラーのみ)
+#[update]
+fn remove_tags(video_id: String, tags: Vec<String>) -> ApiResult<()> {
+    let tags: BTreeSet<String> = tags.iter().filter_map(|tag| normalize_tag(tag).ok()).collect();
+    update_video(&video_id, |video| {
+        video.tags.retain(|tag| !tags.contains(tag));
+        Ok(())
+    })
+}
+
+/// 動画のカテゴリを設定する。None を渡すとカテゴリを外す (動画の所有者またはコントローラーのみ)
+#[update]
+fn set_video_category(video_id: String, category: Option<String>) -> ApiResult<()> {
+    let category = match category.as_deref().map(normalize_category).transpose() {
+        Ok(category) => category,
+        Err(e) => return ApiResult::Err(e),
+    };
+    update_video(&video_id, |video| {
+        video.category = category;
+        Ok(())
+    })
+}
+
+/// 使われているタグと、そのタグが付いた動画数を多い順に返す
+/// list_videos_by_tag と同じく一覧に表示できる動画だけを数え、そのような動画が無いタグは返さない
+#[query]
+fn list_tags() -> Vec<TagCount> {
+    let mut tags: Vec<TagCount> = TAG_INDEX.with(|index| {
+        VIDEOS.with(|videos| {
+            let videos = videos.borrow();
+            index
+                .borrow()
+                .iter()
+                .map(|(tag, video_ids)| TagCount {
+                    tag: tag.clone(),
+                    count: video_ids
+                        .iter()
+                        .filter(|video_id| videos.get(*video_id).is_some_and(Video::is_listed))
+                        .count() as u64,
+                })
+                .filter(|tag| tag.count > 0)
+                .collect()
+        })
+    });
+    tags.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.tag.cmp(&b.tag)));
+    tags
+}
+
+/// 指定されたタグが付いた動画を新しい順に返す
+/// cursor には前回のレスポンスの next_cursor を渡す
+#[query]
+fn list_videos_by_tag(tag: String, limit: Option<u32>, cursor: Option<String>) -> ApiResult<VideosByTagResponse> {
+    let tag = match normalize_tag(&tag) {
+        Ok(tag) => tag,
+        Err(e) => return ApiResult::Err(e),
+    };
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT) as usize;
+
+    // 動画IDは作成時刻なので、ID の降順が新しい順になる
+    let mut video_ids: Vec<String> = TAG_INDEX.with(|index| {
+        VIDEOS.with(|videos| {
+            let videos = videos.borrow();
+            index
+                .borrow()
+                .get(&tag)
+                .map(|video_ids| {
+                    video_ids
+                        .iter()
+                        .rev()
+                        .filter(|video_id| cursor.as_ref().is_none_or(|cursor| *video_id < cursor))
+                        .filter(|video_id| videos.get(*video_id).is_some_and(Video::is_listed))
+                        .take(limit + 1)
+                        .cloned()
+                        .collect()
+                })
+                .unwrap_or_default()
+        })
+    });
+    let has_more = video_ids.len() > limit;
+    video_ids.truncate(limit);
+
+    let videos: Vec<VideoSummary> = VIDEOS.with(|videos| {
+        let videos = videos.borrow();
+        video_ids
+            .iter()
+            .filter_map(|video_id| videos.get(video_id).map(Video::summary))
+            .collect()
+    });
+    ApiResult::Ok(VideosByTagResponse {
+        next_cursor: if has_more { video_ids.last().cloned() } else { None },
+        videos,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize_tag, MAX_TAG_LEN};
+    use crate::error::ApiError;
+
+    #[test]
+    fn leading_hashes_are_stripped() {
+        assert_eq!(normalize_tag("#rust").unwrap(), "rust");
+        assert_eq!(normalize_tag("  ##rust ").unwrap(), "rust");
+        assert_eq!(normalize_tag("c#").unwrap(), "c#");
+    }
+
+    #[test]
+    fn whitespace_runs_become_a_single_hyphen() {
+        assert_eq!(normalize_tag("live  coding\tsession").unwrap(), "live-coding-session");
+        assert_eq!(normalize_tag("# ic cdk").unwrap(), "ic-cdk");
+    }
+
+    #[test]
+    fn tags_are_lowercased() {
+        assert_eq!(normalize_tag("Rust IC").unwrap(), "rust-ic");
+        assert_eq!(normalize_tag("猫").unwrap(), "猫");
+    }
+
+    #[test]
+    fn empty_tags_are_rejected() {
+        for tag in ["", "   ", "#", " ## "] {
+            assert!(matches!(normalize_tag(tag), Err(ApiError::InvalidArgument { .. })), "{tag:?}");
+        }
+    }
+
+    // 上限はバイト数ではなく文字数で数える
+    #[test]
+    fn length_limit_counts_characters() {
+        assert!(normalize_tag(&"猫".repeat(MAX_TAG_LEN)).is_ok());
+        assert!(matches!(
+            normalize_tag(&"a".repeat(MAX_TAG_LEN + 1)),
+            Err(ApiError::InvalidArgument { .. })
+        ));
+        // 正規化した後の長さで判定する
+        assert!(normalize_tag(&format!("#{}", "a".repeat(MAX_TAG_LEN))).is_ok());
+    }
+}
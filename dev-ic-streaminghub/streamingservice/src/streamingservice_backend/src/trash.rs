@@ -0,0 +1,266 @@
+// 削除された動画のゴミ箱
+//
+// delete_video で削除された動画は VIDEOS から取り除かれてここへ移動するため、
+// 一覧や再生の API からは見えなくなる。保持期間内は所有者が復元でき、
+// 保持期間を過ぎたものは定期タイマーで完全に削除される。
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use ic_cdk_timers::TimerId;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::error::{ApiError, ApiResult};
+use crate::{discard_video, ensure_controller, insert_video, schedule, Video};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TrashConfig {
+    pub retention_secs: u64, // 削除からこの秒数の間は復元できる
+    pub interval_secs: u64,  // 完全削除タイマーの実行間隔
+}
+
+impl Default for TrashConfig {
+    fn default() -> Self {
+        TrashConfig {
+            retention_secs: 30 * 24 * 60 * 60,
+            interval_secs: 60 * 60,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct TrashedVideo {
+    video: Video,
+    deleted_at: u64,
+    deleted_by: Principal,
+}
+
+// ゴミ箱内の動画情報
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TrashedVideoInfo {
+    pub video_id: String,
+    pub title: String,
+    pub deleted_at: u64,
+    pub purge_at: u64, // この日時を過ぎると完全に削除される
+}
+
+// アップグレード時に退避する状態
+#[derive(CandidType, Deserialize, Default)]
+pub(crate) struct TrashState {
+    videos: HashMap<String, TrashedVideo>,
+    config: TrashConfig,
+}
+
+thread_local! {
+    static TRASH: RefCell<HashMap<String, TrashedVideo>> = RefCell::new(HashMap::new());
+    static TRASH_CONFIG: RefCell<TrashConfig> = RefCell::new(TrashConfig::default());
+    static TRASH_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+}
+
+pub(crate) fn save() -> TrashState {
+    TrashState {
+        videos: TRASH.with(|trash| trash.take()),
+        config: TRASH_CONFIG.with(|config| config.take()),
+    }
+}
+
+pub(crate) fn restore(state: TrashState) {
+    TRASH.with(|trash| *trash.borrow_mut() = state.videos);
+    TRASH_CONFIG.with(|config| *config.borrow_mut() = state.config);
+}
+
+/// 完全削除タイマーを (再) 設定する
+/// init / post_upgrade と設定変更時に呼ばれる
+pub(crate) fn start_timer() {
+    let interval = TRASH_CONFIG.with(|config| config.borrow().interval_secs).max(1);
+    let timer_id = ic_cdk_timers::set_timer_interval(Duration::from_secs(interval), || {
+        let purged = purge_expired(ic_cdk::api::time());
+        if purged > 0 {
+            ic_cdk::println!("Purged {} videos from trash", purged);
+        }
+    });
+    TRASH_TIMER.with(|timer| {
+        if let Some(old_timer_id) = timer.borrow_mut().replace(timer_id) {
+            ic_cdk_timers::clear_timer(old_timer_id);
+        }
+    });
+}
+
+pub(crate) fn move_to_trash(video: Video, deleted_by: Principal) {
+    let trashed = TrashedVideo {
+        video,
+        deleted_at: ic_cdk::api::time(),
+        deleted_by,
+    };
+    TRASH.with(|trash| {
+        trash.borrow_mut().insert(trashed.video.id.clone(), trashed);
+    });
+}
+
+impl TrashedVideo {
+    /// 保持期間を過ぎて復元できなくなっていれば true
+    fn is_expired(&self, now: u64, retention: u64) -> bool {
+        now.saturating_sub(self.deleted_at) > retention
+    }
+}
+
+fn retention_nanos() -> u64 {
+    TRASH_CONFIG.with(|config| config.borrow().retention_secs.saturating_mul(NANOS_PER_SEC))
+}
+
+fn purge_expired(now: u64) -> usize {
+    let retention = retention_nanos();
+    TRASH.with(|trash| {
+        let mut trash = trash.borrow_mut();
+        let before = trash.len();
+        trash.retain(|_, trashed| {
+            let keep = !trashed.is_expired(now, retention);
+            if !keep {
+                discard_video(&trashed.video);
+            }
+            keep
+        });
+        before - trash.len()
+    })
+}
+
+/// 呼び出し元が削除した (または所有する) ゴミ箱内の動画一覧を返す
+/// コントローラーは全件を参照できる
+#[query]
+fn list_trash() -> Vec<TrashedVideoInfo> {
+    let caller = ic_cdk::caller();
+    let retention = retention_nanos();
+    TRASH.with(|trash| {
+        trash
+            .borrow()
+            .values()
+            .filter(|trashed| trashed.deleted_by == caller || trashed.video.can_manage(&caller))
+            .map(|trashed| TrashedVideoInfo {
+                video_id: trashed.video.id.clone(),
+                title: trashed.video.title.clone(),
+                deleted_at: trashed.deleted_at,
+                purge_at: trashed.deleted_at.saturating_add(retention),
+            })
+            .collect()
+    })
+}
+
+/// ゴミ箱内の動画を復元する (所有者のみ, 保持期間内のみ)
+#[update]
+fn restore_video(video_id: String) -> ApiResult<String> {
+    let caller = ic_cdk::caller();
+    let now = ic_cdk::api::time();
+    let retention = retention_nanos();
+    let restored = TRASH.with(|trash| {
+        let mut trash = trash.borrow_mut();
+        match trash.get(&video_id) {
+            Some(trashed) if trashed.video.owner != caller => {
+                Err(ApiError::unauthorized("Caller is not the owner of the video"))
+            }
+            Some(trashed) if trashed.is_expired(now, retention) => {
+                Err(ApiError::conflict("Retention period has expired"))
+            }
+            Some(_) => Ok(trash.remove(&video_id).unwrap().video),
+            None => Err(ApiError::not_found("Video in trash")),
+        }
+    });
+    match restored {
+        Ok(video) => {
+            insert_video(video);
+            schedule::rearm_timer();
+            ApiResult::Ok(video_id)
+        }
+        Err(e) => ApiResult::Err(e),
+    }
+}
+
+/// ゴミ箱内の動画を保持期間を待たずに完全削除する (コントローラーのみ)
+#[update]
+fn purge_video(video_id: String) -> ApiResult<()> {
+    if let Err(e) = ensure_controller() {
+        return ApiResult::Err(e);
+    }
+    TRASH.with(|trash| {
+        if let Some(trashed) = trash.borrow_mut().remove(&video_id) {
+            discard_video(&trashed.video);
+            ApiResult::Ok(())
+        } else {
+            ApiResult::Err(ApiError::not_found("Video in trash"))
+        }
+    })
+}
+
+#[query]
+fn get_trash_config() -> TrashConfig {
+    TRASH_CONFIG.with(|config| config.borrow().clone())
+}
+
+/// 保持期間と実行間隔を変更し、タイマーを再設定する (コントローラーのみ)
+#[update]
+fn set_trash_config(config: TrashConfig) -> ApiResult<TrashConfig> {
+    if let Err(e) = ensure_controller() {
+        return ApiResult::Err(e);
+    }
+    if config.interval_secs == 0 {
+        return ApiResult::Err(ApiError::invalid_argument(
+            "interval_secs",
+            "interval_secs must be greater than 0",
+        ));
+    }
+    TRASH_CONFIG.with(|current| *current.borrow_mut() = config.clone());
+    start_timer();
+    ApiResult::Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blobs;
+
+    const RETENTION: u64 = 30 * 24 * 60 * 60 * NANOS_PER_SEC;
+
+    fn trashed(id: &str, deleted_at: u64) -> TrashedVideo {
+        let owner = Principal::self_authenticating(b"owner");
+        TrashedVideo {
+            video: Video::for_test(id, owner, 0),
+            deleted_at,
+            deleted_by: owner,
+        }
+    }
+
+    #[test]
+    fn video_can_be_restored_until_the_end_of_retention() {
+        let trashed = trashed("video", 1_000);
+        assert!(!trashed.is_expired(1_000, RETENTION));
+        assert!(!trashed.is_expired(1_000 + RETENTION, RETENTION));
+        assert!(trashed.is_expired(1_000 + RETENTION + 1, RETENTION));
+    }
+
+    // 削除日時が未来 (時計の巻き戻り) でも期限切れにしない
+    #[test]
+    fn video_deleted_in_the_future_is_not_expired() {
+        assert!(!trashed("video", 2_000).is_expired(1_000, RETENTION));
+    }
+
+    #[test]
+    fn purge_removes_only_expired_videos_and_releases_their_chunks() {
+        let owner = Principal::self_authenticating(b"owner");
+        let hash = blobs::put(vec![1; 10], owner);
+        let mut expired = trashed("expired", 0);
+        expired.video.segments = vec![crate::SegmentInfo {
+            chunks: vec![Some(hash)],
+            total_chunk_count: 1,
+        }];
+        TRASH.with(|trash| {
+            let mut trash = trash.borrow_mut();
+            trash.insert("expired".to_string(), expired);
+            trash.insert("kept".to_string(), trashed("kept", RETENTION));
+        });
+
+        assert_eq!(purge_expired(RETENTION + 1), 1);
+        TRASH.with(|trash| assert!(trash.borrow().keys().eq(["kept"])));
+        assert_eq!(blobs::get(&hash), None);
+    }
+}
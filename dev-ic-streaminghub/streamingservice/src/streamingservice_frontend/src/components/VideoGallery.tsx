@@ -137,6 +137,7 @@ export const VideoGallery: React.FC = () => {
       // セグメントを順次アップロード（チャンクサイズとバッチサイズを最適化）
       const CHUNK_SIZE = 0.5 * 1024 * 1024; // 512KBに縮小
       const BATCH_SIZE = 10; // 同時アップロード数を制限
+      const CHUNKS_PER_CALL = 3; // upload_chunks_batch 1回で送るチャンク数（ingress上限 2MiB 未満に収める）
       const RETRY_COUNT = 3; // リトライ回数
       const RETRY_DELAY = 2000; // リトライ間隔（ミリ秒）
 
@@ -155,36 +156,47 @@ export const VideoGallery: React.FC = () => {
           chunks.push(segment.data.slice(offset, Math.min(offset + CHUNK_SIZE, segment.data.length)));
         }
 
-        for (let chunk_index = 0; chunk_index < chunks.length; chunk_index++) {
+        // 複数チャンクを1回の呼び出しでまとめて送る。失敗したチャンクだけを再送する
+        let pending = chunks.map((_, chunk_index) => chunk_index);
+        while (pending.length > 0) {
+          const callChunks = pending.slice(0, CHUNKS_PER_CALL);
           let retries = 0;
-          let success = false;
+          let remaining = callChunks;
 
-          while (retries < RETRY_COUNT && !success) {
+          while (remaining.length > 0) {
             try {
-              console.log(`--------------Uploading segment ${segment.index} / ${segments.length}, chunk ${chunk_index + 1}/${chunks.length}`);
-              const result = await actor.upload_ts_segment_chunk(
+              console.log(`--------------Uploading segment ${segment.index} / ${segments.length}, chunks ${remaining.map(c => c + 1).join(',')}/${chunks.length}`);
+              const result = await actor.upload_chunks_batch(
                 backendApiVersion,
                 video_id,
-                segment.index,
-                chunk_index,
-                chunks.length,
-                Array.from(chunks[chunk_index])
+                remaining.map(chunk_index => ({
+                  segment_index: segment.index,
+                  chunk_index,
+                  total_chunk_count: chunks.length,
+                  segment_chunk_data: Array.from(chunks[chunk_index]),
+                }))
               );
 
-              if ('ok' in result) {
-                success = true;
-                console.log(`--------------Successfully uploaded segment ${segment.index} / ${segments.length}, chunk ${chunk_index + 1}/${chunks.length}`);
-              } else {
-                throw new Error(`Upload failed: ${result.err || 'Unknown error'}`);
+              if ('err' in result) {
+                throw new Error(`Upload failed: ${JSON.stringify(result.err)}`);
+              }
+              const failed = result.ok.filter(entry => 'err' in entry.result);
+              uploadedChunks += result.ok.length - failed.length;
+              // アップロード進捗は30-100%で表示（FFmpeg処理が0-30%）
+              setUploadProgress(30 + (uploadedChunks / totalChunks) * 70);
+              remaining = failed.map(entry => entry.chunk_index);
+              if (remaining.length > 0) {
+                throw new Error(`Upload failed for chunks ${remaining.map(c => c + 1).join(',')}`);
               }
+              console.log(`--------------Successfully uploaded segment ${segment.index} / ${segments.length}, chunks ${callChunks.map(c => c + 1).join(',')}/${chunks.length}`);
             } catch (error) {
               retries++;
-              console.error(`Upload attempt ${retries} failed for segment ${segment.index}, chunk ${chunk_index + 1}:`, error);
-              
+              console.error(`Upload attempt ${retries} failed for segment ${segment.index}:`, error);
+
               if (retries === RETRY_COUNT) {
-                throw new Error(`Failed to upload segment ${segment.index} chunk ${chunk_index + 1} after ${RETRY_COUNT} retries: ${error}`);
+                throw new Error(`Failed to upload segment ${segment.index} after ${RETRY_COUNT} retries: ${error}`);
               }
-              
+
               // 指数バックオフで待機
               const delay = RETRY_DELAY * Math.pow(2, retries - 1);
               console.log(`Waiting ${delay}ms before retry...`);
@@ -192,14 +204,7 @@ export const VideoGallery: React.FC = () => {
             }
           }
 
-          if (!success) {
-            throw new Error(`Failed to upload segment ${segment.index} chunk ${chunk_index + 1} after all retries`);
-          }
-
-          uploadedChunks++;
-          // アップロード進捗は30-100%で表示（FFmpeg処理が0-30%）
-          const uploadProgress = (uploadedChunks / totalChunks) * 70;
-          setUploadProgress(30 + uploadProgress);
+          pending = pending.slice(CHUNKS_PER_CALL);
         }
       };
 
@@ -273,6 +278,12 @@ export const VideoGallery: React.FC = () => {
         console.log('Thumbnail upload completed');
       }
 
+      // アップロード完了を確定する（未確定のまま放置された動画はGCで削除される）
+      const finalizeResult = await actor.finalize_video(backendApiVersion, video_id);
+      if ('err' in finalizeResult) {
+        throw new Error(`Finalize failed: ${JSON.stringify(finalizeResult.err)}`);
+      }
+
       console.log('get_video_list');
       // 動画リストを更新
       const videoList = await actor.get_video_list();
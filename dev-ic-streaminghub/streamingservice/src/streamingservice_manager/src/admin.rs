@@ -0,0 +1,173 @@
+// マネージャーの管理者とキャニスター作成の許可
+//
+// マネージャーはすべての子キャニスターのコントローラーなので、子キャニスターを操作する API は
+// 台帳上の所有者か管理者だけが呼び出せる。匿名の呼び出しはすべて拒否する。
+// 管理者はコントローラーが add_admin / remove_admin で登録する。コントローラー自身も管理者として扱う。
+// 管理者と、管理者が許可リストに登録したプリンシパルは支払いなしでキャニスターを作成できる。
+// 作成の方針を Open にすると、それ以外のプリンシパルも支払って作成できる (payments.rs)。
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+use crate::payments;
+use crate::registry;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+pub enum CreationPolicy {
+    #[default]
+    Allowlist, // 管理者と許可リストのプリンシパルだけが作成できる
+    Open,      // それ以外の匿名でないプリンシパルも、支払えば作成できる
+}
+
+// アップグレード時に退避する状態
+#[derive(CandidType, Deserialize, Default)]
+pub(crate) struct AdminState {
+    admins: BTreeSet<Principal>,
+    creators: BTreeSet<Principal>, // キャニスターの作成を許可したプリンシパル
+    creation_policy: CreationPolicy,
+}
+
+thread_local! {
+    static ADMIN_STATE: RefCell<AdminState> = RefCell::new(AdminState::default());
+}
+
+pub(crate) fn save() -> AdminState {
+    ADMIN_STATE.with(|state| state.take())
+}
+
+pub(crate) fn restore(state: AdminState) {
+    ADMIN_STATE.with(|s| *s.borrow_mut() = state);
+}
+
+// コントローラー以外の呼び出しを拒否する
+pub(crate) fn ensure_controller() -> Result<(), String> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err("Caller is not a controller".to_string())
+    }
+}
+
+// 匿名の呼び出しを拒否し、呼び出し元を返す
+pub(crate) fn ensure_authenticated() -> Result<Principal, String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err("Anonymous callers are not allowed".to_string());
+    }
+    Ok(caller)
+}
+
+/// 管理者またはコントローラーであれば true
+pub(crate) fn is_admin(principal: &Principal) -> bool {
+    ic_cdk::api::is_controller(principal)
+        || ADMIN_STATE.with(|state| state.borrow().admins.contains(principal))
+}
+
+// 呼び出し元が管理者であることを確認し、呼び出し元を返す
+pub(crate) fn ensure_admin() -> Result<Principal, String> {
+    let caller = ic_cdk::caller();
+    if !is_admin(&caller) {
+        return Err("Caller is not an admin".to_string());
+    }
+    Ok(caller)
+}
+
+// 呼び出し元がキャニスターの所有者か管理者であることを確認する
+pub(crate) fn ensure_owner_or_admin(canister_id: &Principal) -> Result<(), String> {
+    let caller = ensure_authenticated()?;
+    if is_admin(&caller) {
+        return Ok(());
+    }
+    match registry::get(canister_id) {
+        Some(record) if record.owner == caller => Ok(()),
+        _ => Err("Caller is not the owner of the canister".to_string()),
+    }
+}
+
+/// 支払いなしでキャニスターを作成できれば true (管理者と許可リストのプリンシパル)
+pub(crate) fn is_exempt_from_payment(principal: &Principal) -> bool {
+    is_admin(principal) || ADMIN_STATE.with(|state| state.borrow().creators.contains(principal))
+}
+
+// 呼び出し元がキャニスターを作成できることを確認し、呼び出し元を返す
+// 支払いが必要な呼び出し元は、方針が Open で支払いを受け付けている場合だけ作成できる
+// (支払いは payments::charge で受け取る)
+pub(crate) fn ensure_can_create() -> Result<Principal, String> {
+    let caller = ensure_authenticated()?;
+    if is_exempt_from_payment(&caller) {
+        return Ok(caller);
+    }
+    if ADMIN_STATE.with(|state| state.borrow().creation_policy) != CreationPolicy::Open {
+        return Err("Caller is not allowed to create canisters".to_string());
+    }
+    if !payments::enabled() {
+        return Err("Creating canisters requires a payment, but payments are not enabled".to_string());
+    }
+    Ok(caller)
+}
+
+/// 管理者を追加する (コントローラーのみ)
+#[update]
+fn add_admin(principal: Principal) -> Result<(), String> {
+    ensure_controller()?;
+    if principal == Principal::anonymous() {
+        return Err("The anonymous principal cannot be an admin".to_string());
+    }
+    ADMIN_STATE.with(|state| state.borrow_mut().admins.insert(principal));
+    Ok(())
+}
+
+/// 管理者を削除する (コントローラーのみ)
+#[update]
+fn remove_admin(principal: Principal) -> Result<(), String> {
+    ensure_controller()?;
+    ADMIN_STATE.with(|state| state.borrow_mut().admins.remove(&principal));
+    Ok(())
+}
+
+/// 登録済みの管理者を返す (コントローラーを除く, 管理者のみ)
+#[query]
+fn list_admins() -> Result<Vec<Principal>, String> {
+    ensure_admin()?;
+    Ok(ADMIN_STATE.with(|state| state.borrow().admins.iter().cloned().collect()))
+}
+
+/// キャニスターの作成を許可する (管理者のみ)
+#[update]
+fn add_creator(principal: Principal) -> Result<(), String> {
+    ensure_admin()?;
+    if principal == Principal::anonymous() {
+        return Err("The anonymous principal cannot create canisters".to_string());
+    }
+    ADMIN_STATE.with(|state| state.borrow_mut().creators.insert(principal));
+    Ok(())
+}
+
+/// キャニスターの作成の許可を取り消す (管理者のみ)
+#[update]
+fn remove_creator(principal: Principal) -> Result<(), String> {
+    ensure_admin()?;
+    ADMIN_STATE.with(|state| state.borrow_mut().creators.remove(&principal));
+    Ok(())
+}
+
+/// キャニスターの作成を許可したプリンシパルを返す (管理者のみ)
+#[query]
+fn list_creators() -> Result<Vec<Principal>, String> {
+    ensure_admin()?;
+    Ok(ADMIN_STATE.with(|state| state.borrow().creators.iter().cloned().collect()))
+}
+
+#[query]
+fn get_creation_policy() -> CreationPolicy {
+    ADMIN_STATE.with(|state| state.borrow().creation_policy)
+}
+
+/// キャニスターを作成できるプリンシパルの方針を変更する (管理者のみ)
+#[update]
+fn set_creation_policy(policy: CreationPolicy) -> Result<(), String> {
+    ensure_admin()?;
+    ADMIN_STATE.with(|state| state.borrow_mut().creation_policy = policy);
+    Ok(())
+}
@@ -0,0 +1,110 @@
+// 子キャニスターをまたいだカタログの集計
+//
+// 台帳の streamingservice_backend のキャニスターを StreamingClient で並行して呼び出し、結果をまとめて返す。
+// composite query なので、子キャニスターはマネージャーと同じサブネットにある必要がある。
+// 呼び出しに失敗したキャニスターは全体をエラーにせず、キャニスターごとのエラーとして返す。
+use candid::{CandidType, Deserialize, Principal};
+use futures::future::join_all;
+use ic_cdk_macros::*;
+
+use crate::admin::{ensure_admin, ensure_owner_or_admin};
+use crate::client::{BlobStats, ChannelInfo, StreamingClient, VideoSummary};
+use crate::registry::{self, CanisterKind, CanisterRecord};
+
+// 並行して呼び出す子キャニスターの数
+const CALL_CONCURRENCY: usize = 20;
+
+#[derive(CandidType, Deserialize)]
+pub struct CanisterVideos {
+    pub canister_id: Principal,
+    pub title: String,
+    pub result: Result<Vec<VideoSummary>, String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ChannelUsage {
+    pub info: ChannelInfo,
+    pub blobs: BlobStats,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CanisterChannel {
+    pub canister_id: Principal,
+    pub result: Result<ChannelUsage, String>,
+}
+
+async fn channel_usage(client: StreamingClient) -> Result<ChannelUsage, String> {
+    let (info, blobs) = futures::join!(client.channel_info(), client.blob_stats());
+    Ok(ChannelUsage {
+        info: info?,
+        blobs: blobs?,
+    })
+}
+
+fn ensure_streaming(canister_id: &Principal) -> Result<(), String> {
+    match registry::get(canister_id) {
+        Some(record) if record.kind == CanisterKind::Streaming => Ok(()),
+        _ => Err("Canister is not a registered streaming canister".to_string()),
+    }
+}
+
+/// 台帳の streamingservice_backend のキャニスターごとに f を呼び出し、台帳の順に結果を返す
+async fn fan_out<T, F, Fut>(f: F) -> Vec<(CanisterRecord, T)>
+where
+    F: Fn(StreamingClient) -> Fut,
+    Fut: std::future::Future<Output = T>,
+{
+    let records = registry::list_by_kind(CanisterKind::Streaming);
+    let mut results = Vec::with_capacity(records.len());
+    for batch in records.chunks(CALL_CONCURRENCY) {
+        let outputs = join_all(batch.iter().map(|record| f(StreamingClient::new(record.canister_id)))).await;
+        results.extend(batch.iter().cloned().zip(outputs));
+    }
+    results
+}
+
+/// すべてのチャンネルの公開中の動画を返す
+#[query(composite = true)]
+async fn list_all_videos() -> Vec<CanisterVideos> {
+    fan_out(|client| async move { client.list_video_summaries().await })
+        .await
+        .into_iter()
+        .map(|(record, result)| CanisterVideos {
+            canister_id: record.canister_id,
+            title: record.title,
+            result,
+        })
+        .collect()
+}
+
+/// すべてのチャンネルの情報とストレージの使用量を返す (管理者のみ)
+#[query(composite = true)]
+async fn list_all_channels() -> Result<Vec<CanisterChannel>, String> {
+    ensure_admin()?;
+    Ok(fan_out(channel_usage)
+        .await
+        .into_iter()
+        .map(|(record, result)| CanisterChannel {
+            canister_id: record.canister_id,
+            result,
+        })
+        .collect())
+}
+
+/// チャンネルの情報とストレージの使用量を返す (所有者か管理者のみ)
+#[query(composite = true)]
+async fn get_channel_usage(canister_id: Principal) -> Result<ChannelUsage, String> {
+    ensure_owner_or_admin(&canister_id)?;
+    ensure_streaming(&canister_id)?;
+    channel_usage(StreamingClient::new(canister_id)).await
+}
+
+/// チャンネルの動画のタイトルを返す (所有者か管理者のみ)
+/// 子キャニスターは非表示・公開期間外の動画のタイトルを動画を管理できる呼び出し元にだけ返す (get_video_info_v2)。
+/// マネージャーは子キャニスターのコントローラーとしてそれらも受け取るため、所有者か管理者に限る
+#[query(composite = true)]
+async fn get_video_title(canister_id: Principal, video_id: String) -> Result<String, String> {
+    ensure_owner_or_admin(&canister_id)?;
+    ensure_streaming(&canister_id)?;
+    StreamingClient::new(canister_id).video_title(video_id).await
+}
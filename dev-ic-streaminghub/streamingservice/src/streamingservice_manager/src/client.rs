@@ -0,0 +1,128 @@
+// 子キャニスターを呼び出す型付きのクライアント
+//
+// streamingservice_backend と greet_backend のうちマネージャーが使うメソッドを、
+// 子キャニスターの .did と同じ型で呼び出す。呼び出しの失敗と子キャニスターが返した ApiError は
+// どちらも文字列のエラーにまとめる。
+use candid::{CandidType, Deserialize, Principal};
+
+use crate::Quotas;
+
+// streamingservice_backend の ApiError
+#[derive(CandidType, Deserialize, Debug)]
+pub enum ApiError {
+    NotFound { message: String },
+    Unauthorized { message: String },
+    InvalidArgument { field: String, message: String },
+    QuotaExceeded { message: String },
+    Conflict { message: String },
+    Internal { message: String },
+}
+
+// streamingservice_backend の ApiResult
+#[derive(CandidType, Deserialize)]
+enum ApiResult<T> {
+    #[serde(rename = "ok")]
+    Ok(T),
+    #[serde(rename = "err")]
+    Err(ApiError),
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ChannelInfo {
+    pub owner: Option<Principal>,
+    pub title: String,
+    pub description: String,
+    pub quotas: Quotas,
+    pub video_count: u64,
+    pub stored_bytes: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct BlobStats {
+    pub blob_count: u64,
+    pub stored_bytes: u64,
+    pub referenced_bytes: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ReactionCounts {
+    pub like: u64,
+    pub dislike: u64,
+    pub heart: u64,
+    pub laugh: u64,
+    pub surprised: u64,
+    pub sad: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct VideoSummary {
+    pub video_id: String,
+    pub title: String,
+    pub description: String,
+    pub owner: Principal,
+    pub tags: Vec<String>,
+    pub category: Option<String>,
+    pub created_at: u64,
+    pub published: bool,
+    pub reactions: ReactionCounts,
+}
+
+async fn call<A, R>(canister_id: Principal, method: &str, args: A) -> Result<R, String>
+where
+    A: candid::utils::ArgumentEncoder,
+    R: for<'a> Deserialize<'a> + CandidType,
+{
+    ic_cdk::call::<A, (R,)>(canister_id, method, args)
+        .await
+        .map(|(response,)| response)
+        .map_err(|(code, msg)| format!("Failed to call {}: code {:?}, message: {}", method, code, msg))
+}
+
+/// streamingservice_backend のクライアント
+pub(crate) struct StreamingClient {
+    canister_id: Principal,
+}
+
+impl StreamingClient {
+    pub(crate) fn new(canister_id: Principal) -> Self {
+        StreamingClient { canister_id }
+    }
+
+    /// チャンネルの情報と使用量
+    pub(crate) async fn channel_info(&self) -> Result<ChannelInfo, String> {
+        call(self.canister_id, "get_channel_info", ()).await
+    }
+
+    /// チャンクの保存状況
+    pub(crate) async fn blob_stats(&self) -> Result<BlobStats, String> {
+        call(self.canister_id, "get_blob_stats", ()).await
+    }
+
+    /// 公開中の動画の概要 (新しい順)
+    pub(crate) async fn list_video_summaries(&self) -> Result<Vec<VideoSummary>, String> {
+        call(self.canister_id, "list_video_summaries", ()).await
+    }
+
+    /// 動画のタイトル
+    pub(crate) async fn video_title(&self, video_id: String) -> Result<String, String> {
+        match call(self.canister_id, "get_video_info_v2", (video_id,)).await? {
+            ApiResult::Ok(title) => Ok(title),
+            ApiResult::Err(e) => Err(format!("get_video_info_v2 failed: {:?}", e)),
+        }
+    }
+}
+
+/// greet_backend のクライアント
+pub(crate) struct GreetClient {
+    canister_id: Principal,
+}
+
+impl GreetClient {
+    pub(crate) fn new(canister_id: Principal) -> Self {
+        GreetClient { canister_id }
+    }
+
+    pub(crate) async fn greet(&self, name: String) -> Result<String, String> {
+        call(self.canister_id, "greet", (name,)).await
+    }
+}
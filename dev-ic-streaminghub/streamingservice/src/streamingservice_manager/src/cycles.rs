@@ -0,0 +1,408 @@
+// 台帳のキャニスターのサイクル残高の監視と自動補充
+//
+// 定期タイマーで台帳のすべてのキャニスターの canister_status を取得し、
+// 残高がしきい値を下回ったキャニスターに目標残高までサイクルを送る。
+// しきい値と目標残高はキャニスターごとのポリシーで上書きでき、補充を止めることもできる。
+// 期間ごとの補充の合計は予算を超えず、マネージャー自身の残高も予備を下回らないようにする。
+// 補充の結果は履歴に残し、キャニスターの所有者が参照できる。
+use candid::{CandidType, Deserialize, Principal};
+use futures::future::join_all;
+use ic_cdk::api::management_canister::main::{self as management, CanisterIdRecord};
+use ic_cdk_macros::*;
+use ic_cdk_timers::TimerId;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::admin::{ensure_admin, ensure_owner_or_admin};
+use crate::registry;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+// 履歴の最大保持件数 (古いものから捨てる)
+const MAX_HISTORY_LEN: usize = 1_000;
+// 並行して canister_status を呼び出すキャニスターの数
+const STATUS_BATCH_SIZE: usize = 20;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TopUpConfig {
+    pub interval_secs: u64,       // タイマーの実行間隔
+    pub threshold: u128,          // 残高がこれを下回ったら補充する
+    pub target: u128,             // 補充後の残高
+    pub budget: u128,             // budget_period_secs ごとに補充に使えるサイクルの合計
+    pub budget_period_secs: u64,
+    pub manager_reserve: u128,    // マネージャー自身に残しておくサイクル
+}
+
+impl Default for TopUpConfig {
+    fn default() -> Self {
+        TopUpConfig {
+            interval_secs: 6 * 60 * 60,
+            threshold: 300_000_000_000,
+            target: 1_000_000_000_000,
+            budget: 100_000_000_000_000,
+            budget_period_secs: 24 * 60 * 60,
+            manager_reserve: 2_000_000_000_000,
+        }
+    }
+}
+
+// キャニスターごとのポリシー (設定が無いキャニスターは TopUpConfig の値を使う)
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TopUpPolicy {
+    pub enabled: bool,
+    pub threshold: u128,
+    pub target: u128,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub enum TopUpTrigger {
+    Timer,
+    Manual,
+    Deposit, // DepositCycles で呼び出し元が送ったサイクル
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub enum TopUpOutcome {
+    Deposited,
+    BudgetExhausted, // 予算かマネージャーの残高が足りず補充しなかった
+    Failed(String),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TopUpEntry {
+    pub canister_id: Principal,
+    pub balance: Option<u128>, // 補充前の残高 (DepositCycles では取得しない)
+    pub amount: u128,
+    pub outcome: TopUpOutcome,
+    pub trigger: TopUpTrigger,
+    pub at: u64,
+}
+
+// アップグレード時に退避する状態
+#[derive(CandidType, Deserialize, Default)]
+pub(crate) struct CyclesState {
+    config: TopUpConfig,
+    policies: BTreeMap<Principal, TopUpPolicy>,
+    history: Vec<TopUpEntry>,
+    budget_period_start: u64,
+    budget_spent: u128, // 現在の期間に補充したサイクルの合計
+}
+
+thread_local! {
+    static CYCLES: RefCell<CyclesState> = RefCell::new(CyclesState::default());
+    static TOP_UP_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+    // 確認の実行中は次のタイマーでの確認を見送る
+    static CHECKING: Cell<bool> = const { Cell::new(false) };
+}
+
+pub(crate) fn save() -> CyclesState {
+    CYCLES.with(|cycles| cycles.take())
+}
+
+pub(crate) fn restore(state: CyclesState) {
+    CYCLES.with(|cycles| *cycles.borrow_mut() = state);
+}
+
+/// 補充タイマーを (再) 設定する
+/// init / post_upgrade と設定変更時に呼ばれる
+pub(crate) fn start_timer() {
+    let interval = CYCLES.with(|cycles| cycles.borrow().config.interval_secs).max(1);
+    let timer_id = ic_cdk_timers::set_timer_interval(Duration::from_secs(interval), || {
+        ic_cdk::spawn(async {
+            if let Ok(entries) = check_all(TopUpTrigger::Timer).await {
+                if !entries.is_empty() {
+                    ic_cdk::println!("Topped up {} canisters", entries.len());
+                }
+            }
+        });
+    });
+    TOP_UP_TIMER.with(|timer| {
+        if let Some(old_timer_id) = timer.borrow_mut().replace(timer_id) {
+            ic_cdk_timers::clear_timer(old_timer_id);
+        }
+    });
+}
+
+// 確認の実行中であることを示すガード
+struct CheckingGuard;
+
+impl CheckingGuard {
+    fn acquire() -> Result<Self, String> {
+        if CHECKING.with(|checking| checking.replace(true)) {
+            return Err("A cycles check is already running".to_string());
+        }
+        Ok(CheckingGuard)
+    }
+}
+
+impl Drop for CheckingGuard {
+    fn drop(&mut self) {
+        CHECKING.with(|checking| checking.set(false));
+    }
+}
+
+fn record(entry: TopUpEntry) {
+    CYCLES.with(|cycles| {
+        let history = &mut cycles.borrow_mut().history;
+        history.push(entry);
+        if history.len() > MAX_HISTORY_LEN {
+            let overflow = history.len() - MAX_HISTORY_LEN;
+            history.drain(..overflow);
+        }
+    });
+}
+
+/// 予算とマネージャーの残高の範囲で amount サイクルを確保する
+/// 確保できない場合は false を返す
+fn reserve_budget(amount: u128) -> bool {
+    let now = ic_cdk::api::time();
+    let balance = ic_cdk::api::canister_balance128();
+    CYCLES.with(|cycles| reserve_budget_at(&mut cycles.borrow_mut(), amount, now, balance))
+}
+
+/// 時刻 now・マネージャーの残高 balance のときに amount サイクルを確保する
+/// 期間が終わっていれば使った予算を 0 に戻してから確認する
+fn reserve_budget_at(state: &mut CyclesState, amount: u128, now: u64, balance: u128) -> bool {
+    let period = state.config.budget_period_secs.saturating_mul(NANOS_PER_SEC);
+    if now.saturating_sub(state.budget_period_start) >= period {
+        state.budget_period_start = now;
+        state.budget_spent = 0;
+    }
+    let within_budget = state.budget_spent.saturating_add(amount) <= state.config.budget;
+    let within_balance = balance.saturating_sub(amount) >= state.config.manager_reserve;
+    if !within_budget || !within_balance {
+        return false;
+    }
+    state.budget_spent += amount;
+    true
+}
+
+// 送金に失敗したサイクルは使っていないので予算に戻す
+fn refund_budget(amount: u128) {
+    CYCLES.with(|cycles| refund_budget_of(&mut cycles.borrow_mut(), amount));
+}
+
+fn refund_budget_of(state: &mut CyclesState, amount: u128) {
+    state.budget_spent = state.budget_spent.saturating_sub(amount);
+}
+
+/// 残高がしきい値を下回っていれば、目標残高までに必要なサイクルを返す
+fn top_up_amount(balance: u128, threshold: u128, target: u128) -> Option<u128> {
+    (balance < threshold).then(|| target.saturating_sub(balance))
+}
+
+/// キャニスターの残高を確認し、しきい値を下回っていれば補充する
+/// 補充が不要な場合は None を返す
+async fn check(canister_id: Principal, trigger: TopUpTrigger) -> Option<TopUpEntry> {
+    let (enabled, threshold, target) = CYCLES.with(|cycles| {
+        let state = cycles.borrow();
+        match state.policies.get(&canister_id) {
+            Some(policy) => (policy.enabled, policy.threshold, policy.target),
+            None => (true, state.config.threshold, state.config.target),
+        }
+    });
+    if !enabled {
+        return None;
+    }
+
+    let entry = |balance, amount, outcome| TopUpEntry {
+        canister_id,
+        balance,
+        amount,
+        outcome,
+        trigger: trigger.clone(),
+        at: ic_cdk::api::time(),
+    };
+    let balance = match management::canister_status(CanisterIdRecord { canister_id }).await {
+        Ok((status,)) => u128::try_from(&status.cycles.0).unwrap_or(u128::MAX),
+        Err(e) => {
+            let error = format!("Failed to get canister status: {:?}", e);
+            return Some(entry(None, 0, TopUpOutcome::Failed(error)));
+        }
+    };
+    let amount = top_up_amount(balance, threshold, target)?;
+    if !reserve_budget(amount) {
+        return Some(entry(Some(balance), amount, TopUpOutcome::BudgetExhausted));
+    }
+    let outcome = match management::deposit_cycles(CanisterIdRecord { canister_id }, amount).await {
+        Ok(()) => TopUpOutcome::Deposited,
+        Err(e) => {
+            refund_budget(amount);
+            TopUpOutcome::Failed(format!("Failed to deposit cycles: {:?}", e))
+        }
+    };
+    Some(entry(Some(balance), amount, outcome))
+}
+
+/// 台帳のすべてのキャニスターを確認して補充し、履歴に追加した記録を返す
+async fn check_all(trigger: TopUpTrigger) -> Result<Vec<TopUpEntry>, String> {
+    let _guard = CheckingGuard::acquire()?;
+    let canister_ids: Vec<Principal> = registry::list().into_iter().map(|record| record.canister_id).collect();
+
+    let mut entries = Vec::new();
+    for batch in canister_ids.chunks(STATUS_BATCH_SIZE) {
+        let results = join_all(batch.iter().map(|&canister_id| check(canister_id, trigger.clone()))).await;
+        for entry in results.into_iter().flatten() {
+            record(entry.clone());
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// DepositCycles で送ったサイクルを履歴に残す
+pub(crate) fn record_deposit(canister_id: Principal, amount: u128, result: &Result<(), String>) {
+    record(TopUpEntry {
+        canister_id,
+        balance: None,
+        amount,
+        outcome: match result {
+            Ok(()) => TopUpOutcome::Deposited,
+            Err(e) => TopUpOutcome::Failed(e.clone()),
+        },
+        trigger: TopUpTrigger::Deposit,
+        at: ic_cdk::api::time(),
+    });
+}
+
+/// 台帳のキャニスターの残高を即時確認して補充し、結果を返す (管理者のみ)
+#[update]
+async fn top_up_now() -> Result<Vec<TopUpEntry>, String> {
+    ensure_admin()?;
+    check_all(TopUpTrigger::Manual).await
+}
+
+#[query]
+fn get_top_up_config() -> TopUpConfig {
+    CYCLES.with(|cycles| cycles.borrow().config.clone())
+}
+
+/// 補充の設定を変更し、タイマーを再設定する (管理者のみ)
+#[update]
+fn set_top_up_config(config: TopUpConfig) -> Result<TopUpConfig, String> {
+    ensure_admin()?;
+    if config.interval_secs == 0 || config.budget_period_secs == 0 {
+        return Err("interval_secs and budget_period_secs must be greater than 0".to_string());
+    }
+    if config.target <= config.threshold {
+        return Err("target must be greater than threshold".to_string());
+    }
+    CYCLES.with(|cycles| cycles.borrow_mut().config = config.clone());
+    start_timer();
+    Ok(config)
+}
+
+/// キャニスターごとのポリシーを設定する。None を指定すると既定の設定に戻す (管理者のみ)
+#[update]
+fn set_top_up_policy(canister_id: Principal, policy: Option<TopUpPolicy>) -> Result<(), String> {
+    ensure_admin()?;
+    if registry::get(&canister_id).is_none() {
+        return Err("Canister is not registered".to_string());
+    }
+    CYCLES.with(|cycles| {
+        let policies = &mut cycles.borrow_mut().policies;
+        match policy {
+            Some(policy) if policy.enabled && policy.target <= policy.threshold => {
+                Err("target must be greater than threshold".to_string())
+            }
+            Some(policy) => {
+                policies.insert(canister_id, policy);
+                Ok(())
+            }
+            None => {
+                policies.remove(&canister_id);
+                Ok(())
+            }
+        }
+    })
+}
+
+/// キャニスターのポリシーを返す (所有者か管理者のみ)
+#[query]
+fn get_top_up_policy(canister_id: Principal) -> Result<Option<TopUpPolicy>, String> {
+    ensure_owner_or_admin(&canister_id)?;
+    Ok(CYCLES.with(|cycles| cycles.borrow().policies.get(&canister_id).cloned()))
+}
+
+/// キャニスターの補充の履歴を新しい順に返す (所有者か管理者のみ)
+#[query]
+fn get_top_up_history(canister_id: Principal) -> Result<Vec<TopUpEntry>, String> {
+    ensure_owner_or_admin(&canister_id)?;
+    Ok(CYCLES.with(|cycles| {
+        cycles
+            .borrow()
+            .history
+            .iter()
+            .rev()
+            .filter(|entry| entry.canister_id == canister_id)
+            .cloned()
+            .collect()
+    }))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 60 * 60 * NANOS_PER_SEC;
+
+    fn state(budget: u128, manager_reserve: u128) -> CyclesState {
+        CyclesState {
+            config: TopUpConfig {
+                budget,
+                budget_period_secs: 60 * 60,
+                manager_reserve,
+                ..TopUpConfig::default()
+            },
+            ..CyclesState::default()
+        }
+    }
+
+    #[test]
+    fn reservations_stop_at_the_budget() {
+        let mut state = state(100, 0);
+        assert!(reserve_budget_at(&mut state, 60, 0, u128::MAX));
+        assert!(reserve_budget_at(&mut state, 40, 0, u128::MAX));
+        assert!(!reserve_budget_at(&mut state, 1, 0, u128::MAX));
+        assert_eq!(state.budget_spent, 100);
+    }
+
+    #[test]
+    fn budget_resets_when_the_period_ends() {
+        let mut state = state(100, 0);
+        assert!(reserve_budget_at(&mut state, 100, HOUR, u128::MAX));
+        assert!(!reserve_budget_at(&mut state, 1, 2 * HOUR - 1, u128::MAX));
+        assert!(reserve_budget_at(&mut state, 100, 2 * HOUR, u128::MAX));
+        assert_eq!(state.budget_period_start, 2 * HOUR);
+        assert_eq!(state.budget_spent, 100);
+    }
+
+    // マネージャーの残高が予備を下回る補充はしない
+    #[test]
+    fn manager_reserve_is_kept() {
+        let mut state = state(1_000, 500);
+        assert!(!reserve_budget_at(&mut state, 101, 0, 600));
+        assert!(reserve_budget_at(&mut state, 100, 0, 600));
+        assert_eq!(state.budget_spent, 100);
+    }
+
+    #[test]
+    fn refunded_cycles_can_be_reserved_again() {
+        let mut state = state(100, 0);
+        assert!(reserve_budget_at(&mut state, 100, 0, u128::MAX));
+        refund_budget_of(&mut state, 100);
+        assert_eq!(state.budget_spent, 0);
+        assert!(reserve_budget_at(&mut state, 100, 0, u128::MAX));
+        // 使った分より多く戻しても負にならない
+        refund_budget_of(&mut state, 1_000);
+        assert_eq!(state.budget_spent, 0);
+    }
+
+    #[test]
+    fn top_up_brings_the_balance_to_the_target_only_below_the_threshold() {
+        assert_eq!(top_up_amount(299, 300, 1_000), Some(701));
+        assert_eq!(top_up_amount(300, 300, 1_000), None);
+        assert_eq!(top_up_amount(0, 300, 1_000), Some(1_000));
+    }
+}
@@ -1,159 +1,373 @@
-use candid::{Nat, CandidType, Encode, Decode, Principal, encode_args};
-use ic_cdk::api::management_canister::{
-    main::{
-        create_canister, install_code, deposit_cycles, start_canister, stop_canister, delete_canister, canister_status,
-        CanisterInstallMode, CreateCanisterArgument, InstallCodeArgument, CanisterSettings, CanisterIdRecord, LogVisibility
-    },
+use candid::{Nat, CandidType, Encode, Principal};
+use std::collections::BTreeMap;
+use std::fmt;
+use ic_cdk::api::management_canister::main::{
+    self as management,
+    CanisterInstallMode, CreateCanisterArgument, CanisterIdRecord, CanisterSettings,
+    CanisterStatusResponse, CanisterStatusType, DefiniteCanisterSettings, QueryStats,
 };
+use futures::future::join_all;
 
 use ic_cdk_macros::*;
 use serde::Deserialize;
-use ic_cdk::{
-    api::{call, time},
-    id,
-};
 
+mod admin;
+mod catalogue;
+mod client;
+mod cycles;
+mod memory;
+mod payments;
+mod registry;
+mod releases;
+mod settings;
+mod upgrades;
+
+use admin::CreationPolicy;
+use catalogue::{CanisterChannel, CanisterVideos, ChannelUsage};
+use client::GreetClient;
+use cycles::{TopUpConfig, TopUpEntry, TopUpPolicy};
+use payments::{Account, PaymentConfig};
+use registry::{CanisterKind, CanisterRecord};
+use releases::WasmRelease;
+use settings::{SettingsOverrides, SettingsTemplate};
+use upgrades::UpgradeRollout;
+
+// CanisterStatus で一度に問い合わせられるキャニスターの上限
+const MAX_STATUS_BATCH_LEN: usize = 100;
+// 並行して canister_status を呼び出すキャニスターの数
+const STATUS_CONCURRENCY: usize = 20;
+
+// 管理キャニスターの canister_status の結果
+// controllers は settings.controllers と同じ (旧バージョンとの互換のため残している)
 #[derive(CandidType, Deserialize)]
 pub struct CanisterStatusResult {
     pub controllers: Vec<Principal>,
+    pub status: CanisterStatusType,
+    pub settings: DefiniteCanisterSettings,
+    pub module_hash: Option<Vec<u8>>,
+    pub memory_size: Nat,
+    pub cycles: Nat,
+    pub reserved_cycles: Nat,
+    pub idle_cycles_burned_per_day: Nat,
+    pub query_stats: QueryStats,
 }
 
-#[update]
-async fn CreateAndInstallCanister() -> Result<Principal, String> {
-    // 🔹 Create Canister with optional settings
-    let canister_setting = CanisterSettings {
-        controllers: Some(vec![id()]),
-        compute_allocation: Some(Nat::from(0_u64)),
-        memory_allocation: Some(Nat::from(0_u64)),
-        freezing_threshold: Some(Nat::from(0_u64)),
-        reserved_cycles_limit: Some(Nat::from(0_u64)),
-        log_visibility: Some(LogVisibility::Public),
-        wasm_memory_limit: Some(Nat::from(4_u64 * 1024_u64 * 1024_u64)), // 4MB memory limit
-    };
-    let create_args = CreateCanisterArgument {
-        settings: Some(canister_setting),
-    };
+impl From<CanisterStatusResponse> for CanisterStatusResult {
+    fn from(status: CanisterStatusResponse) -> Self {
+        CanisterStatusResult {
+            controllers: status.settings.controllers.clone(),
+            status: status.status,
+            settings: status.settings,
+            module_hash: status.module_hash,
+            memory_size: status.memory_size,
+            cycles: status.cycles,
+            reserved_cycles: status.reserved_cycles,
+            idle_cycles_burned_per_day: status.idle_cycles_burned_per_day,
+            query_stats: status.query_stats,
+        }
+    }
+}
 
-    let (create_result,) = match create_canister(create_args, 900_000_000_000).await {
-        Ok(res) => res,
-        Err(e) => return Err(format!("Failed to create canister: {:?}", e)),
-    };
+#[derive(CandidType, Deserialize)]
+pub struct CanisterStatusEntry {
+    pub canister_id: Principal,
+    pub result: Result<CanisterStatusResult, String>,
+}
 
-    let new_canister_id = create_result.canister_id;
+// アップグレードの間だけ stable memory に退避するヒープ上の状態 (wasm のチャンクは releases.rs が stable memory に直接置く)
+#[derive(CandidType, Deserialize, Default)]
+struct StableState {
+    registry: BTreeMap<Principal, CanisterRecord>,
+    releases: releases::ReleasesState,
+    upgrades: upgrades::UpgradesState,
+    cycles: cycles::CyclesState,
+    admin: admin::AdminState,
+    settings: BTreeMap<CanisterKind, SettingsTemplate>,
+    payments: payments::PaymentsState,
+}
 
-    // // 🔹 WASM binary (using an empty WASM for now)
-    // // In a real implementation, you would include the actual WASM binary
-    // let wasm_module: Vec<u8> = vec![
-    //     0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00, 
-    //     0x01, 0x04, 0x01, 0x60, 0x00, 0x00, 0x03, 0x02, 
-    //     0x01, 0x00, 0x07, 0x08, 0x01, 0x04, 0x63, 0x61, 
-    //     0x6E, 0x64, 0x00, 0x00, 0x0A, 0x04, 0x01, 0x02, 
-    //     0x00, 0x0B
-    // ];
+// 退避する状態のバージョン
+// candid は opt 以外のフィールドが欠けているとデコードに失敗する (#[serde(default)] は効かない) ため、
+// 状態の形を変えるときは新しいバージョンを追加し、load_state で古いバージョンから移行する
+#[derive(CandidType, Deserialize)]
+enum VersionedState {
+    V1(StableState),
+}
 
-    // 🔹 WASM バイナリを用意（ここでは空のWASMを使うが、実際には include_bytes! などでファイル読み込み）
-    let wasm_module: Vec<u8> = include_bytes!("../../../target/wasm32-unknown-unknown/release/greet_backend.wasm").to_vec();
+fn load_state() -> Result<StableState, String> {
+    let bytes = memory::load_upgrade_state()?;
+    match candid::decode_one(&bytes).map_err(|e| format!("Failed to decode the upgrade state: {}", e))? {
+        VersionedState::V1(state) => Ok(state),
+    }
+}
 
-    // 🔹 Initialize arguments (empty for now)
-    let init_args = Encode!().unwrap();
+#[init]
+fn init() {
+    cycles::start_timer();
+}
 
-    // 🔹 Install Code
-    let install_args = InstallCodeArgument {
-        mode: CanisterInstallMode::Install,
-        canister_id: new_canister_id,
-        wasm_module,
-        arg: init_args,
+#[pre_upgrade]
+fn pre_upgrade() {
+    let state = StableState {
+        registry: registry::save(),
+        releases: releases::save(),
+        upgrades: upgrades::save(),
+        cycles: cycles::save(),
+        admin: admin::save(),
+        settings: settings::save(),
+        payments: payments::save(),
     };
+    let bytes = candid::encode_one(VersionedState::V1(state)).expect("Failed to encode the upgrade state");
+    memory::save_upgrade_state(&bytes);
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    // 復元できなければトラップしてアップグレードを取り消す
+    // (空の状態で続けると、子キャニスターのコントローラーのまま所有者の記録を失う)
+    let state = match memory::layout() {
+        // 状態を保存していなかった最初のバージョンからのアップグレードでは空の状態から始める
+        Ok(memory::Layout::Empty) => Ok(StableState::default()),
+        Ok(memory::Layout::Managed) => load_state(),
+        Err(e) => Err(e),
+    }
+    .unwrap_or_else(|e| ic_cdk::trap(&e));
+    registry::restore(state.registry);
+    releases::restore(state.releases);
+    upgrades::restore(state.upgrades);
+    cycles::restore(state.cycles);
+    admin::restore(state.admin);
+    settings::restore(state.settings);
+    payments::restore(state.payments);
+    cycles::start_timer();
+}
+
+// キャニスターの作成に失敗した理由
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum CreateCanisterError {
+    // 作成を始める前に失敗した (権限・リリース・設定・支払い)。支払いは発生していない
+    Rejected { message: String },
+    // キャニスターは残っていない (作成できなかったか、インストールに失敗して削除した)。支払いは返金した
+    NotCreated { message: String },
+    // インストールに失敗し、作成したキャニスターも削除できなかった。
+    // キャニスターに支払いのサイクルが残っているため返金せず、空のキャニスターとして呼び出し元に登録した。
+    // 所有者は install_latest_release でインストールをやり直せる
+    NotInstalled { canister_id: Principal, message: String },
+}
 
-    if let Err(e) = install_code(install_args).await {
-        return Err(format!("Failed to install code: {:?}", e));
+impl From<String> for CreateCanisterError {
+    fn from(message: String) -> Self {
+        CreateCanisterError::Rejected { message }
     }
+}
 
-    Ok(new_canister_id)
+impl From<&str> for CreateCanisterError {
+    fn from(message: &str) -> Self {
+        CreateCanisterError::Rejected { message: message.to_string() }
+    }
 }
 
-#[update]
-async fn CreateStreamingCanister(title: String, description: String) -> Result<Principal, String> {
-    // 🔹 Create Canister with optional settings
-    let canister_setting = CanisterSettings {
-        controllers: Some(vec![id()]),
-        compute_allocation: Some(Nat::from(0_u64)),
-        memory_allocation: Some(Nat::from(0_u64)),
-        freezing_threshold: Some(Nat::from(0_u64)),
-        reserved_cycles_limit: Some(Nat::from(0_u64)),
-        log_visibility: Some(LogVisibility::Public),
-        wasm_memory_limit: Some(Nat::from(4_u64 * 1024_u64 * 1024_u64)), // 4MB memory limit
-    };
+impl fmt::Display for CreateCanisterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CreateCanisterError::Rejected { message } | CreateCanisterError::NotCreated { message } => {
+                f.write_str(message)
+            }
+            CreateCanisterError::NotInstalled { canister_id, message } => write!(
+                f,
+                "{} (the canister {} was registered to the caller without code; call install_latest_release to install it)",
+                message, canister_id
+            ),
+        }
+    }
+}
+
+/// キャニスターを作成し、リリースをインストールする
+/// インストールに失敗した場合は作成したキャニスターを削除し、支払いを返金する。
+/// 削除もできなかった場合は空のキャニスターを初期化引数と一緒に呼び出し元に登録する
+async fn create_and_install(
+    kind: CanisterKind,
+    owner: Principal,
+    title: String,
+    canister_setting: CanisterSettings,
+    creation_cycles: u128,
+    release: &WasmRelease,
+    init_args: Vec<u8>,
+) -> Result<Principal, CreateCanisterError> {
+    let payment = payments::charge(owner, creation_cycles).await?;
     let create_args = CreateCanisterArgument {
         settings: Some(canister_setting),
     };
 
-    let (create_result,) = match create_canister(create_args, 900_000_000_000).await {
+    let (create_result,) = match management::create_canister(create_args, creation_cycles).await {
         Ok(res) => res,
-        Err(e) => return Err(format!("Failed to create canister: {:?}", e)),
+        Err(e) => {
+            payments::refund(owner, payment).await;
+            return Err(CreateCanisterError::NotCreated { message: format!("Failed to create canister: {:?}", e) });
+        }
     };
 
     let new_canister_id = create_result.canister_id;
 
-    // // In a real implementation, you would include the actual streamingservice_backend WASM binary
-    // // For now, we're using a placeholder
-    // let wasm_module: Vec<u8> = vec![
-    //     0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00, 
-    //     0x01, 0x04, 0x01, 0x60, 0x00, 0x00, 0x03, 0x02, 
-    //     0x01, 0x00, 0x07, 0x08, 0x01, 0x04, 0x63, 0x61, 
-    //     0x6E, 0x64, 0x00, 0x00, 0x0A, 0x04, 0x01, 0x02, 
-    //     0x00, 0x0B
-    // ];
-
-    // 🔹 WASM バイナリを用意（ここでは空のWASMを使うが、実際には include_bytes! などでファイル読み込み）
-    let wasm_module: Vec<u8> = include_bytes!("../../../target/wasm32-unknown-unknown/release/greet_backend.wasm").to_vec();
-
-    // Initialize with title and description
-    let init_args = match Encode!(&title, &description) {
-        Ok(args) => args,
-        Err(e) => return Err(format!("Failed to encode arguments: {:?}", e)),
-    };
-
     // 🔹 Install Code
-    let install_args = InstallCodeArgument {
-        mode: CanisterInstallMode::Install,
-        canister_id: new_canister_id,
-        wasm_module,
-        arg: init_args,
-    };
-
-    if let Err(e) = install_code(install_args).await {
-        return Err(format!("Failed to install code: {:?}", e));
+    if let Err(message) =
+        releases::install(new_canister_id, release, CanisterInstallMode::Install, init_args.clone()).await
+    {
+        // コードがインストールされていないキャニスターは停止せずに削除できる
+        return match management::delete_canister(CanisterIdRecord { canister_id: new_canister_id }).await {
+            Ok(_) => {
+                payments::refund(owner, payment).await;
+                Err(CreateCanisterError::NotCreated { message })
+            }
+            Err(delete_error) => {
+                ic_cdk::println!("Failed to delete canister {} after a failed install: {:?}", new_canister_id, delete_error);
+                registry::register_without_code(new_canister_id, owner, kind, title, init_args);
+                Err(CreateCanisterError::NotInstalled { canister_id: new_canister_id, message })
+            }
+        };
     }
 
+    registry::register(new_canister_id, owner, kind, release.wasm_hash.clone(), title);
     Ok(new_canister_id)
 }
 
+/// 呼び出し元を所有者とする greet_backend のキャニスターを作成し、キャニスター ID を返す
+/// 支払いが必要な呼び出し元はサイクルを添付するか、事前にレジャーで送金しておく
+#[update]
+async fn create_and_install_canister_v2(settings: Option<SettingsOverrides>) -> Result<Principal, CreateCanisterError> {
+    let owner = admin::ensure_can_create()?;
+    let release = releases::latest(CanisterKind::Greet).ok_or("No greet_backend release has been uploaded")?;
+
+    // 🔹 Create Canister with the settings template of the kind
+    let (canister_setting, creation_cycles) =
+        settings::for_creation(CanisterKind::Greet, owner, settings.unwrap_or_default())?;
+
+    // 🔹 Initialize arguments (empty for now)
+    let init_args = Encode!().unwrap();
+
+    create_and_install(CanisterKind::Greet, owner, String::new(), canister_setting, creation_cycles, &release, init_args)
+        .await
+}
+
+/// 非推奨: create_and_install_canister_v2 を使うこと (次のリリースで削除する)
+#[update(name = "CreateAndInstallCanister")]
+async fn create_and_install_canister(settings: Option<SettingsOverrides>) -> Result<Principal, String> {
+    create_and_install_canister_v2(settings).await.map_err(|e| e.to_string())
+}
+
+// streamingservice_backend のクォータ (None の項目は無制限)
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Quotas {
+    pub max_videos: Option<u64>,
+    pub max_stored_bytes: Option<u64>,
+}
+
+// streamingservice_backend の初期化引数
+#[derive(CandidType, Deserialize)]
+pub struct StreamingInitArgs {
+    pub owner: Principal,
+    pub title: String,
+    pub description: String,
+    pub quotas: Quotas,
+}
+
+// クォータを指定せずに作成した場合の既定値
+const DEFAULT_QUOTAS: Quotas = Quotas {
+    max_videos: Some(1_000),
+    max_stored_bytes: Some(2 * 1024 * 1024 * 1024), // 2GiB
+};
+
+/// 呼び出し元を所有者とする streamingservice_backend のキャニスターを作成し、キャニスター ID を返す
+/// settings を指定するとテンプレートの設定の一部を上書きする
+/// 支払いが必要な呼び出し元はサイクルを添付するか、事前にレジャーで送金しておく
+#[update]
+async fn create_streaming_canister_v2(
+    title: String,
+    description: String,
+    quotas: Option<Quotas>,
+    settings: Option<SettingsOverrides>,
+) -> Result<Principal, CreateCanisterError> {
+    let owner = admin::ensure_can_create()?;
+    let release =
+        releases::latest(CanisterKind::Streaming).ok_or("No streamingservice_backend release has been uploaded")?;
+
+    // 🔹 Create Canister with the settings template of the kind
+    let (canister_setting, creation_cycles) =
+        settings::for_creation(CanisterKind::Streaming, owner, settings.unwrap_or_default())?;
+
+    // 初期化引数で所有者・タイトル・説明文・クォータを渡す
+    let init_args = StreamingInitArgs {
+        owner,
+        title: title.clone(),
+        description,
+        quotas: quotas.unwrap_or(DEFAULT_QUOTAS),
+    };
+    let init_args = match Encode!(&Some(init_args)) {
+        Ok(args) => args,
+        Err(e) => return Err(format!("Failed to encode arguments: {:?}", e).into()),
+    };
+
+    create_and_install(CanisterKind::Streaming, owner, title, canister_setting, creation_cycles, &release, init_args)
+        .await
+}
+
+/// 非推奨: create_streaming_canister_v2 を使うこと (次のリリースで削除する)
+#[update(name = "CreateStreamingCanister")]
+async fn create_streaming_canister(
+    title: String,
+    description: String,
+    quotas: Option<Quotas>,
+    settings: Option<SettingsOverrides>,
+) -> Result<Principal, String> {
+    create_streaming_canister_v2(title, description, quotas, settings)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// 作成時にコードをインストールできなかったキャニスターに、種類の最新のリリースをインストールする (所有者か管理者のみ)
+/// 初期化引数には作成時に渡したものを使う
 #[update]
-async fn DepositCycles(canister_principal: String) -> Result<(), String> {
-    // Add 10^12 cycles
-    let available_cycles = ic_cdk::api::call::msg_cycles_available();
-    ic_cdk::api::call::msg_cycles_accept(1_000_000_000_000_u64.min(available_cycles));
-    
+async fn install_latest_release(canister_id: Principal) -> Result<(), String> {
+    admin::ensure_owner_or_admin(&canister_id)?;
+    let record = registry::get(&canister_id).ok_or("Canister is not registered")?;
+    let install_arg = record.install_arg.ok_or("Canister already has code installed")?;
+    let release = releases::latest(record.kind).ok_or("No release has been uploaded for the canister kind")?;
+
+    releases::install(canister_id, &release, CanisterInstallMode::Install, install_arg).await?;
+    registry::set_wasm_hash(&canister_id, release.wasm_hash);
+    Ok(())
+}
+
+/// 呼び出し元が添付したサイクルをすべてキャニスターに送る
+#[update(name = "DepositCycles")]
+async fn deposit_cycles(canister_principal: String) -> Result<(), String> {
     let canister_id = match Principal::from_text(canister_principal) {
         Ok(principal) => principal,
         Err(e) => return Err(format!("Invalid principal: {:?}", e)),
     };
+    admin::ensure_owner_or_admin(&canister_id)?;
 
-    match deposit_cycles(CanisterIdRecord { canister_id: canister_id }, 1_000_000_000_000_u128).await {
-        Ok(_) => Ok(()),
-        Err(e) => Err(format!("Failed to deposit cycles: {:?}", e)),
+    let amount = ic_cdk::api::call::msg_cycles_accept128(ic_cdk::api::call::msg_cycles_available128());
+    if amount == 0 {
+        return Err("Attach the cycles to deposit to the call".to_string());
     }
+
+    let result = management::deposit_cycles(CanisterIdRecord { canister_id }, amount)
+        .await
+        .map_err(|e| format!("Failed to deposit cycles: {:?}", e));
+    cycles::record_deposit(canister_id, amount, &result);
+    result
 }
 
-#[update]
-async fn StartCanister(canister_principal: String) -> Result<(), String> {
+#[update(name = "StartCanister")]
+async fn start_canister(canister_principal: String) -> Result<(), String> {
     let canister_id = match Principal::from_text(canister_principal) {
         Ok(principal) => principal,
         Err(e) => return Err(format!("Invalid principal: {:?}", e)),
     };
+    admin::ensure_owner_or_admin(&canister_id)?;
 
-    match start_canister(
+    match management::start_canister(
         CanisterIdRecord { canister_id }
     ).await {
         Ok(_) => Ok(()),
@@ -161,14 +375,15 @@ async fn StartCanister(canister_principal: String) -> Result<(), String> {
     }
 }
 
-#[update]
-async fn StopCanister(canister_principal: String) -> Result<(), String> {
+#[update(name = "StopCanister")]
+async fn stop_canister(canister_principal: String) -> Result<(), String> {
     let canister_id = match Principal::from_text(canister_principal) {
         Ok(principal) => principal,
         Err(e) => return Err(format!("Invalid principal: {:?}", e)),
     };
+    admin::ensure_owner_or_admin(&canister_id)?;
 
-    match stop_canister(
+    match management::stop_canister(
         CanisterIdRecord { canister_id }
     ).await {
         Ok(_) => Ok(()),
@@ -176,53 +391,123 @@ async fn StopCanister(canister_principal: String) -> Result<(), String> {
     }
 }
 
-#[update]
-async fn DeleteCanister(canister_principal: String) -> Result<(), String> {
+#[update(name = "DeleteCanister")]
+async fn delete_canister(canister_principal: String) -> Result<(), String> {
     let canister_id = match Principal::from_text(canister_principal) {
         Ok(principal) => principal,
         Err(e) => return Err(format!("Invalid principal: {:?}", e)),
     };
+    admin::ensure_owner_or_admin(&canister_id)?;
 
-    match delete_canister(
+    match management::delete_canister(
         CanisterIdRecord { canister_id }
     ).await {
-        Ok(_) => Ok(()),
+        Ok(_) => {
+            registry::unregister(&canister_id);
+            Ok(())
+        }
         Err(e) => Err(format!("Failed to delete canister: {:?}", e)),
     }
 }
 
-#[update]
-async fn CanisterStatus(canister_principal: String) -> Result<CanisterStatusResult, String> {
+async fn fetch_canister_status(canister_id: Principal) -> Result<CanisterStatusResult, String> {
+    management::canister_status(CanisterIdRecord { canister_id })
+        .await
+        .map(|(status,)| status.into())
+        .map_err(|e| format!("Failed to get canister status: {:?}", e))
+}
+
+#[update(name = "CanisterStatus")]
+async fn canister_status(canister_principal: String) -> Result<CanisterStatusResult, String> {
     let canister_id = match Principal::from_text(canister_principal) {
         Ok(principal) => principal,
         Err(e) => return Err(format!("Invalid principal: {:?}", e)),
     };
+    admin::ensure_owner_or_admin(&canister_id)?;
 
-    match canister_status(
-        CanisterIdRecord { canister_id }
-    ).await {
-        Ok((status,)) => Ok(CanisterStatusResult {
-            controllers: status.settings.controllers,
-        }),
-        Err(e) => Err(format!("Failed to get canister status: {:?}", e)),
-    }
+    fetch_canister_status(canister_id).await
 }
 
+/// 複数のキャニスターの状態をまとめて返す
+/// 取得に失敗したキャニスターと、所有者でも管理者でもないキャニスターはエントリごとにエラーを返す
+#[update(name = "CanisterStatusBatch")]
+async fn canister_status_batch(canister_ids: Vec<Principal>) -> Result<Vec<CanisterStatusEntry>, String> {
+    if canister_ids.len() > MAX_STATUS_BATCH_LEN {
+        return Err(format!("At most {} canisters can be queried at once", MAX_STATUS_BATCH_LEN));
+    }
 
-#[update]
-async fn CallGreet(canister_principal: String, greeting: String) -> Result<(String), String> {
+    let mut entries = Vec::with_capacity(canister_ids.len());
+    for batch in canister_ids.chunks(STATUS_CONCURRENCY) {
+        let results = join_all(batch.iter().map(|&canister_id| async move {
+            admin::ensure_owner_or_admin(&canister_id)?;
+            fetch_canister_status(canister_id).await
+        }))
+        .await;
+        entries.extend(
+            batch
+                .iter()
+                .zip(results)
+                .map(|(&canister_id, result)| CanisterStatusEntry { canister_id, result }),
+        );
+    }
+    Ok(entries)
+}
+
+/// greet_backend のキャニスターの greet を呼び出す
+#[update(name = "CallGreet")]
+async fn call_greet(canister_principal: String, greeting: String) -> Result<String, String> {
     let canister_id = match Principal::from_text(canister_principal) {
         Ok(principal) => principal,
         Err(e) => return Err(format!("Invalid principal: {:?}", e)),
     };
-    // // greet関数の引数をエンコード
-    // let args = match encode_args((greeting,)) {
-    //     Ok(args) => args,
-    //     Err(e) => return Err(format!("Failed to encode arguments: {:?}", e)),
-    // };
-    // キャニスター間呼び出し
-    match ic_cdk::call(canister_id, "Greet", (greeting,)).await {
-        Ok((response,)) => Ok((response)),
-        Err((code, msg)) => Err(format!("Failed to call Greet: code {:?}, message: {}", code, msg)),
-    }
-}
\ No newline at end of file
+    admin::ensure_owner_or_admin(&canister_id)?;
+    GreetClient::new(canister_id).greet(greeting).await
+}
+
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use candid_parser::utils::{service_compatible, CandidSource};
+    use std::path::Path;
+
+    // コードから生成したインターフェースがコミット済みの .did と後方互換であることを確認する
+    #[test]
+    fn candid_interface_is_backward_compatible() {
+        let generated = super::__export_service();
+        let committed = Path::new(env!("CARGO_MANIFEST_DIR")).join("streamingservice_manager.did");
+        service_compatible(CandidSource::Text(&generated), CandidSource::File(&committed))
+            .unwrap_or_else(|e| panic!("Generated interface is not compatible with the committed .did: {e:?}"));
+    }
+
+    // pre_upgrade で stable memory に書き込んだ状態を post_upgrade で読み戻せること
+    #[test]
+    fn upgrade_state_round_trips_through_stable_memory() {
+        let mut state = super::StableState::default();
+        let canister_id = candid::Principal::self_authenticating(b"canister");
+        state.registry.insert(
+            canister_id,
+            super::CanisterRecord {
+                canister_id,
+                owner: candid::Principal::self_authenticating(b"owner"),
+                kind: super::CanisterKind::Streaming,
+                wasm_hash: vec![1; 32],
+                created_at: 0,
+                title: "Channel".to_string(),
+                install_arg: None,
+            },
+        );
+        let bytes = candid::encode_one(super::VersionedState::V1(state)).unwrap();
+        super::memory::save_upgrade_state(&bytes);
+
+        let restored = super::load_state().unwrap();
+        assert_eq!(restored.registry[&canister_id].title, "Channel");
+    }
+
+    // 読み戻せない状態は空の状態として扱わずエラーにする (post_upgrade はトラップする)
+    #[test]
+    fn corrupted_upgrade_state_is_an_error() {
+        super::memory::save_upgrade_state(b"not candid");
+        assert!(super::load_state().is_err());
+    }
+}
@@ -0,0 +1,297 @@
+// キャニスター作成の支払い
+//
+// 作成の方針が Open のとき、管理者と許可リストのプリンシパル以外も、作成に使うサイクルを支払えばキャニスターを作成できる。
+// 支払い方法は 2 つ:
+// - サイクル: 呼び出しにサイクルを添付する。作成に必要な分だけ受け取り、残りは呼び出し元に戻る。
+// - ICRC-1 レジャー (ICP など): 呼び出し元ごとのマネージャーのサブアカウントに事前に送金しておく。
+//   作成時に価格分をマネージャーのアカウントへ移し、残りは呼び出し元のアカウントに返金する。
+// 支払い後に作成に失敗した場合、サイクルは次回の作成に使えるクレジットとして残し、
+// レジャーのトークンは呼び出し元に返金する。
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::admin::{self, ensure_admin};
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct LedgerPayment {
+    pub ledger_canister_id: Principal, // ICRC-1 に対応したレジャー
+    pub price: Nat,                    // キャニスター 1 つの価格 (レジャーの最小単位, 手数料を除く)
+}
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct PaymentConfig {
+    pub accept_cycles: bool,
+    pub ledger: Option<LedgerPayment>,
+}
+
+// ICRC-1 のアカウント
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    TemporarilyUnavailable,
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+}
+
+// 作成のために受け取った支払い (失敗時の返金に使う)
+pub(crate) enum Payment {
+    Exempt,
+    Cycles(u128),
+    Ledger { ledger_canister_id: Principal, amount: Nat, fee: Nat },
+}
+
+// アップグレード時に退避する状態
+#[derive(CandidType, Deserialize, Default)]
+pub(crate) struct PaymentsState {
+    config: PaymentConfig,
+    credits: BTreeMap<Principal, u128>, // 作成に失敗した支払いのサイクル
+}
+
+thread_local! {
+    static PAYMENTS: RefCell<PaymentsState> = RefCell::new(PaymentsState::default());
+    // レジャーで支払い中の呼び出し元 (同じサブアカウントからの二重の引き落としを防ぐ)
+    static PAYING: RefCell<BTreeSet<Principal>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+pub(crate) fn save() -> PaymentsState {
+    PAYMENTS.with(|payments| payments.take())
+}
+
+pub(crate) fn restore(state: PaymentsState) {
+    PAYMENTS.with(|payments| *payments.borrow_mut() = state);
+}
+
+fn config() -> PaymentConfig {
+    PAYMENTS.with(|payments| payments.borrow().config.clone())
+}
+
+/// 支払いによる作成を受け付けていれば true
+pub(crate) fn enabled() -> bool {
+    let config = config();
+    config.accept_cycles || config.ledger.is_some()
+}
+
+/// 呼び出し元ごとの入金用サブアカウント (プリンシパルの長さとバイト列を 32 バイトに詰める)
+fn subaccount(principal: &Principal) -> Vec<u8> {
+    let bytes = principal.as_slice();
+    let mut subaccount = vec![0u8; 32];
+    subaccount[0] = bytes.len() as u8;
+    subaccount[1..=bytes.len()].copy_from_slice(bytes);
+    subaccount
+}
+
+struct PayingGuard(Principal);
+
+impl PayingGuard {
+    fn acquire(principal: Principal) -> Result<Self, String> {
+        if !PAYING.with(|paying| paying.borrow_mut().insert(principal)) {
+            return Err("A payment from this caller is already in progress".to_string());
+        }
+        Ok(PayingGuard(principal))
+    }
+}
+
+impl Drop for PayingGuard {
+    fn drop(&mut self) {
+        PAYING.with(|paying| paying.borrow_mut().remove(&self.0));
+    }
+}
+
+async fn ledger_fee(ledger_canister_id: Principal) -> Result<Nat, String> {
+    ic_cdk::call::<_, (Nat,)>(ledger_canister_id, "icrc1_fee", ())
+        .await
+        .map(|(fee,)| fee)
+        .map_err(|(code, msg)| format!("Failed to get the ledger fee: code {:?}, message: {}", code, msg))
+}
+
+async fn ledger_balance(ledger_canister_id: Principal, account: Account) -> Result<Nat, String> {
+    ic_cdk::call::<_, (Nat,)>(ledger_canister_id, "icrc1_balance_of", (account,))
+        .await
+        .map(|(balance,)| balance)
+        .map_err(|(code, msg)| format!("Failed to get the ledger balance: code {:?}, message: {}", code, msg))
+}
+
+async fn ledger_transfer(
+    ledger_canister_id: Principal,
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Nat,
+) -> Result<Nat, String> {
+    let arg = TransferArg {
+        from_subaccount,
+        to,
+        amount,
+        fee: Some(fee),
+        memo: None,
+        created_at_time: None,
+    };
+    match ic_cdk::call::<_, (Result<Nat, TransferError>,)>(ledger_canister_id, "icrc1_transfer", (arg,)).await {
+        Ok((Ok(block_index),)) => Ok(block_index),
+        Ok((Err(e),)) => Err(format!("Ledger transfer failed: {:?}", e)),
+        Err((code, msg)) => Err(format!("Failed to call the ledger: code {:?}, message: {}", code, msg)),
+    }
+}
+
+/// 添付されたサイクルとクレジットから cycles を支払う
+/// 必要な分だけを受け取り、残りのサイクルは呼び出し元に戻る
+fn charge_cycles(owner: Principal, cycles: u128) -> Result<Payment, String> {
+    let credit = PAYMENTS.with(|payments| payments.borrow().credits.get(&owner).copied().unwrap_or(0));
+    let from_credit = credit.min(cycles);
+    let needed = cycles - from_credit;
+    let available = ic_cdk::api::call::msg_cycles_available128();
+    if available < needed {
+        return Err(format!("Attach at least {} cycles to create a canister", needed));
+    }
+    ic_cdk::api::call::msg_cycles_accept128(needed);
+    PAYMENTS.with(|payments| {
+        let credits = &mut payments.borrow_mut().credits;
+        match credit - from_credit {
+            0 => credits.remove(&owner),
+            rest => credits.insert(owner, rest),
+        }
+    });
+    Ok(Payment::Cycles(cycles))
+}
+
+/// 呼び出し元のサブアカウントから価格分をマネージャーのアカウントに移し、残りを返金する
+async fn charge_ledger(owner: Principal, ledger: LedgerPayment) -> Result<Payment, String> {
+    let _guard = PayingGuard::acquire(owner)?;
+    let ledger_canister_id = ledger.ledger_canister_id;
+    let from = Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(subaccount(&owner)),
+    };
+    let fee = ledger_fee(ledger_canister_id).await?;
+    let balance = ledger_balance(ledger_canister_id, from.clone()).await?;
+    let required = ledger.price.clone() + fee.clone();
+    if balance < required {
+        return Err(format!(
+            "Send at least {} (price {} plus fee {}) to the payment account before creating a canister",
+            required, ledger.price, fee
+        ));
+    }
+
+    let manager = Account {
+        owner: ic_cdk::id(),
+        subaccount: None,
+    };
+    ledger_transfer(ledger_canister_id, from.subaccount.clone(), manager, ledger.price.clone(), fee.clone()).await?;
+
+    // 価格を超えて送金された分は呼び出し元に返す (手数料に満たない端数は残る)
+    let excess = balance - required;
+    if excess > fee {
+        let to = Account {
+            owner,
+            subaccount: None,
+        };
+        if let Err(e) = ledger_transfer(ledger_canister_id, from.subaccount, to, excess - fee.clone(), fee.clone()).await {
+            ic_cdk::println!("Failed to refund the excess payment of {}: {}", owner, e);
+        }
+    }
+    Ok(Payment::Ledger {
+        ledger_canister_id,
+        amount: ledger.price,
+        fee,
+    })
+}
+
+/// キャニスターの作成の支払いを受け取る
+/// 管理者と許可リストのプリンシパルは支払いが不要
+/// サイクルが添付されていればサイクルで、そうでなければレジャーで支払う
+pub(crate) async fn charge(owner: Principal, cycles: u128) -> Result<Payment, String> {
+    if admin::is_exempt_from_payment(&owner) {
+        return Ok(Payment::Exempt);
+    }
+    let config = config();
+    if config.accept_cycles && ic_cdk::api::call::msg_cycles_available128() > 0 {
+        return charge_cycles(owner, cycles);
+    }
+    match config.ledger {
+        Some(ledger) => charge_ledger(owner, ledger).await,
+        None if config.accept_cycles => charge_cycles(owner, cycles),
+        None => Err("Caller is not allowed to create canisters".to_string()),
+    }
+}
+
+/// 作成に失敗したときに支払いを戻す
+pub(crate) async fn refund(owner: Principal, payment: Payment) {
+    match payment {
+        Payment::Exempt => {}
+        Payment::Cycles(cycles) => PAYMENTS.with(|payments| {
+            *payments.borrow_mut().credits.entry(owner).or_default() += cycles;
+        }),
+        Payment::Ledger {
+            ledger_canister_id,
+            amount,
+            fee,
+        } => {
+            if amount <= fee {
+                return;
+            }
+            let to = Account {
+                owner,
+                subaccount: None,
+            };
+            if let Err(e) = ledger_transfer(ledger_canister_id, None, to, amount - fee.clone(), fee).await {
+                ic_cdk::println!("Failed to refund the payment of {}: {}", owner, e);
+            }
+        }
+    }
+}
+
+#[query]
+fn get_payment_config() -> PaymentConfig {
+    config()
+}
+
+/// 支払いによる作成の設定を変更する (管理者のみ)
+#[update]
+fn set_payment_config(config: PaymentConfig) -> Result<(), String> {
+    ensure_admin()?;
+    if let Some(ledger) = &config.ledger {
+        if ledger.price == 0_u64 {
+            return Err("price must be greater than 0".to_string());
+        }
+    }
+    PAYMENTS.with(|payments| payments.borrow_mut().config = config);
+    Ok(())
+}
+
+/// 呼び出し元がレジャーで支払うときの送金先のアカウントを返す
+#[query]
+fn get_payment_account() -> Account {
+    Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(subaccount(&ic_cdk::caller())),
+    }
+}
+
+/// 作成に失敗して残っている呼び出し元のサイクルのクレジットを返す
+#[query]
+fn get_creation_credit() -> u128 {
+    let caller = ic_cdk::caller();
+    PAYMENTS.with(|payments| payments.borrow().credits.get(&caller).copied().unwrap_or(0))
+}
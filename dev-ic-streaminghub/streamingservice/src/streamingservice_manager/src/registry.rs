@@ -0,0 +1,138 @@
+// マネージャーが作成したキャニスターの台帳
+//
+// 作成したキャニスターごとに所有者・種類・インストールした wasm のハッシュ・作成日時・タイトルを記録する。
+// 作成時に登録し、削除時に取り除く。台帳はアップグレード時に安定メモリへ退避する。
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::admin::ensure_admin;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum CanisterKind {
+    Streaming, // streamingservice_backend
+    Greet,     // greet_backend
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CanisterRecord {
+    pub canister_id: Principal,
+    pub owner: Principal,
+    pub kind: CanisterKind,
+    pub wasm_hash: Vec<u8>, // インストールした wasm の SHA-256
+    pub created_at: u64,
+    pub title: String,
+    pub install_arg: Option<Vec<u8>>, // コードをインストールできずに登録した場合の初期化引数 (install_latest_release で使う)
+}
+
+thread_local! {
+    static REGISTRY: RefCell<BTreeMap<Principal, CanisterRecord>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+pub(crate) fn save() -> BTreeMap<Principal, CanisterRecord> {
+    REGISTRY.with(|registry| registry.take())
+}
+
+pub(crate) fn restore(state: BTreeMap<Principal, CanisterRecord>) {
+    REGISTRY.with(|registry| *registry.borrow_mut() = state);
+}
+
+/// 作成したキャニスターを台帳に登録する
+pub(crate) fn register(canister_id: Principal, owner: Principal, kind: CanisterKind, wasm_hash: Vec<u8>, title: String) {
+    insert(canister_id, owner, kind, wasm_hash, title, None);
+}
+
+/// コードをインストールできなかったキャニスターを、後でインストールするときの初期化引数と一緒に登録する
+pub(crate) fn register_without_code(
+    canister_id: Principal,
+    owner: Principal,
+    kind: CanisterKind,
+    title: String,
+    install_arg: Vec<u8>,
+) {
+    insert(canister_id, owner, kind, Vec::new(), title, Some(install_arg));
+}
+
+fn insert(
+    canister_id: Principal,
+    owner: Principal,
+    kind: CanisterKind,
+    wasm_hash: Vec<u8>,
+    title: String,
+    install_arg: Option<Vec<u8>>,
+) {
+    let record = CanisterRecord {
+        canister_id,
+        owner,
+        kind,
+        wasm_hash,
+        created_at: ic_cdk::api::time(),
+        title,
+        install_arg,
+    };
+    REGISTRY.with(|registry| registry.borrow_mut().insert(canister_id, record));
+}
+
+/// 削除したキャニスターを台帳から取り除く
+pub(crate) fn unregister(canister_id: &Principal) -> Option<CanisterRecord> {
+    REGISTRY.with(|registry| registry.borrow_mut().remove(canister_id))
+}
+
+pub(crate) fn list() -> Vec<CanisterRecord> {
+    REGISTRY.with(|registry| registry.borrow().values().cloned().collect())
+}
+
+/// 指定した種類のキャニスターを返す
+pub(crate) fn list_by_kind(kind: CanisterKind) -> Vec<CanisterRecord> {
+    REGISTRY.with(|registry| {
+        registry
+            .borrow()
+            .values()
+            .filter(|record| record.kind == kind)
+            .cloned()
+            .collect()
+    })
+}
+
+/// アップグレードしたキャニスターの wasm のハッシュを更新する
+/// コードをインストールできていなかったキャニスターでは初期化引数も消す
+pub(crate) fn set_wasm_hash(canister_id: &Principal, wasm_hash: Vec<u8>) {
+    REGISTRY.with(|registry| {
+        if let Some(record) = registry.borrow_mut().get_mut(canister_id) {
+            record.wasm_hash = wasm_hash;
+            record.install_arg = None;
+        }
+    });
+}
+
+pub(crate) fn get(canister_id: &Principal) -> Option<CanisterRecord> {
+    REGISTRY.with(|registry| registry.borrow().get(canister_id).cloned())
+}
+
+/// 呼び出し元が所有するキャニスターを返す
+#[query]
+fn list_my_canisters() -> Vec<CanisterRecord> {
+    let caller = ic_cdk::caller();
+    REGISTRY.with(|registry| {
+        registry
+            .borrow()
+            .values()
+            .filter(|record| record.owner == caller)
+            .cloned()
+            .collect()
+    })
+}
+
+/// 台帳のすべてのキャニスターを返す (管理者のみ)
+#[query]
+fn list_all_canisters() -> Result<Vec<CanisterRecord>, String> {
+    ensure_admin()?;
+    Ok(list())
+}
+
+/// キャニスターの台帳の記録を返す
+#[query]
+fn get_canister_record(canister_id: Principal) -> Option<CanisterRecord> {
+    get(&canister_id)
+}
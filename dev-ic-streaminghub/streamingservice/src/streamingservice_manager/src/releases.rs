@@ -0,0 +1,260 @@
+// キャニスターにインストールする wasm のリリース管理
+//
+// コントローラーは wasm を 1MiB 以下のチャンクに分けて upload_wasm_chunk で送り、
+// create_wasm_release でチャンクの並びと wasm 全体のハッシュを指定してリリースとして登録する。
+// チャンクとリリースはハッシュで管理し、同じ内容を重複して保持しない。
+// インストール時は対象キャニスターのチャンクストアにチャンクを送り、install_chunked_code で
+// インストールするため、ingress メッセージの上限を超える wasm も扱える。
+//
+// チャンクはヒープではなく stable memory の BTreeMap に置き (memory.rs)、アップグレードで退避しない。
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::management_canister::main::{
+    self as management, CanisterInstallMode, ClearChunkStoreArgument, InstallChunkedCodeArgument,
+    UploadChunkArgument,
+};
+use ic_cdk_macros::*;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::admin::ensure_controller;
+use crate::memory::{self, Memory};
+use crate::registry::CanisterKind;
+
+type ChunkHash = [u8; 32];
+
+// 管理キャニスターのチャンクストアが受け付けるチャンクの上限
+const MAX_CHUNK_BYTES: usize = 1024 * 1024;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct WasmRelease {
+    pub kind: CanisterKind,
+    pub version: String,
+    pub wasm_hash: Vec<u8>,
+    pub chunk_hashes: Vec<Vec<u8>>, // wasm を構成するチャンクのハッシュ (順番どおり)
+    pub size: u64,
+    pub created_at: u64,
+    pub uploaded_by: Principal,
+}
+
+// アップグレード時に退避する状態
+#[derive(CandidType, Deserialize, Default)]
+pub(crate) struct ReleasesState {
+    releases: BTreeMap<Vec<u8>, WasmRelease>, // wasm のハッシュ -> リリース
+}
+
+thread_local! {
+    static RELEASES: RefCell<ReleasesState> = RefCell::new(ReleasesState::default());
+    // チャンクのハッシュ -> バイト列
+    static CHUNKS: RefCell<StableBTreeMap<ChunkHash, Vec<u8>, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::WASM_CHUNKS)));
+}
+
+pub(crate) fn save() -> ReleasesState {
+    RELEASES.with(|releases| releases.take())
+}
+
+pub(crate) fn restore(state: ReleasesState) {
+    RELEASES.with(|releases| *releases.borrow_mut() = state);
+}
+
+// 保存しているチャンクを返す (ハッシュの長さが違えば None)
+fn chunk(hash: &[u8]) -> Option<Vec<u8>> {
+    let hash: ChunkHash = hash.try_into().ok()?;
+    CHUNKS.with(|chunks| chunks.borrow().get(&hash))
+}
+
+fn remove_chunk(hash: &[u8]) {
+    if let Ok(hash) = ChunkHash::try_from(hash) {
+        CHUNKS.with(|chunks| chunks.borrow_mut().remove(&hash));
+    }
+}
+
+fn is_released(state: &ReleasesState, hash: &[u8]) -> bool {
+    state.releases.values().any(|release| release.chunk_hashes.iter().any(|h| h == hash))
+}
+
+/// 種類ごとに最後に登録したリリースを返す (新規作成ではこのリリースをインストールする)
+pub(crate) fn latest(kind: CanisterKind) -> Option<WasmRelease> {
+    RELEASES.with(|releases| {
+        releases
+            .borrow()
+            .releases
+            .values()
+            .filter(|release| release.kind == kind)
+            .max_by_key(|release| release.created_at)
+            .cloned()
+    })
+}
+
+pub(crate) fn get(wasm_hash: &[u8]) -> Option<WasmRelease> {
+    RELEASES.with(|releases| releases.borrow().releases.get(wasm_hash).cloned())
+}
+
+/// リリースの wasm を対象のキャニスターにインストールする
+/// チャンクを対象のキャニスターのチャンクストアに送り、インストール後にチャンクストアを空にする
+pub(crate) async fn install(
+    canister_id: Principal,
+    release: &WasmRelease,
+    mode: CanisterInstallMode,
+    arg: Vec<u8>,
+) -> Result<(), String> {
+    let mut chunk_hashes_list = Vec::with_capacity(release.chunk_hashes.len());
+    for hash in &release.chunk_hashes {
+        let chunk = chunk(hash)
+            .ok_or_else(|| format!("Chunk {} of release {} is missing", hex(hash), release.version))?;
+        let (chunk_hash,) = management::upload_chunk(UploadChunkArgument { canister_id, chunk })
+            .await
+            .map_err(|e| format!("Failed to upload wasm chunk: {:?}", e))?;
+        chunk_hashes_list.push(chunk_hash);
+    }
+
+    let result = management::install_chunked_code(InstallChunkedCodeArgument {
+        mode,
+        target_canister: canister_id,
+        store_canister: None,
+        chunk_hashes_list,
+        wasm_module_hash: release.wasm_hash.clone(),
+        arg,
+    })
+    .await
+    .map_err(|e| format!("Failed to install code: {:?}", e));
+
+    // インストールに失敗してもチャンクストアは空にしておく
+    if let Err(e) = management::clear_chunk_store(ClearChunkStoreArgument { canister_id }).await {
+        ic_cdk::println!("Failed to clear chunk store of {}: {:?}", canister_id, e);
+    }
+    result
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// wasm のチャンクを保存し、チャンクのハッシュを返す (コントローラーのみ)
+#[update]
+fn upload_wasm_chunk(chunk: Vec<u8>) -> Result<Vec<u8>, String> {
+    ensure_controller()?;
+    if chunk.is_empty() {
+        return Err("Chunk must not be empty".to_string());
+    }
+    if chunk.len() > MAX_CHUNK_BYTES {
+        return Err(format!("Chunk is larger than {} bytes", MAX_CHUNK_BYTES));
+    }
+    let hash: ChunkHash = Sha256::digest(&chunk).into();
+    CHUNKS.with(|chunks| chunks.borrow_mut().insert(hash, chunk));
+    Ok(hash.to_vec())
+}
+
+/// アップロード済みのチャンクを並べて wasm のリリースとして登録する (コントローラーのみ)
+/// チャンクを連結した内容のハッシュが wasm_hash と一致しない場合はエラー
+#[update]
+fn create_wasm_release(
+    kind: CanisterKind,
+    version: String,
+    chunk_hashes: Vec<Vec<u8>>,
+    wasm_hash: Vec<u8>,
+) -> Result<WasmRelease, String> {
+    ensure_controller()?;
+    if version.trim().is_empty() {
+        return Err("Version must not be empty".to_string());
+    }
+    if chunk_hashes.is_empty() {
+        return Err("A release needs at least one chunk".to_string());
+    }
+    RELEASES.with(|releases| {
+        let mut state = releases.borrow_mut();
+        if state.releases.contains_key(&wasm_hash) {
+            return Err("A release with the same wasm hash already exists".to_string());
+        }
+        if state.releases.values().any(|release| release.kind == kind && release.version == version) {
+            return Err(format!("Version {} already exists", version));
+        }
+
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        for hash in &chunk_hashes {
+            let chunk = chunk(hash).ok_or_else(|| format!("Chunk {} has not been uploaded", hex(hash)))?;
+            hasher.update(&chunk);
+            size += chunk.len() as u64;
+        }
+        if hasher.finalize().as_slice() != wasm_hash.as_slice() {
+            return Err("Wasm hash does not match the uploaded chunks".to_string());
+        }
+
+        let release = WasmRelease {
+            kind,
+            version,
+            wasm_hash: wasm_hash.clone(),
+            chunk_hashes,
+            size,
+            created_at: ic_cdk::api::time(),
+            uploaded_by: ic_cdk::caller(),
+        };
+        state.releases.insert(wasm_hash, release.clone());
+        Ok(release)
+    })
+}
+
+/// 登録済みのリリースを新しい順に返す。kind を指定するとその種類だけを返す
+#[query]
+fn list_wasm_releases(kind: Option<CanisterKind>) -> Vec<WasmRelease> {
+    RELEASES.with(|releases| {
+        let mut list: Vec<WasmRelease> = releases
+            .borrow()
+            .releases
+            .values()
+            .filter(|release| kind.is_none_or(|kind| release.kind == kind))
+            .cloned()
+            .collect();
+        list.sort_by_key(|release| std::cmp::Reverse(release.created_at));
+        list
+    })
+}
+
+/// リリースを削除する (コントローラーのみ)
+/// 他のリリースから参照されていないチャンクも削除する
+#[update]
+fn delete_wasm_release(wasm_hash: Vec<u8>) -> Result<(), String> {
+    ensure_controller()?;
+    RELEASES.with(|releases| {
+        let mut state = releases.borrow_mut();
+        let release = state
+            .releases
+            .remove(&wasm_hash)
+            .ok_or_else(|| "Release not found".to_string())?;
+        for hash in release.chunk_hashes {
+            if !is_released(&state, &hash) {
+                remove_chunk(&hash);
+            }
+        }
+        Ok(())
+    })
+}
+
+/// リリースに含まれていないチャンクを削除する (コントローラーのみ)
+/// 登録を取りやめたアップロードの後始末に使う。削除したチャンク数を返す
+#[update]
+fn clear_unreleased_wasm_chunks() -> Result<u64, String> {
+    ensure_controller()?;
+    let unreleased: Vec<ChunkHash> = RELEASES.with(|releases| {
+        let state = releases.borrow();
+        CHUNKS.with(|chunks| {
+            chunks
+                .borrow()
+                .iter()
+                .map(|(hash, _)| hash)
+                .filter(|hash| !is_released(&state, hash))
+                .collect()
+        })
+    });
+    CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        for hash in &unreleased {
+            chunks.remove(hash);
+        }
+    });
+    Ok(unreleased.len() as u64)
+}
+
@@ -0,0 +1,223 @@
+// 子キャニスターの設定 (CanisterSettings) のテンプレート
+//
+// キャニスターの種類ごとに、作成時に送るサイクルと CanisterSettings の既定値をテンプレートとして持つ。
+// テンプレートは管理者が変更できる。作成する呼び出し元は設定の一部を上書きでき、
+// 作成後も所有者か管理者が update_settings で変更できる。
+// どの場合もマネージャー自身をコントローラーに含め、値は範囲を確認してから管理キャニスターに渡す。
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::management_canister::main::{
+    self as management, CanisterSettings, LogVisibility, UpdateSettingsArgument,
+};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::admin::{ensure_admin, ensure_owner_or_admin};
+use crate::registry::{self, CanisterKind};
+
+const DAY_SECS: u64 = 24 * 60 * 60;
+const MIN_FREEZING_THRESHOLD_SECS: u64 = DAY_SECS;
+const MAX_FREEZING_THRESHOLD_SECS: u64 = 365 * DAY_SECS;
+// 子キャニスターは wasm32 なのでヒープは 4GiB まで
+const MAX_WASM_MEMORY_LIMIT: u64 = 4 * 1024 * 1024 * 1024;
+const MAX_MEMORY_ALLOCATION: u64 = 1024 * 1024 * 1024 * 1024;
+// キャニスターの作成と wasm のインストールに必要なサイクルを下回らないようにする
+const MIN_CREATION_CYCLES: u128 = 500_000_000_000;
+const MAX_CREATION_CYCLES: u128 = 100_000_000_000_000;
+// 管理キャニスターが受け付けるコントローラーの上限
+const MAX_CONTROLLERS: usize = 10;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct SettingsTemplate {
+    pub creation_cycles: u128,       // 作成時にキャニスターに送るサイクル
+    pub freezing_threshold: u64,     // 秒
+    pub wasm_memory_limit: u64,      // バイト
+    pub memory_allocation: u64,      // バイト (0 は確保しない)
+    pub reserved_cycles_limit: u128,
+    pub log_visibility: LogVisibility,
+    pub owner_is_controller: bool,   // 所有者もコントローラーにする
+}
+
+impl SettingsTemplate {
+    fn default_for(kind: CanisterKind) -> Self {
+        match kind {
+            CanisterKind::Streaming => SettingsTemplate {
+                creation_cycles: 1_000_000_000_000,
+                freezing_threshold: 30 * DAY_SECS,
+                // 動画のチャンクは stable memory (blobs.rs) に置くため、ヒープにはメタデータと
+                // アップグレード時にエンコードする状態だけが載る。両方が同時に載っても収まる 1GiB にする
+                wasm_memory_limit: 1024 * 1024 * 1024,
+                memory_allocation: 0,
+                reserved_cycles_limit: 5_000_000_000_000,
+                log_visibility: LogVisibility::Controllers,
+                owner_is_controller: true,
+            },
+            CanisterKind::Greet => SettingsTemplate {
+                creation_cycles: 900_000_000_000,
+                freezing_threshold: 30 * DAY_SECS,
+                wasm_memory_limit: 256 * 1024 * 1024,
+                memory_allocation: 0,
+                reserved_cycles_limit: 5_000_000_000_000,
+                log_visibility: LogVisibility::Controllers,
+                owner_is_controller: true,
+            },
+        }
+    }
+}
+
+// 呼び出し元が指定する設定 (None の項目はテンプレートまたは現在の値のまま)
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct SettingsOverrides {
+    pub controllers: Option<Vec<Principal>>, // マネージャーは常に追加する
+    pub freezing_threshold: Option<u64>,
+    pub wasm_memory_limit: Option<u64>,
+    pub log_visibility: Option<LogVisibility>,
+}
+
+thread_local! {
+    static TEMPLATES: RefCell<BTreeMap<CanisterKind, SettingsTemplate>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+pub(crate) fn save() -> BTreeMap<CanisterKind, SettingsTemplate> {
+    TEMPLATES.with(|templates| templates.take())
+}
+
+pub(crate) fn restore(state: BTreeMap<CanisterKind, SettingsTemplate>) {
+    TEMPLATES.with(|templates| *templates.borrow_mut() = state);
+}
+
+fn template(kind: CanisterKind) -> SettingsTemplate {
+    TEMPLATES
+        .with(|templates| templates.borrow().get(&kind).cloned())
+        .unwrap_or_else(|| SettingsTemplate::default_for(kind))
+}
+
+fn validate_freezing_threshold(secs: u64) -> Result<(), String> {
+    if !(MIN_FREEZING_THRESHOLD_SECS..=MAX_FREEZING_THRESHOLD_SECS).contains(&secs) {
+        return Err(format!(
+            "freezing_threshold must be between {} and {} seconds",
+            MIN_FREEZING_THRESHOLD_SECS, MAX_FREEZING_THRESHOLD_SECS
+        ));
+    }
+    Ok(())
+}
+
+fn validate_wasm_memory_limit(bytes: u64) -> Result<(), String> {
+    if bytes == 0 || bytes > MAX_WASM_MEMORY_LIMIT {
+        return Err(format!("wasm_memory_limit must be between 1 and {} bytes", MAX_WASM_MEMORY_LIMIT));
+    }
+    Ok(())
+}
+
+fn validate_log_visibility(visibility: &LogVisibility) -> Result<(), String> {
+    if let LogVisibility::AllowedViewers(viewers) = visibility {
+        if viewers.len() > MAX_CONTROLLERS {
+            return Err(format!("At most {} log viewers can be allowed", MAX_CONTROLLERS));
+        }
+    }
+    Ok(())
+}
+
+fn validate_template(template: &SettingsTemplate) -> Result<(), String> {
+    if !(MIN_CREATION_CYCLES..=MAX_CREATION_CYCLES).contains(&template.creation_cycles) {
+        return Err(format!(
+            "creation_cycles must be between {} and {}",
+            MIN_CREATION_CYCLES, MAX_CREATION_CYCLES
+        ));
+    }
+    if template.memory_allocation > MAX_MEMORY_ALLOCATION {
+        return Err(format!("memory_allocation must be at most {} bytes", MAX_MEMORY_ALLOCATION));
+    }
+    validate_freezing_threshold(template.freezing_threshold)?;
+    validate_wasm_memory_limit(template.wasm_memory_limit)?;
+    validate_log_visibility(&template.log_visibility)
+}
+
+fn validate_overrides(overrides: &SettingsOverrides) -> Result<(), String> {
+    if let Some(secs) = overrides.freezing_threshold {
+        validate_freezing_threshold(secs)?;
+    }
+    if let Some(bytes) = overrides.wasm_memory_limit {
+        validate_wasm_memory_limit(bytes)?;
+    }
+    if let Some(visibility) = &overrides.log_visibility {
+        validate_log_visibility(visibility)?;
+    }
+    Ok(())
+}
+
+/// マネージャーを先頭に、重複を除いたコントローラーの一覧を作る
+fn controllers(principals: impl IntoIterator<Item = Principal>) -> Result<Vec<Principal>, String> {
+    let mut controllers = vec![ic_cdk::id()];
+    for principal in principals {
+        if principal == Principal::anonymous() {
+            return Err("The anonymous principal cannot be a controller".to_string());
+        }
+        if !controllers.contains(&principal) {
+            controllers.push(principal);
+        }
+    }
+    if controllers.len() > MAX_CONTROLLERS {
+        return Err(format!("A canister can have at most {} controllers", MAX_CONTROLLERS));
+    }
+    Ok(controllers)
+}
+
+/// 種類のテンプレートに呼び出し元の設定を重ね、作成時の設定と送るサイクルを返す
+pub(crate) fn for_creation(
+    kind: CanisterKind,
+    owner: Principal,
+    overrides: SettingsOverrides,
+) -> Result<(CanisterSettings, u128), String> {
+    validate_overrides(&overrides)?;
+    let template = template(kind);
+    let owner_controller = template.owner_is_controller.then_some(owner);
+    let settings = CanisterSettings {
+        controllers: Some(controllers(
+            owner_controller.into_iter().chain(overrides.controllers.unwrap_or_default()),
+        )?),
+        compute_allocation: Some(Nat::from(0_u64)),
+        memory_allocation: Some(Nat::from(template.memory_allocation)),
+        freezing_threshold: Some(Nat::from(overrides.freezing_threshold.unwrap_or(template.freezing_threshold))),
+        reserved_cycles_limit: Some(Nat::from(template.reserved_cycles_limit)),
+        log_visibility: Some(overrides.log_visibility.unwrap_or(template.log_visibility)),
+        wasm_memory_limit: Some(Nat::from(overrides.wasm_memory_limit.unwrap_or(template.wasm_memory_limit))),
+    };
+    Ok((settings, template.creation_cycles))
+}
+
+/// 種類ごとのテンプレートを返す
+#[query]
+fn get_settings_template(kind: CanisterKind) -> SettingsTemplate {
+    template(kind)
+}
+
+/// 種類ごとのテンプレートを変更する (管理者のみ)
+#[update]
+fn set_settings_template(kind: CanisterKind, template: SettingsTemplate) -> Result<(), String> {
+    ensure_admin()?;
+    validate_template(&template)?;
+    TEMPLATES.with(|templates| templates.borrow_mut().insert(kind, template));
+    Ok(())
+}
+
+/// 作成済みのキャニスターの設定を変更する (所有者か管理者のみ)
+/// controllers を指定した場合もマネージャーはコントローラーに残す
+#[update]
+async fn update_settings(canister_id: Principal, overrides: SettingsOverrides) -> Result<(), String> {
+    ensure_owner_or_admin(&canister_id)?;
+    if registry::get(&canister_id).is_none() {
+        return Err("Canister is not registered".to_string());
+    }
+    validate_overrides(&overrides)?;
+    let settings = CanisterSettings {
+        controllers: overrides.controllers.map(controllers).transpose()?,
+        freezing_threshold: overrides.freezing_threshold.map(Nat::from),
+        wasm_memory_limit: overrides.wasm_memory_limit.map(Nat::from),
+        log_visibility: overrides.log_visibility,
+        ..Default::default()
+    };
+    management::update_settings(UpdateSettingsArgument { canister_id, settings })
+        .await
+        .map_err(|e| format!("Failed to update settings: {:?}", e))
+}
@@ -0,0 +1,372 @@
+// 台帳のキャニスターを登録済みのリリースへ一括でアップグレードする
+//
+// ロールアウトは対象のキャニスターを batch_size 件ずつのバッチに分け、バッチ内のキャニスターを
+// 並行して 停止 -> Upgrade モードでインストール -> 開始 の順に処理する。
+// キャニスターごとの結果を記録し、失敗したキャニスターがあるバッチの後でロールアウトを中断する。
+// 中断したロールアウトやマネージャーのアップグレードで途切れたロールアウトは resume で再開でき、
+// rollback でアップグレードに成功したキャニスターを直前のリリースに戻せる。
+use candid::{encode_args, CandidType, Deserialize, Principal};
+use futures::future::join_all;
+use ic_cdk::api::management_canister::main::{self as management, CanisterIdRecord, CanisterInstallMode};
+use ic_cdk_macros::*;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+
+use crate::admin::ensure_controller;
+use crate::registry::{self, CanisterKind};
+use crate::releases::{self, WasmRelease};
+
+// 1 バッチで並行してアップグレードするキャニスターの上限
+const MAX_BATCH_SIZE: u32 = 20;
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub enum UpgradeStatus {
+    Pending,
+    Succeeded,
+    Skipped, // すでに対象のリリースがインストールされている
+    Failed(String),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CanisterUpgrade {
+    pub canister_id: Principal,
+    pub from_wasm_hash: Vec<u8>, // ロールアウト開始時にインストールされていた wasm のハッシュ
+    pub to_wasm_hash: Vec<u8>,
+    pub status: UpgradeStatus,
+    pub finished_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum RolloutState {
+    Running,
+    Halted, // 失敗したキャニスターがあるため中断した
+    Completed,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct UpgradeRollout {
+    pub id: u64,
+    pub kind: CanisterKind,
+    pub batch_size: u32,
+    pub rollback_of: Option<u64>, // ロールバックの場合は元のロールアウトの ID
+    pub state: RolloutState,
+    pub canisters: Vec<CanisterUpgrade>,
+    pub started_by: Principal,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+// アップグレード時に退避する状態
+#[derive(CandidType, Deserialize, Default)]
+pub(crate) struct UpgradesState {
+    next_id: u64,
+    rollouts: BTreeMap<u64, UpgradeRollout>,
+}
+
+thread_local! {
+    static UPGRADES: RefCell<UpgradesState> = RefCell::new(UpgradesState::default());
+    // 実行中のロールアウトの ID (ヒープにだけ持つため、マネージャーのアップグレードで途切れたロールアウトは再開できる)
+    static RUNNING: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+pub(crate) fn save() -> UpgradesState {
+    UPGRADES.with(|upgrades| upgrades.take())
+}
+
+pub(crate) fn restore(state: UpgradesState) {
+    UPGRADES.with(|upgrades| *upgrades.borrow_mut() = state);
+}
+
+// 実行中のロールアウトを 1 つに限るためのガード
+// 呼び出しがトラップした場合も drop されて実行中の状態が解除される
+struct RunningGuard;
+
+impl RunningGuard {
+    fn acquire(id: u64) -> Result<Self, String> {
+        RUNNING.with(|running| match running.get() {
+            Some(running_id) => Err(format!("Rollout {} is already running", running_id)),
+            None => {
+                running.set(Some(id));
+                Ok(RunningGuard)
+            }
+        })
+    }
+}
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        RUNNING.with(|running| running.set(None));
+    }
+}
+
+// 別のロールアウトが実行中なら新しいロールアウトを作成しない
+fn ensure_idle() -> Result<(), String> {
+    match RUNNING.with(|running| running.get()) {
+        Some(running_id) => Err(format!("Rollout {} is already running", running_id)),
+        None => Ok(()),
+    }
+}
+
+fn get(id: u64) -> Result<UpgradeRollout, String> {
+    UPGRADES
+        .with(|upgrades| upgrades.borrow().rollouts.get(&id).cloned())
+        .ok_or_else(|| format!("Rollout {} not found", id))
+}
+
+fn insert(
+    kind: CanisterKind,
+    batch_size: u32,
+    rollback_of: Option<u64>,
+    canisters: Vec<CanisterUpgrade>,
+) -> u64 {
+    let now = ic_cdk::api::time();
+    UPGRADES.with(|upgrades| {
+        let mut state = upgrades.borrow_mut();
+        let id = state.next_id;
+        state.next_id += 1;
+        let rollout = UpgradeRollout {
+            id,
+            kind,
+            batch_size,
+            rollback_of,
+            state: RolloutState::Running,
+            canisters,
+            started_by: ic_cdk::caller(),
+            created_at: now,
+            updated_at: now,
+        };
+        state.rollouts.insert(id, rollout);
+        id
+    })
+}
+
+fn update_rollout(id: u64, f: impl FnOnce(&mut UpgradeRollout)) {
+    UPGRADES.with(|upgrades| {
+        if let Some(rollout) = upgrades.borrow_mut().rollouts.get_mut(&id) {
+            f(rollout);
+            rollout.updated_at = ic_cdk::api::time();
+        }
+    });
+}
+
+fn validate_batch_size(batch_size: u32) -> Result<(), String> {
+    if batch_size == 0 || batch_size > MAX_BATCH_SIZE {
+        return Err(format!("Batch size must be between 1 and {}", MAX_BATCH_SIZE));
+    }
+    Ok(())
+}
+
+/// キャニスターを停止してリリースにアップグレードし、再び開始する
+/// アップグレードに失敗してもキャニスターを停止したままにしない
+async fn upgrade_canister(canister_id: Principal, release: WasmRelease) -> Result<(), String> {
+    let stopped = management::stop_canister(CanisterIdRecord { canister_id })
+        .await
+        .map_err(|e| format!("Failed to stop canister: {:?}", e));
+    let upgraded = match stopped {
+        Ok(()) => {
+            let arg = encode_args(()).map_err(|e| format!("Failed to encode arguments: {:?}", e))?;
+            let installed = releases::install(canister_id, &release, CanisterInstallMode::Upgrade(None), arg).await;
+            if installed.is_ok() {
+                registry::set_wasm_hash(&canister_id, release.wasm_hash.clone());
+            }
+            installed
+        }
+        Err(e) => Err(e),
+    };
+    let started = management::start_canister(CanisterIdRecord { canister_id })
+        .await
+        .map_err(|e| format!("Failed to start canister: {:?}", e));
+    upgraded.and(started)
+}
+
+/// 未処理のキャニスターをバッチごとにアップグレードする
+/// 失敗したキャニスターがあるバッチの後で中断し、すべて処理したら完了にする
+async fn run(id: u64) -> Result<UpgradeRollout, String> {
+    let _guard = RunningGuard::acquire(id)?;
+    update_rollout(id, |rollout| rollout.state = RolloutState::Running);
+
+    loop {
+        let rollout = get(id)?;
+        let batch: Vec<(Principal, Vec<u8>)> = rollout
+            .canisters
+            .iter()
+            .filter(|canister| canister.status == UpgradeStatus::Pending)
+            .take(rollout.batch_size as usize)
+            .map(|canister| (canister.canister_id, canister.to_wasm_hash.clone()))
+            .collect();
+        if batch.is_empty() {
+            update_rollout(id, |rollout| rollout.state = RolloutState::Completed);
+            return get(id);
+        }
+
+        let results = join_all(batch.into_iter().map(|(canister_id, wasm_hash)| async move {
+            let result = match releases::get(&wasm_hash) {
+                Some(release) => upgrade_canister(canister_id, release).await,
+                None => Err("Release not found".to_string()),
+            };
+            (canister_id, result)
+        }))
+        .await;
+
+        let now = ic_cdk::api::time();
+        let mut failed = false;
+        update_rollout(id, |rollout| {
+            for (canister_id, result) in results {
+                let Some(canister) = rollout.canisters.iter_mut().find(|c| c.canister_id == canister_id) else {
+                    continue;
+                };
+                canister.status = match result {
+                    Ok(()) => UpgradeStatus::Succeeded,
+                    Err(e) => {
+                        failed = true;
+                        UpgradeStatus::Failed(e)
+                    }
+                };
+                canister.finished_at = Some(now);
+            }
+        });
+        if failed {
+            update_rollout(id, |rollout| rollout.state = RolloutState::Halted);
+            return get(id);
+        }
+    }
+}
+
+/// 登録済みのリリースを台帳のキャニスターにロールアウトし、結果を返す (コントローラーのみ)
+/// canister_ids を指定するとそのキャニスターだけを、省略するとリリースと同じ種類のすべてのキャニスターを対象にする
+#[update]
+async fn start_upgrade_rollout(
+    wasm_hash: Vec<u8>,
+    canister_ids: Option<Vec<Principal>>,
+    batch_size: u32,
+) -> Result<UpgradeRollout, String> {
+    ensure_controller()?;
+    validate_batch_size(batch_size)?;
+    ensure_idle()?;
+    let release = releases::get(&wasm_hash).ok_or("Release not found")?;
+
+    let records = match canister_ids {
+        Some(canister_ids) => canister_ids
+            .into_iter()
+            .map(|canister_id| {
+                let record = registry::get(&canister_id)
+                    .filter(|record| record.kind == release.kind)
+                    .ok_or_else(|| format!("Canister {} is not a registered {:?} canister", canister_id, release.kind))?;
+                if record.install_arg.is_some() {
+                    return Err(format!("Canister {} has no code installed; use install_latest_release", canister_id));
+                }
+                Ok(record)
+            })
+            .collect::<Result<Vec<_>, String>>()?,
+        // コードがインストールされていないキャニスターはアップグレードできないので対象にしない
+        None => registry::list_by_kind(release.kind)
+            .into_iter()
+            .filter(|record| record.install_arg.is_none())
+            .collect(),
+    };
+    if records.is_empty() {
+        return Err("No canisters to upgrade".to_string());
+    }
+
+    let canisters = records
+        .into_iter()
+        .map(|record| {
+            let up_to_date = record.wasm_hash == release.wasm_hash;
+            CanisterUpgrade {
+                canister_id: record.canister_id,
+                from_wasm_hash: record.wasm_hash,
+                to_wasm_hash: release.wasm_hash.clone(),
+                status: if up_to_date { UpgradeStatus::Skipped } else { UpgradeStatus::Pending },
+                finished_at: None,
+            }
+        })
+        .collect();
+    let id = insert(release.kind, batch_size, None, canisters);
+    run(id).await
+}
+
+/// 中断したロールアウトを再開する (コントローラーのみ)
+/// 失敗したキャニスターも再度アップグレードする
+#[update]
+async fn resume_upgrade_rollout(id: u64) -> Result<UpgradeRollout, String> {
+    ensure_controller()?;
+    ensure_idle()?;
+    if get(id)?.state == RolloutState::Completed {
+        return Err(format!("Rollout {} has already completed", id));
+    }
+    update_rollout(id, |rollout| {
+        for canister in &mut rollout.canisters {
+            if let UpgradeStatus::Failed(_) = canister.status {
+                canister.status = UpgradeStatus::Pending;
+            }
+        }
+    });
+    run(id).await
+}
+
+/// ロールアウトでアップグレードに成功したキャニスターを直前のリリースに戻す (コントローラーのみ)
+/// ロールバックも新しいロールアウトとして記録する。その後さらにアップグレードしたキャニスターは対象外
+#[update]
+async fn rollback_upgrade_rollout(id: u64, batch_size: u32) -> Result<UpgradeRollout, String> {
+    ensure_controller()?;
+    validate_batch_size(batch_size)?;
+    ensure_idle()?;
+    let original = get(id)?;
+
+    let mut canisters = Vec::new();
+    for canister in original.canisters {
+        if canister.status != UpgradeStatus::Succeeded {
+            continue;
+        }
+        let current = registry::get(&canister.canister_id).map(|record| record.wasm_hash);
+        if current.as_ref() != Some(&canister.to_wasm_hash) {
+            continue;
+        }
+        if releases::get(&canister.from_wasm_hash).is_none() {
+            return Err(format!(
+                "The previous release of canister {} has been deleted",
+                canister.canister_id
+            ));
+        }
+        canisters.push(CanisterUpgrade {
+            canister_id: canister.canister_id,
+            from_wasm_hash: canister.to_wasm_hash,
+            to_wasm_hash: canister.from_wasm_hash,
+            status: UpgradeStatus::Pending,
+            finished_at: None,
+        });
+    }
+    if canisters.is_empty() {
+        return Err("No canisters to roll back".to_string());
+    }
+
+    let rollback_id = insert(original.kind, batch_size, Some(id), canisters);
+    run(rollback_id).await
+}
+
+/// ロールアウトを返す (コントローラーのみ)
+#[query]
+fn get_upgrade_rollout(id: u64) -> Result<UpgradeRollout, String> {
+    ensure_controller()?;
+    get(id)
+}
+
+/// ロールアウトを新しい順に返す (コントローラーのみ)
+#[query]
+fn list_upgrade_rollouts() -> Result<Vec<UpgradeRollout>, String> {
+    ensure_controller()?;
+    Ok(UPGRADES.with(|upgrades| upgrades.borrow().rollouts.values().rev().cloned().collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_size_must_be_between_one_and_the_maximum() {
+        assert!(validate_batch_size(0).is_err());
+        assert!(validate_batch_size(1).is_ok());
+        assert!(validate_batch_size(MAX_BATCH_SIZE).is_ok());
+        assert!(validate_batch_size(MAX_BATCH_SIZE + 1).is_err());
+    }
+}
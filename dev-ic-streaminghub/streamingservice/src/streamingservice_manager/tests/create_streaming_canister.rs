@@ -0,0 +1,605 @@
+// CreateStreamingCanister で作成したキャニスターに動画をアップロードできることを PocketIC で確認する
+//
+// 事前に wasm をビルドしておく必要があるため通常の cargo test では実行しない:
+//   cargo build --release --target wasm32-unknown-unknown -p streamingservice_backend
+//   cargo build --release --target wasm32-unknown-unknown -p streamingservice_manager
+//   cargo test -p streamingservice_manager -- --ignored
+//
+// レジャーでの支払いのテストには ICRC-1 レジャーの wasm (ic-icrc1-ledger.wasm.gz) が必要で、
+// パスを ICRC1_LEDGER_WASM 環境変数で指定する。
+use candid::types::value::IDLValue;
+use candid::{decode_one, encode_args, CandidType, Deserialize, Nat, Principal};
+use pocket_ic::PocketIc;
+use sha2::{Digest, Sha256};
+
+const MANAGER_WASM: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/../../target/wasm32-unknown-unknown/release/streamingservice_manager.wasm"
+);
+const BACKEND_WASM: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/../../target/wasm32-unknown-unknown/release/streamingservice_backend.wasm"
+);
+const WASM_CHUNK_BYTES: usize = 1024 * 1024;
+const VERSION: &str = "1";
+
+#[derive(CandidType, Deserialize, Debug)]
+enum ApiResult<T> {
+    #[serde(rename = "ok")]
+    Ok(T),
+    #[serde(rename = "err")]
+    Err(IDLValue),
+}
+
+impl<T> ApiResult<T> {
+    fn unwrap(self) -> T {
+        match self {
+            ApiResult::Ok(value) => value,
+            ApiResult::Err(e) => panic!("Call failed: {:?}", e),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct Quotas {
+    max_videos: Option<u64>,
+    max_stored_bytes: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct ChannelInfo {
+    owner: Option<Principal>,
+    title: String,
+    description: String,
+    quotas: Quotas,
+    video_count: u64,
+    stored_bytes: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+enum CanisterKind {
+    Streaming,
+    Greet,
+}
+
+#[derive(CandidType, Deserialize)]
+enum CreationPolicy {
+    Allowlist,
+    Open,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct CanisterRecord {
+    canister_id: Principal,
+    owner: Principal,
+    kind: CanisterKind,
+    wasm_hash: Vec<u8>,
+    created_at: u64,
+    title: String,
+}
+
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+enum UpgradeStatus {
+    Pending,
+    Succeeded,
+    Skipped,
+    Failed(String),
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct CanisterUpgrade {
+    canister_id: Principal,
+    from_wasm_hash: Vec<u8>,
+    to_wasm_hash: Vec<u8>,
+    status: UpgradeStatus,
+}
+
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+enum RolloutState {
+    Running,
+    Halted,
+    Completed,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct UpgradeRollout {
+    id: u64,
+    rollback_of: Option<u64>,
+    state: RolloutState,
+    canisters: Vec<CanisterUpgrade>,
+}
+
+impl UpgradeRollout {
+    fn status_of(&self, canister_id: Principal) -> &UpgradeStatus {
+        &self
+            .canisters
+            .iter()
+            .find(|canister| canister.canister_id == canister_id)
+            .unwrap_or_else(|| panic!("Canister {} is not in rollout {}", canister_id, self.id))
+            .status
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LedgerPayment {
+    ledger_canister_id: Principal,
+    price: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+struct PaymentConfig {
+    accept_cycles: bool,
+    ledger: Option<LedgerPayment>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct CanisterVideos {
+    canister_id: Principal,
+    result: Result<Vec<IDLValue>, String>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct SegmentChunkResponse {
+    segment_chunk_data: Vec<u8>,
+    total_chunk_count: u32,
+}
+
+fn update<T: for<'a> Deserialize<'a> + CandidType>(
+    pic: &PocketIc,
+    canister_id: Principal,
+    sender: Principal,
+    method: &str,
+    args: impl candid::utils::ArgumentEncoder,
+) -> T {
+    let response = pic
+        .update_call(canister_id, sender, method, encode_args(args).unwrap())
+        .unwrap_or_else(|e| panic!("{} was rejected: {:?}", method, e));
+    decode_one(&response).unwrap()
+}
+
+fn controller() -> Principal {
+    Principal::self_authenticating(b"controller")
+}
+
+fn creator() -> Principal {
+    Principal::self_authenticating(b"creator")
+}
+
+fn backend_wasm() -> Vec<u8> {
+    std::fs::read(BACKEND_WASM).expect("Build streamingservice_backend.wasm before running this test")
+}
+
+// wasm をチャンクに分けてアップロードし、streamingservice_backend のリリースとして登録して wasm のハッシュを返す
+fn upload_release(pic: &PocketIc, manager: Principal, version: &str, wasm: &[u8]) -> Vec<u8> {
+    let chunk_hashes: Vec<Vec<u8>> = wasm
+        .chunks(WASM_CHUNK_BYTES)
+        .map(|chunk| {
+            let hash: Result<Vec<u8>, String> =
+                update(pic, manager, controller(), "upload_wasm_chunk", (chunk.to_vec(),));
+            hash.unwrap()
+        })
+        .collect();
+    let wasm_hash = Sha256::digest(wasm).to_vec();
+    let release: Result<IDLValue, String> = update(
+        pic,
+        manager,
+        controller(),
+        "create_wasm_release",
+        (CanisterKind::Streaming, version.to_string(), chunk_hashes, wasm_hash.clone()),
+    );
+    release.unwrap();
+    wasm_hash
+}
+
+// マネージャーをインストールし、streamingservice_backend の wasm をリリースとして登録して
+// creator() にキャニスターの作成を許可する
+fn setup_manager() -> (PocketIc, Principal) {
+    let manager_wasm = std::fs::read(MANAGER_WASM).expect("Build streamingservice_manager.wasm before running this test");
+    let pic = PocketIc::new();
+    let manager = pic.create_canister_with_settings(Some(controller()), None);
+    pic.add_cycles(manager, 100_000_000_000_000);
+    pic.install_canister(manager, manager_wasm, encode_args(()).unwrap(), Some(controller()));
+
+    upload_release(&pic, manager, "0.1.0", &backend_wasm());
+    let allowed: Result<(), String> = update(&pic, manager, controller(), "add_creator", (creator(),));
+    allowed.unwrap();
+    (pic, manager)
+}
+
+#[test]
+#[ignore = "requires the release wasm builds and the PocketIC server"]
+fn creator_can_upload_to_created_streaming_canister() {
+    let (pic, manager) = setup_manager();
+    let creator = creator();
+
+    let quotas = Quotas {
+        max_videos: Some(10),
+        max_stored_bytes: Some(1024 * 1024),
+    };
+    let created: Result<Principal, String> = update(
+        &pic,
+        manager,
+        creator,
+        "CreateStreamingCanister",
+        ("My channel".to_string(), "Videos about cats".to_string(), Some(quotas.clone())),
+    );
+    let streaming = created.unwrap();
+
+    let records: Vec<CanisterRecord> = update(&pic, manager, creator, "list_my_canisters", ());
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].canister_id, streaming);
+    assert_eq!(records[0].owner, creator);
+    assert_eq!(records[0].kind, CanisterKind::Streaming);
+    assert_eq!(records[0].title, "My channel");
+
+    let info: ChannelInfo = update(&pic, streaming, creator, "get_channel_info", ());
+    assert_eq!(info.owner, Some(creator));
+    assert_eq!(info.title, "My channel");
+    assert_eq!(info.description, "Videos about cats");
+    assert_eq!(info.quotas, quotas);
+
+    let video_id: ApiResult<String> = update(
+        &pic,
+        streaming,
+        creator,
+        "create_video_v2",
+        (VERSION.to_string(), "First video".to_string(), "Hello".to_string()),
+    );
+    let video_id = video_id.unwrap();
+
+    let playlist = "#EXTM3U\n#EXT-X-TARGETDURATION:10\n#EXTINF:10.0,\nsegment0.ts\n#EXT-X-ENDLIST\n";
+    let uploaded: ApiResult<()> = update(
+        &pic,
+        streaming,
+        creator,
+        "upload_playlist_v2",
+        (VERSION.to_string(), video_id.clone(), playlist.to_string()),
+    );
+    uploaded.unwrap();
+
+    let segment = vec![0x47_u8; 188 * 4];
+    let uploaded: ApiResult<()> = update(
+        &pic,
+        streaming,
+        creator,
+        "upload_ts_segment_chunk_v2",
+        (VERSION.to_string(), video_id.clone(), 0_u32, 0_u32, 1_u32, segment.clone()),
+    );
+    uploaded.unwrap();
+
+    let finalized: ApiResult<()> = update(
+        &pic,
+        streaming,
+        creator,
+        "finalize_video",
+        (VERSION.to_string(), video_id.clone()),
+    );
+    finalized.unwrap();
+
+    let chunk: ApiResult<SegmentChunkResponse> = update(
+        &pic,
+        streaming,
+        Principal::anonymous(),
+        "get_segment_chunk_v2",
+        (video_id, 0_u32, 0_u32),
+    );
+    let chunk = chunk.unwrap();
+    assert_eq!(chunk.segment_chunk_data, segment);
+    assert_eq!(chunk.total_chunk_count, 1);
+
+    let info: ChannelInfo = update(&pic, streaming, creator, "get_channel_info", ());
+    assert_eq!(info.video_count, 1);
+    assert_eq!(info.stored_bytes, segment.len() as u64);
+
+    // 所有者以外はチャンネルに動画を作成できない
+    let stranger = Principal::self_authenticating(b"stranger");
+    let video_id: ApiResult<String> = update(
+        &pic,
+        streaming,
+        stranger,
+        "create_video_v2",
+        (VERSION.to_string(), "Spam".to_string(), String::new()),
+    );
+    assert!(matches!(video_id, ApiResult::Err(_)));
+
+    // マネージャーから子キャニスターの動画一覧を取得できる
+    let response = pic
+        .query_call(manager, Principal::anonymous(), "list_all_videos", encode_args(()).unwrap())
+        .unwrap();
+    let catalogue: Vec<CanisterVideos> = decode_one(&response).unwrap();
+    assert_eq!(catalogue.len(), 1);
+    assert_eq!(catalogue[0].canister_id, streaming);
+    assert!(catalogue[0].result.is_ok());
+}
+
+#[test]
+#[ignore = "requires the release wasm builds and the PocketIC server"]
+fn anonymous_caller_cannot_create_streaming_canister() {
+    let (pic, manager) = setup_manager();
+    let created: Result<Principal, String> = update(
+        &pic,
+        manager,
+        Principal::anonymous(),
+        "CreateStreamingCanister",
+        ("My channel".to_string(), String::new(), None::<Quotas>),
+    );
+    assert!(created.is_err());
+}
+
+#[test]
+#[ignore = "requires the release wasm builds and the PocketIC server"]
+fn only_allowed_callers_can_create_and_delete_canisters() {
+    let (pic, manager) = setup_manager();
+    let stranger = Principal::self_authenticating(b"stranger");
+
+    let created: Result<Principal, String> = update(
+        &pic,
+        manager,
+        stranger,
+        "CreateStreamingCanister",
+        ("Not allowed".to_string(), String::new(), None::<Quotas>),
+    );
+    assert!(created.is_err());
+
+    let created: Result<Principal, String> = update(
+        &pic,
+        manager,
+        creator(),
+        "CreateStreamingCanister",
+        ("My channel".to_string(), String::new(), None::<Quotas>),
+    );
+    let streaming = created.unwrap();
+
+    let deleted: Result<(), String> = update(&pic, manager, stranger, "DeleteCanister", (streaming.to_text(),));
+    assert!(deleted.is_err());
+    let stopped: Result<(), String> = update(&pic, manager, stranger, "StopCanister", (streaming.to_text(),));
+    assert!(stopped.is_err());
+
+    let stopped: Result<(), String> = update(&pic, manager, creator(), "StopCanister", (streaming.to_text(),));
+    stopped.unwrap();
+    let deleted: Result<(), String> = update(&pic, manager, creator(), "DeleteCanister", (streaming.to_text(),));
+    deleted.unwrap();
+}
+
+// 同じコードで内容 (ハッシュ) だけが違う wasm を作る (末尾にカスタムセクションを追加する)
+fn with_custom_section(wasm: &[u8], payload: &[u8]) -> Vec<u8> {
+    let name = b"test";
+    let section_len = 1 + name.len() + payload.len();
+    assert!(section_len < 0x80, "Section length must fit in one LEB128 byte");
+    let mut wasm = wasm.to_vec();
+    wasm.push(0); // カスタムセクションの ID
+    wasm.push(section_len as u8);
+    wasm.push(name.len() as u8);
+    wasm.extend_from_slice(name);
+    wasm.extend_from_slice(payload);
+    wasm
+}
+
+fn create_channel(pic: &PocketIc, manager: Principal, title: &str) -> Principal {
+    let created: Result<Principal, String> = update(
+        pic,
+        manager,
+        creator(),
+        "CreateStreamingCanister",
+        (title.to_string(), String::new(), None::<Quotas>),
+    );
+    created.unwrap()
+}
+
+fn installed_wasm_hash(pic: &PocketIc, manager: Principal, canister_id: Principal) -> Vec<u8> {
+    let records: Vec<CanisterRecord> = update(pic, manager, creator(), "list_my_canisters", ());
+    records
+        .into_iter()
+        .find(|record| record.canister_id == canister_id)
+        .map(|record| record.wasm_hash)
+        .unwrap()
+}
+
+#[test]
+#[ignore = "requires the release wasm builds and the PocketIC server"]
+fn rollout_upgrades_canisters_and_rollback_restores_the_previous_release() {
+    let (pic, manager) = setup_manager();
+    let creator = creator();
+    let first = create_channel(&pic, manager, "First");
+    let second = create_channel(&pic, manager, "Second");
+    let video_id: ApiResult<String> = update(
+        &pic,
+        first,
+        creator,
+        "create_video_v2",
+        (VERSION.to_string(), "Kept across upgrades".to_string(), String::new()),
+    );
+    video_id.unwrap();
+    let original_hash = installed_wasm_hash(&pic, manager, first);
+    let new_hash = upload_release(&pic, manager, "0.2.0", &with_custom_section(&backend_wasm(), b"0.2.0"));
+
+    let invalid: Result<UpgradeRollout, String> = update(
+        &pic,
+        manager,
+        controller(),
+        "start_upgrade_rollout",
+        (new_hash.clone(), None::<Vec<Principal>>, 0_u32),
+    );
+    assert!(invalid.is_err());
+
+    // 所有者がマネージャーをコントローラーから外したキャニスターはアップグレードに失敗し、ロールアウトが中断する
+    pic.set_controllers(second, Some(creator), vec![creator]).unwrap();
+    let rollout: Result<UpgradeRollout, String> = update(
+        &pic,
+        manager,
+        controller(),
+        "start_upgrade_rollout",
+        (new_hash.clone(), None::<Vec<Principal>>, 1_u32),
+    );
+    let rollout = rollout.unwrap();
+    assert_eq!(rollout.state, RolloutState::Halted);
+    assert!(matches!(rollout.status_of(second), UpgradeStatus::Failed(_)));
+    // 1 件ずつのバッチなので、失敗したバッチより後のキャニスターは処理しない
+    assert!(matches!(rollout.status_of(first), UpgradeStatus::Succeeded | UpgradeStatus::Pending));
+    assert_eq!(installed_wasm_hash(&pic, manager, second), original_hash);
+
+    // コントローラーを戻して再開すると、失敗したキャニスターも再度アップグレードする
+    pic.set_controllers(second, Some(creator), vec![manager, creator]).unwrap();
+    let resumed: Result<UpgradeRollout, String> =
+        update(&pic, manager, controller(), "resume_upgrade_rollout", (rollout.id,));
+    let resumed = resumed.unwrap();
+    assert_eq!(resumed.state, RolloutState::Completed);
+    assert_eq!(resumed.status_of(first), &UpgradeStatus::Succeeded);
+    assert_eq!(resumed.status_of(second), &UpgradeStatus::Succeeded);
+    assert_eq!(installed_wasm_hash(&pic, manager, first), new_hash);
+    assert_eq!(installed_wasm_hash(&pic, manager, second), new_hash);
+    let info: ChannelInfo = update(&pic, first, creator, "get_channel_info", ());
+    assert_eq!(info.video_count, 1);
+
+    // すでに対象のリリースがインストールされているキャニスターは飛ばす
+    let again: Result<UpgradeRollout, String> = update(
+        &pic,
+        manager,
+        controller(),
+        "start_upgrade_rollout",
+        (new_hash.clone(), Some(vec![first]), 1_u32),
+    );
+    let again = again.unwrap();
+    assert_eq!(again.state, RolloutState::Completed);
+    assert_eq!(again.status_of(first), &UpgradeStatus::Skipped);
+
+    // ロールバックは元のロールアウトでアップグレードしたキャニスターを直前のリリースに戻す
+    let rollback: Result<UpgradeRollout, String> =
+        update(&pic, manager, controller(), "rollback_upgrade_rollout", (rollout.id, 2_u32));
+    let rollback = rollback.unwrap();
+    assert_eq!(rollback.rollback_of, Some(rollout.id));
+    assert_eq!(rollback.state, RolloutState::Completed);
+    for canister in &rollback.canisters {
+        assert_eq!(canister.status, UpgradeStatus::Succeeded);
+        assert_eq!(canister.from_wasm_hash, new_hash);
+        assert_eq!(canister.to_wasm_hash, original_hash);
+    }
+    assert_eq!(installed_wasm_hash(&pic, manager, first), original_hash);
+    assert_eq!(installed_wasm_hash(&pic, manager, second), original_hash);
+    let info: ChannelInfo = update(&pic, first, creator, "get_channel_info", ());
+    assert_eq!(info.video_count, 1);
+
+    // 元のロールアウトのリリースのままのキャニスターが無いため、もう一度はロールバックできない
+    let rollback: Result<UpgradeRollout, String> =
+        update(&pic, manager, controller(), "rollback_upgrade_rollout", (rollout.id, 2_u32));
+    assert!(rollback.is_err());
+}
+
+const LEDGER_FEE: u64 = 10_000;
+const PRICE: u64 = 100_000_000;
+
+// ICRC-1 レジャーをインストールし、payer に初期残高を持たせる
+fn install_ledger(pic: &PocketIc, payer: Principal) -> Principal {
+    let path = std::env::var("ICRC1_LEDGER_WASM").expect("Set ICRC1_LEDGER_WASM to the path of the ICRC-1 ledger wasm");
+    let wasm = std::fs::read(path).expect("Failed to read the ICRC-1 ledger wasm");
+    let ledger = pic.create_canister_with_settings(Some(controller()), None);
+    pic.add_cycles(ledger, 10_000_000_000_000);
+    let init_args = candid_parser::parse_idl_args(&format!(
+        r#"(variant {{ Init = record {{
+            minting_account = record {{ owner = principal "{minter}" }};
+            transfer_fee = {fee} : nat;
+            token_symbol = "TST";
+            token_name = "Test";
+            metadata = vec {{}};
+            initial_balances = vec {{ record {{ record {{ owner = principal "{payer}" }}; 1_000_000_000 : nat }} }};
+            archive_options = record {{
+                num_blocks_to_archive = 1000 : nat64;
+                trigger_threshold = 2000 : nat64;
+                controller_id = principal "{minter}";
+            }};
+        }} }})"#,
+        minter = controller(),
+        fee = LEDGER_FEE,
+        payer = payer,
+    ))
+    .unwrap();
+    pic.install_canister(ledger, wasm, init_args.to_bytes().unwrap(), Some(controller()));
+    ledger
+}
+
+fn balance_of(pic: &PocketIc, ledger: Principal, owner: Principal) -> Nat {
+    let account = Account { owner, subaccount: None };
+    let response = pic
+        .query_call(ledger, owner, "icrc1_balance_of", encode_args((account,)).unwrap())
+        .unwrap();
+    decode_one(&response).unwrap()
+}
+
+#[test]
+#[ignore = "requires the release wasm builds, the ICRC-1 ledger wasm and the PocketIC server"]
+fn payer_can_create_streaming_canister_with_ledger_transfer() {
+    let (pic, manager) = setup_manager();
+    let payer = Principal::self_authenticating(b"payer");
+    let ledger = install_ledger(&pic, payer);
+
+    let config = PaymentConfig {
+        accept_cycles: false,
+        ledger: Some(LedgerPayment {
+            ledger_canister_id: ledger,
+            price: Nat::from(PRICE),
+        }),
+    };
+    let configured: Result<(), String> = update(&pic, manager, controller(), "set_payment_config", (config,));
+    configured.unwrap();
+    let opened: Result<(), String> = update(&pic, manager, controller(), "set_creation_policy", (CreationPolicy::Open,));
+    opened.unwrap();
+
+    // 支払いの前は作成できない
+    let created: Result<Principal, String> = update(
+        &pic,
+        manager,
+        payer,
+        "CreateStreamingCanister",
+        ("Paid channel".to_string(), String::new(), None::<Quotas>),
+    );
+    assert!(created.is_err());
+
+    // 価格より多く送金しておくと、作成後に超過分が返金される
+    let payment_account: Account = update(&pic, manager, payer, "get_payment_account", ());
+    let deposit = PRICE + PRICE / 2;
+    let transfer = TransferArg {
+        from_subaccount: None,
+        to: payment_account,
+        amount: Nat::from(deposit),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let transferred: Result<Nat, IDLValue> = update(&pic, ledger, payer, "icrc1_transfer", (transfer,));
+    transferred.unwrap();
+
+    let created: Result<Principal, String> = update(
+        &pic,
+        manager,
+        payer,
+        "CreateStreamingCanister",
+        ("Paid channel".to_string(), String::new(), None::<Quotas>),
+    );
+    let streaming = created.unwrap();
+
+    let records: Vec<CanisterRecord> = update(&pic, manager, payer, "list_my_canisters", ());
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].canister_id, streaming);
+
+    // 送金・マネージャーへの移動・返金でそれぞれ手数料がかかる
+    let refunded = deposit - PRICE - 2 * LEDGER_FEE;
+    let expected = 1_000_000_000 - deposit - LEDGER_FEE + refunded;
+    assert_eq!(balance_of(&pic, ledger, payer), Nat::from(expected));
+    assert_eq!(balance_of(&pic, ledger, manager), Nat::from(PRICE));
+}